@@ -0,0 +1,146 @@
+#![no_std]
+//Shared by the VMC and keyboard firmware, which both keep their settings in the top few erase
+//sectors of flash. Each record gets a sector to itself, so it can be rewritten without disturbing
+//the others
+use core::cell::RefCell;
+
+use defmt::*;
+
+use embassy_sync::blocking_mutex::{raw::RawMutex, Mutex};
+
+use embedded_storage::nor_flash::NorFlash;
+use serde::{de::DeserializeOwned, Serialize};
+
+//Records are a header (magic, version, payload length, checksum) followed by the postcard-encoded payload
+const HEADER_LEN: usize = 10;
+//Records written before the header had a version (magic, payload length, checksum)
+const LEGACY_HEADER_LEN: usize = 8;
+pub const RECORD_LEN: usize = 256; //One flash page is plenty for any record
+
+//The records a firmware stores - usually an enum, with a sector for each variant
+pub trait Record: Copy + Format {
+    //Counting up from the first sector of the storage area
+    fn sector(self) -> usize;
+}
+
+#[derive(Debug, Format, PartialEq, Copy, Clone)]
+pub enum StoreError {
+    Encode,         //The value doesn't fit in a record
+    NotInitialised, //init hasn't been called yet
+    Flash,          //Erasing or writing the sector failed
+}
+
+pub struct RecordStore<M: RawMutex, F> {
+    magic: u32,
+    start: u32,
+    legacy_version: Option<u16>,
+    flash: Mutex<M, RefCell<Option<F>>>,
+}
+
+impl<M: RawMutex, F: NorFlash> RecordStore<M, F> {
+    //The storage area begins at offset start in the flash, and the firmware must be kept out of it.
+    //Each firmware uses its own magic, so one never mistakes the other's records for its own.
+    //If legacy_version is given, records with the old unversioned header are read as that version
+    pub const fn new(magic: u32, start: u32, legacy_version: Option<u16>) -> Self {
+        Self {
+            magic,
+            start,
+            legacy_version,
+            flash: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    //Records can't be read or stored until this is called
+    pub fn init(&self, flash: F) {
+        self.flash.lock(|f| f.replace(Some(flash)));
+    }
+
+    fn offset(&self, record: impl Record) -> u32 {
+        self.start + (record.sector() * F::ERASE_SIZE) as u32
+    }
+
+    //Returns None if the record has never been written, or fails its checksum - otherwise
+    //the version it was stored with and its (still encoded) payload
+    pub fn read_record<'a, R: Record>(&self, record: R, buf: &'a mut [u8; RECORD_LEN]) -> Option<(u16, &'a [u8])> {
+        let read = self.flash.lock(|f| match f.borrow_mut().as_mut() {
+            Some(flash) => flash.read(self.offset(record), buf).is_ok(),
+            None => false,
+        });
+        if !read {
+            error!("Failed to read {} record from flash", record);
+            return None;
+        }
+
+        let buf: &'a [u8; RECORD_LEN] = buf;
+        let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        if magic != self.magic {
+            debug!("No {} record stored in flash", record);
+            return None;
+        }
+        //The checksum tells the two header layouts apart
+        if let Some(payload) = checked_payload(buf, HEADER_LEN) {
+            return Some((u16::from_le_bytes([buf[4], buf[5]]), payload));
+        }
+        if let Some(legacy_version) = self.legacy_version {
+            if let Some(payload) = checked_payload(buf, LEGACY_HEADER_LEN) {
+                debug!("{} record stored with legacy header", record);
+                return Some((legacy_version, payload));
+            }
+        }
+        warn!("Stored {} record failed checksum", record);
+        None
+    }
+
+    //Loads a record, which must have been stored with the expected version. Returns None if it has
+    //never been written, fails its checksum or won't decode
+    pub fn load_record<T: DeserializeOwned>(&self, record: impl Record, version: u16) -> Option<T> {
+        let mut buf = [0x00u8; RECORD_LEN];
+        match self.read_record(record, &mut buf) {
+            Some((v, payload)) if v == version => postcard::from_bytes(payload).ok(),
+            Some((v, _)) => {
+                warn!("Stored {} record is version {}, expected {}", record, v, version);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn store_record<T: Serialize>(&self, record: impl Record, version: u16, value: &T) -> Result<(), StoreError> {
+        //Unused bytes are left erased
+        let mut buf = [0xFFu8; RECORD_LEN];
+        let len = postcard::to_slice(value, &mut buf[HEADER_LEN..]).map_err(|_| StoreError::Encode)?.len();
+        let checksum = fletcher16(&buf[HEADER_LEN..HEADER_LEN + len]);
+        buf[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        buf[4..6].copy_from_slice(&version.to_le_bytes());
+        buf[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[8..10].copy_from_slice(&checksum.to_le_bytes());
+
+        self.flash.lock(|f| {
+            let mut f = f.borrow_mut();
+            let flash = f.as_mut().ok_or(StoreError::NotInitialised)?;
+            let offset = self.offset(record);
+            debug!("Writing {} record (version {}) to flash at {:#x}", record, version, offset);
+            flash.erase(offset, offset + F::ERASE_SIZE as u32).map_err(|_| StoreError::Flash)?;
+            flash.write(offset, &buf).map_err(|_| StoreError::Flash)
+        })
+    }
+}
+
+//The payload length and checksum are the last two fields of either header
+fn checked_payload(buf: &[u8; RECORD_LEN], header_len: usize) -> Option<&[u8]> {
+    let len = u16::from_le_bytes([buf[header_len - 4], buf[header_len - 3]]) as usize;
+    let checksum = u16::from_le_bytes([buf[header_len - 2], buf[header_len - 1]]);
+    if len > RECORD_LEN - header_len {
+        return None;
+    }
+    let payload = &buf[header_len..header_len + len];
+    (fletcher16(payload) == checksum).then_some(payload)
+}
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let (sum1, sum2) = data.iter().fold((0u16, 0u16), |(sum1, sum2), byte| {
+        let sum1 = (sum1 + *byte as u16) % 255;
+        (sum1, (sum2 + sum1) % 255)
+    });
+    (sum2 << 8) | sum1
+}
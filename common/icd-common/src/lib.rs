@@ -0,0 +1,79 @@
+#![no_std]
+//Shared by vmc-icd and keyboard-icd, so the VMC and keyboard firmware identify themselves to
+//the host in the same way, and the host checks them in the same way
+use core::fmt;
+
+use postcard_rpc::{EndpointMap, TopicMap};
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+//Identifies the firmware running on a device, so the host can refuse to talk to
+//firmware built against a different version of its ICD
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct FirmwareInfo {
+    pub version_major: u8,
+    pub version_minor: u8,
+    pub version_patch: u8,
+    pub icd_fingerprint: u64, //See fingerprint()
+}
+
+//The FirmwareInfo for the crate this is used in, with the given ICD fingerprint
+#[macro_export]
+macro_rules! firmware_info {
+    ($fingerprint:expr) => {
+        $crate::FirmwareInfo {
+            version_major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            version_minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            version_patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            icd_fingerprint: $fingerprint,
+        }
+    };
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+//FNV-1a hash over the keys of every endpoint and topic in an ICD. The keys are derived from
+//each path and its message schema, so any change to the ICD changes the fingerprint.
+pub fn fingerprint(endpoints: &EndpointMap, topics_in: &TopicMap, topics_out: &TopicMap) -> u64 {
+    endpoints
+        .endpoints
+        .iter()
+        .flat_map(|(_, req_key, resp_key)| [*req_key, *resp_key])
+        .chain(topics_in.topics.iter().map(|(_, key)| *key))
+        .chain(topics_out.topics.iter().map(|(_, key)| *key))
+        .flat_map(|key| key.to_bytes())
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+//Firmware reporting a different ICD fingerprint to the one the host was built with
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct IcdMismatch {
+    pub firmware: FirmwareInfo,
+    pub expected: u64,
+}
+
+impl fmt::Display for IcdMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "firmware v{}.{}.{} was built against a different ICD (fingerprint {:016x}, host expects {:016x})",
+            self.firmware.version_major,
+            self.firmware.version_minor,
+            self.firmware.version_patch,
+            self.firmware.icd_fingerprint,
+            self.expected
+        )
+    }
+}
+
+//Check the firmware matches the ICD the host was built with
+pub fn check_firmware(firmware: FirmwareInfo, expected: u64) -> Result<(), IcdMismatch> {
+    if firmware.icd_fingerprint == expected {
+        Ok(())
+    } else {
+        Err(IcdMismatch { firmware, expected })
+    }
+}
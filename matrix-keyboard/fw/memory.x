@@ -1,7 +1,8 @@
 MEMORY
 {
 BOOT2   : ORIGIN = 0x10000000, LENGTH = 0x100
-FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+/* The top 16K of flash is kept free for persistent storage - see flash_storage.rs */
+FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 16K
   RAM : ORIGIN = 0x20000000, LENGTH = 264K
 }
 
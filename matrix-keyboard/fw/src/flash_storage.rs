@@ -0,0 +1,32 @@
+use defmt::*;
+
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+
+use flash_record::{Record, RecordStore};
+
+//Must match the size of the flash in memory.x
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+//memory.x keeps the firmware out of the top STORAGE_SECTORS erase sectors of flash
+const STORAGE_SECTORS: usize = 4;
+
+const RECORD_MAGIC: u32 = 0x534E_4B4B; //"SNKK"
+
+pub static FLASH_STORAGE: RecordStore<ThreadModeRawMutex, Flash<'static, FLASH, Blocking, FLASH_SIZE>> =
+    RecordStore::new(RECORD_MAGIC, (FLASH_SIZE - STORAGE_SECTORS * ERASE_SIZE) as u32, None);
+
+#[derive(Copy, Clone, Format)]
+pub enum StorageRecord {
+    Keymap,
+    LcdConfig,
+    ScanConfig,
+}
+
+impl Record for StorageRecord {
+    fn sector(self) -> usize {
+        self as usize
+    }
+}
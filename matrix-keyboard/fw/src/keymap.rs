@@ -0,0 +1,69 @@
+use core::cell::Cell;
+
+use defmt::*;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+
+use postcard_rpc::header::VarHeader;
+
+use keyboard_icd::{Keymap, KeymapError, KeymapResult, MAX_SENSE_LINES, NO_KEY};
+
+use crate::flash_storage::{StorageRecord, FLASH_STORAGE};
+use crate::Context;
+
+//Bump whenever Keymap changes - a keymap stored by older firmware is then ignored
+const KEYMAP_RECORD_VERSION: u16 = 1;
+
+//The vending machine's own 3 x 8 keypad - drive lines on GPIO21, 20 and 19, sense lines on GPIO0-7
+pub const DEFAULT_KEYMAP: Keymap = Keymap {
+    drive_count: 3,
+    drive_gpios: [21, 20, 19, 0, 0, 0, 0, 0],
+    sense_count: 8,
+    sense_gpios: [0, 1, 2, 3, 4, 5, 6, 7],
+    keys: [
+        [0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, NO_KEY], //A->G
+        [0x27, 0x1E, 0x1F, 0x20, 0x21, 0x52, 0x51, NO_KEY], //0->4, up arrow, down arrow
+        [0x22, 0x23, 0x24, 0x25, 0x26, 0x28, 0x29, NO_KEY], //5->9, enter, escape
+        [NO_KEY; MAX_SENSE_LINES],
+        [NO_KEY; MAX_SENSE_LINES],
+        [NO_KEY; MAX_SENSE_LINES],
+        [NO_KEY; MAX_SENSE_LINES],
+        [NO_KEY; MAX_SENSE_LINES],
+    ],
+};
+
+static KEYMAP: Mutex<ThreadModeRawMutex, Cell<Keymap>> = Mutex::new(Cell::new(DEFAULT_KEYMAP));
+//Carries a new keymap to the scanner, which has to reconfigure the keypad pins for it
+pub static KEYMAP_CHANGED: Signal<ThreadModeRawMutex, Keymap> = Signal::new();
+
+//Loads the stored keymap, or falls back to the default one
+pub fn load_keymap() -> Keymap {
+    match FLASH_STORAGE.load_record::<Keymap>(StorageRecord::Keymap, KEYMAP_RECORD_VERSION) {
+        Some(keymap) if keymap.validate().is_ok() => {
+            info!("Loaded keymap from flash");
+            KEYMAP.lock(|k| k.set(keymap));
+            keymap
+        }
+        _ => {
+            info!("No valid keymap in flash - using default");
+            DEFAULT_KEYMAP
+        }
+    }
+}
+
+pub fn get_keymap(_context: &mut Context, _header: VarHeader, _rqst: ()) -> Keymap {
+    KEYMAP.lock(|k| k.get())
+}
+
+pub fn set_keymap(_context: &mut Context, _header: VarHeader, new_keymap: Keymap) -> KeymapResult {
+    new_keymap.validate()?;
+    if let Err(e) = FLASH_STORAGE.store_record(StorageRecord::Keymap, KEYMAP_RECORD_VERSION, &new_keymap) {
+        error!("Failed to store keymap in flash - {}", e);
+        return Err(KeymapError::StorageError);
+    }
+    KEYMAP.lock(|k| k.set(new_keymap));
+    KEYMAP_CHANGED.signal(new_keymap);
+    info!("Keymap updated");
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use core::cell::Cell;
+
+use defmt::*;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+
+use postcard_rpc::header::VarHeader;
+
+use keyboard_icd::{LcdConfig, LcdConfigError, LcdConfigResult};
+
+use crate::flash_storage::{StorageRecord, FLASH_STORAGE};
+use crate::Context;
+
+//Bump whenever LcdConfig changes - a config stored by older firmware is then ignored
+const LCD_CONFIG_RECORD_VERSION: u16 = 1;
+
+static LCD_CONFIG: Mutex<ThreadModeRawMutex, Cell<LcdConfig>> = Mutex::new(Cell::new(LcdConfig::DEFAULT));
+//Tells the LCD task to reinitialise the display with the new config
+pub static LCD_CONFIG_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+pub fn lcd_config() -> LcdConfig {
+    LCD_CONFIG.lock(|c| c.get())
+}
+
+//Loads the stored config, or leaves the default in place
+pub fn load_lcd_config() {
+    match FLASH_STORAGE.load_record::<LcdConfig>(StorageRecord::LcdConfig, LCD_CONFIG_RECORD_VERSION) {
+        Some(config) if config.validate().is_ok() => {
+            info!("Loaded LCD config from flash");
+            LCD_CONFIG.lock(|c| c.set(config));
+        }
+        _ => {
+            info!("No valid LCD config in flash - using default");
+        }
+    }
+}
+
+pub fn get_lcd_config(_context: &mut Context, _header: VarHeader, _rqst: ()) -> LcdConfig {
+    lcd_config()
+}
+
+pub fn set_lcd_config(_context: &mut Context, _header: VarHeader, new_config: LcdConfig) -> LcdConfigResult {
+    new_config.validate()?;
+    if let Err(e) = FLASH_STORAGE.store_record(StorageRecord::LcdConfig, LCD_CONFIG_RECORD_VERSION, &new_config) {
+        error!("Failed to store LCD config in flash - {}", e);
+        return Err(LcdConfigError::StorageError);
+    }
+    LCD_CONFIG.lock(|c| c.set(new_config));
+    LCD_CONFIG_CHANGED.signal(());
+    info!("LCD config updated");
+    Ok(())
+}
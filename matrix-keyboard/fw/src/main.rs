@@ -2,15 +2,15 @@
 #![no_main]
 use defmt::*;
 
+use core::cell::Cell;
 use core::sync::atomic::{AtomicBool, Ordering};
-use fixedstr::str32;
 use core::unreachable;
 use static_cell::{ConstStaticCell, StaticCell};
 
 use embassy_executor::Spawner;
 
-use embassy_sync::{signal::Signal, blocking_mutex::raw::ThreadModeRawMutex};
-use embassy_time::{Delay, Duration, Timer};
+use embassy_sync::{signal::Signal, blocking_mutex::raw::ThreadModeRawMutex, blocking_mutex::Mutex as BlockingMutex};
+use embassy_time::{block_for, with_timeout, Delay, Duration, Instant, Timer};
 
 use embassy_usb::class::hid::{
     HidReader, HidReaderWriter, HidWriter, ReportId, RequestHandler, State,
@@ -19,7 +19,8 @@ use embassy_usb::control::OutResponse;
 use embassy_usb::{Config as UsbConfig, Handler, UsbDevice};
 
 use embassy_rp::bind_interrupts;
-use embassy_rp::gpio::{AnyPin, Input, Level, Output, Pin, Pull};
+use embassy_rp::flash::Flash;
+use embassy_rp::gpio::{AnyPin, Flex, Input, Level, Output, Pin, Pull};
 use embassy_rp::i2c::{self, Config, InterruptHandler};
 use embassy_rp::peripherals::{I2C0, PIN_16, PIN_17, USB};
 use embassy_rp::usb;
@@ -29,10 +30,28 @@ use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
 use lcd_lcm1602_i2c::sync_lcd::Lcd;
 
+mod flash_storage;
+mod keymap;
+use keymap::{get_keymap, load_keymap, set_keymap, KEYMAP_CHANGED};
+mod lcd_config;
+use lcd_config::{get_lcd_config, lcd_config, load_lcd_config, set_lcd_config, LCD_CONFIG_CHANGED};
+mod scan_config;
+use scan_config::{get_scan_config, load_scan_config, scan_config, set_scan_config};
+mod message_queue;
+use message_queue::{cancel_message, current_text, queue_message, set_idle_text};
+
 //NB if we use second core, this mutex is not suitable
-static DISPLAY_TEXT: Signal<ThreadModeRawMutex, [[u8; 32];2]> = Signal::new();
 static BACKLIGHT_SETTING: Signal<ThreadModeRawMutex, bool> = Signal::new();
-static LCD_ROW_LENGTH: usize = 16;
+//Glyphs uploaded by the host, kept so they can be written to the LCD's CGRAM whenever it changes
+static CUSTOM_GLYPHS: BlockingMutex<ThreadModeRawMutex, Cell<[[u8; 8]; CUSTOM_GLYPH_COUNT]>> =
+    BlockingMutex::new(Cell::new([[0x00u8; 8]; CUSTOM_GLYPH_COUNT]));
+static CUSTOM_GLYPHS_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+//Current position of the service mode switch, for the GetServiceMode endpoint
+static SERVICE_MODE: AtomicBool = AtomicBool::new(false);
+//Whether the LCD is currently responding, for the GetLcdStatus endpoint
+static LCD_PRESENT: AtomicBool = AtomicBool::new(false);
+//How often to look for an LCD that isn't responding, in case it is reseated
+const LCD_PROBE_INTERVAL: Duration = Duration::from_secs(2);
 
 use postcard_rpc::{
     define_dispatch,
@@ -47,8 +66,11 @@ use postcard_rpc::{
 };
 
 use keyboard_icd::{
-    ServiceModeTopic, SetBacklight, SetText,
-    ENDPOINT_LIST, TOPICS_IN_LIST, TOPICS_OUT_LIST,
+    icd_fingerprint, CancelMessage, CustomGlyph, CustomGlyphResult, DisplayText, FirmwareInfo, GetLcdConfig,
+    GetLcdStatus, LcdStatusTopic, GetFirmwareInfo, GetKeymap, GetScanConfig, GetServiceMode,
+    KeyEvent, KeyEventTopic, Keymap, QueueMessage, ScanConfig, ServiceModeTopic, SetBacklight,
+    SetCustomGlyph, SetKeymap, SetScanConfig, SetText, CUSTOM_GLYPH_COUNT, ENDPOINT_LIST, KEYPAD_GPIOS, MAX_DRIVE_LINES,
+    MAX_LCD_ROWS, MAX_SENSE_LINES, NO_KEY, SetLcdConfig, TOPICS_IN_LIST, TOPICS_OUT_LIST,
 };
 
 use {defmt_rtt as _, panic_probe as _};
@@ -62,11 +84,13 @@ type AppTx = WireTxImpl<ThreadModeRawMutex, AppDriver>;
 type AppRx = WireRxImpl<AppDriver>;
 type AppServer = Server<AppTx, AppRx, WireRxBuf, MyApp>;
 
-const KEYPAD_MATRIX: [[u8; 7]; 3] = [
-    [0x04u8, 0x05u8, 0x06u8, 0x07u8, 0x08u8, 0x09u8, 0x0Au8], //A->G
-    [0x27u8, 0x1eu8, 0x1fu8, 0x20u8, 0x21u8, 0x52u8, 0x51u8], //0->4, up arrow, down arrow
-    [0x22u8, 0x23u8, 0x24u8, 0x25u8, 0x26u8, 0x28u8, 0x29u8], //5->9, enter, escape
-];
+//Which keys of the keymap are currently held down, indexed by drive then sense line
+type KeyState = [[bool; MAX_SENSE_LINES]; MAX_DRIVE_LINES];
+
+//HID ErrorRollOver - sent in every slot of a report when the pressed keys can't be reported
+const KEY_ERR_OVF: u8 = 0x01;
+//Keys which auto-repeat while held, if enabled in the ScanConfig - up arrow, down arrow
+const REPEATING_KEYS: [u8; 2] = [0x52, 0x51];
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => UsbInterruptHandler<USB>;
@@ -90,6 +114,18 @@ define_dispatch! {
         | ----------                | ----        | -------                     |
         | SetBacklight              | blocking    | set_backlight               |
         | SetText                   | blocking    | set_text                    |
+        | QueueMessage              | blocking    | queue_message               |
+        | CancelMessage             | blocking    | cancel_message              |
+        | SetCustomGlyph            | blocking    | set_custom_glyph            |
+        | GetLcdConfig              | blocking    | get_lcd_config              |
+        | GetLcdStatus              | blocking    | lcd_status                  |
+        | SetLcdConfig              | blocking    | set_lcd_config              |
+        | GetFirmwareInfo           | blocking    | firmware_info               |
+        | GetServiceMode            | blocking    | service_mode                |
+        | GetScanConfig             | blocking    | get_scan_config             |
+        | SetScanConfig             | blocking    | set_scan_config             |
+        | GetKeymap                 | blocking    | get_keymap                  |
+        | SetKeymap                 | blocking    | set_keymap                  |
 
     };
     topics_in: {
@@ -106,6 +142,11 @@ define_dispatch! {
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
+    flash_storage::FLASH_STORAGE.init(Flash::new_blocking(p.FLASH));
+    let keymap = load_keymap();
+    load_lcd_config();
+    load_scan_config();
+
     // Create the driver, from the HAL.
     let driver = UsbDriver::new(p.USB, Irqs);
 
@@ -162,22 +203,24 @@ async fn main(spawner: Spawner) {
     // Build the builder - USB device will be run by usb_task
     let usb: UsbDevice<'_, UsbDriver<'_, USB>> = builder.build();
 
-    //Description for the matrix keyboard and how the row/columns map to GPIOs
-    let col_pins = [
-        Output::new(p.PIN_21, Level::Low),
-        Output::new(p.PIN_20, Level::Low),
-        Output::new(p.PIN_19, Level::Low),
-    ];
-    let row_pins = [
-        Input::new(p.PIN_0, Pull::Down),
-        Input::new(p.PIN_1, Pull::Down),
-        Input::new(p.PIN_2, Pull::Down),
-        Input::new(p.PIN_3, Pull::Down),
-        Input::new(p.PIN_4, Pull::Down),
-        Input::new(p.PIN_5, Pull::Down),
-        Input::new(p.PIN_6, Pull::Down),
-        Input::new(p.PIN_7, Pull::Down),
-    ];
+    //The GPIOs wired to the keypad header, in the order of KEYPAD_GPIOS - the keymap says
+    //which are the matrix drive and sense lines
+    let keypad = KeypadPins::new(
+        [
+            Flex::new(p.PIN_0),
+            Flex::new(p.PIN_1),
+            Flex::new(p.PIN_2),
+            Flex::new(p.PIN_3),
+            Flex::new(p.PIN_4),
+            Flex::new(p.PIN_5),
+            Flex::new(p.PIN_6),
+            Flex::new(p.PIN_7),
+            Flex::new(p.PIN_19),
+            Flex::new(p.PIN_20),
+            Flex::new(p.PIN_21),
+        ],
+        keymap,
+    );
 
     let led_pin = Output::new(p.PIN_25, Level::Low);
 
@@ -188,9 +231,9 @@ async fn main(spawner: Spawner) {
     //USB HID reader task
     spawner.must_spawn(reader_task(reader, request_handler));
     //USB HID writer task
-    spawner.must_spawn(writer_task(writer, col_pins, row_pins, led_pin));
+    spawner.must_spawn(writer_task(writer, keypad, led_pin, server.sender()));
     //I2C LCD driver task
-    spawner.must_spawn(i2c_task(p.I2C0, p.PIN_17, p.PIN_16));
+    spawner.must_spawn(i2c_task(p.I2C0, p.PIN_17, p.PIN_16, server.sender()));
     //Service mode switch topic task
     spawner.must_spawn(servicemode_switch_task(p.PIN_22.degrade(), server.sender()));
 
@@ -207,10 +250,12 @@ async fn servicemode_switch_task(service_mode_pin: AnyPin, sender: Sender<AppTx>
     let mut msg_count = 0u8;
     loop {
         async_input.wait_for_low().await;
+        SERVICE_MODE.store(true, Ordering::Relaxed);
         //Send signal service mode enabled
         let _ = sender.publish::<ServiceModeTopic>(msg_count.into(), &true).await;
         msg_count = msg_count.wrapping_add(1);
         async_input.wait_for_high().await;
+        SERVICE_MODE.store(false, Ordering::Relaxed);
         //Send signal service mode DISABLED
         let _ = sender.publish::<ServiceModeTopic>(msg_count.into(), &false).await;
         msg_count = msg_count.wrapping_add(1);
@@ -237,155 +282,400 @@ fn set_backlight(_context: &mut Context, _header: VarHeader, rqst: bool) {
     BACKLIGHT_SETTING.signal(rqst);
 }
 
-fn set_text(_context: &mut Context, _header: VarHeader, rqst: [[u8; 32];2]) {
-    DISPLAY_TEXT.signal(rqst);
+//Shown whenever there are no queued messages
+fn set_text(_context: &mut Context, _header: VarHeader, rqst: DisplayText) {
+    set_idle_text(rqst);
 }
 
+fn set_custom_glyph(_context: &mut Context, _header: VarHeader, rqst: CustomGlyph) -> CustomGlyphResult {
+    let index = rqst.index as usize;
+    if index >= CUSTOM_GLYPH_COUNT {
+        return Err(());
+    }
+    CUSTOM_GLYPHS.lock(|glyphs| {
+        let mut g = glyphs.get();
+        g[index] = rqst.rows;
+        glyphs.set(g);
+    });
+    CUSTOM_GLYPHS_CHANGED.signal(());
+    Ok(())
+}
 
+fn firmware_info(_context: &mut Context, _header: VarHeader, _rqst: ()) -> FirmwareInfo {
+    keyboard_icd::firmware_info!(icd_fingerprint())
+}
+
+fn service_mode(_context: &mut Context, _header: VarHeader, _rqst: ()) -> bool {
+    SERVICE_MODE.load(Ordering::Relaxed)
+}
+
+fn lcd_status(_context: &mut Context, _header: VarHeader, _rqst: ()) -> bool {
+    LCD_PRESENT.load(Ordering::Relaxed)
+}
+
+
+//Text is HD44780 character codes, not UTF-8 - the host encodes it to suit the LCD's character ROM
+#[derive(Default)]
 struct DisplayLine {
-    text: str32,
+    text: keyboard_icd::DisplayLine,
     changed : bool,
     scrolling: bool,
     scroll_index: usize,
 }
 
 #[embassy_executor::task]
-async fn i2c_task(interface: I2C0, scl: PIN_17, sda: PIN_16) {
+async fn i2c_task(interface: I2C0, scl: PIN_17, sda: PIN_16, sender: Sender<AppTx>) {
     //Initialise the I2C0 peripheral on GPIO16(SDA) and GPIO17(SCL)
     let mut i2c = i2c::I2c::new_async(interface, scl, sda, Irqs, Config::default());
     let mut delay = Delay;
+    let mut backlight = true;
+    let mut reported_present: Option<bool> = None;
+    let mut msg_count = 0u8;
 
-    //Try to find the LCD
-    if let Ok(mut lcd) = Lcd::new(&mut i2c, &mut delay)
-        .with_address(0x27)
-        .with_cursor_on(false)
-        .with_rows(2)
-        .init()
-    {
-        info!("Found I2C LCD at address 0x27");
-        let _ = lcd.backlight(lcd_lcm1602_i2c::Backlight::On);
-        let _ = lcd.clear();
-
-        //Initial display message
-        let mut display_lines = [
-            DisplayLine {
-                text: str32::from("    SnackBot"),
-                changed: true,
-                scrolling: false,
-                scroll_index:0,
-
-            },
-            DisplayLine {
-                text: str32::from("Initializing..."),
-                changed: true,
-                scrolling: false,
-                scroll_index:0,
-            },
-        ];
-
-        loop {
-            //if backlight setting has changed, apply it.
-            if let Some(res) = BACKLIGHT_SETTING.try_take() {
-                let _ = match res {
+    loop {
+        let config = lcd_config();
+        //Try to find the LCD
+        if let Ok(mut lcd) = Lcd::new(&mut i2c, &mut delay)
+            .with_address(config.address)
+            .with_cursor_on(false)
+            .with_rows(config.rows)
+            .init()
+        {
+            info!("Found {}x{} I2C LCD at address {:#04x}", config.cols, config.rows, config.address);
+            if reported_present != Some(true) {
+                LCD_PRESENT.store(true, Ordering::Relaxed);
+                let _ = sender.publish::<LcdStatusTopic>(msg_count.into(), &true).await;
+                msg_count = msg_count.wrapping_add(1);
+                reported_present = Some(true);
+            }
+            let _ = lcd.clear();
+            //Glyphs from before a reset of the LCD
+            CUSTOM_GLYPHS_CHANGED.signal(());
+
+            let rows = config.rows as usize;
+            let cols = config.cols as usize;
+            let mut display_lines: [DisplayLine; MAX_LCD_ROWS] = Default::default();
+            //Set from the message queue
+            let mut shown_text: Option<DisplayText> = None;
+
+            //Runs until the LCD config is changed, or the LCD stops responding
+            while !LCD_CONFIG_CHANGED.signaled() {
+                //if backlight setting has changed, apply it.
+                if let Some(res) = BACKLIGHT_SETTING.try_take() {
+                    backlight = res;
+                }
+                //Rewriting the backlight every time round also checks the LCD is still there
+                let res = match backlight {
                     true => lcd.backlight(lcd_lcm1602_i2c::Backlight::On),
                     false => lcd.backlight(lcd_lcm1602_i2c::Backlight::Off),
                 };
-            }
+                if res.is_err() {
+                    warn!("Lost contact with I2C LCD");
+                    break;
+                }
 
-            if let Some(line) = DISPLAY_TEXT.try_take() {
-                //Update the display lines
-                for (display_line, new_line) in core::iter::zip(display_lines.iter_mut(), line.iter()) {
-                    let t = if let Ok(text) = core::str::from_utf8(new_line) {
-                        text.trim_end()
-                    } else {
-                        "Invalid ASCII"
-                    };
-
-                    display_line.text = str32::from(t);
-                    display_line.changed = true;
-                    display_line.scroll_index = 0;
-                    //if line longer than LCD_ROW_LENGTH chars, it'll need to scroll
-                    display_line.scrolling = t.len() > LCD_ROW_LENGTH;
+                if CUSTOM_GLYPHS_CHANGED.try_take().is_some() {
+                    for (index, glyph) in CUSTOM_GLYPHS.lock(|glyphs| glyphs.get()).iter().enumerate() {
+                        let _ = lcd.custom_char(index as u8, glyph);
+                    }
+                    //Writing CGRAM moves the cursor away from the display, so redraw it all
+                    for line in display_lines.iter_mut() {
+                        line.changed = true;
+                    }
                 }
-            }
 
-            for (row, line) in display_lines.iter_mut().enumerate() {
-                if line.changed || line.scrolling {
-                    let _ = lcd.set_cursor(row as u8, 0);
-                    let _ = lcd.write_str("                ");
-                    let _ = lcd.set_cursor(row as u8, 0);
-                    let _ = lcd.write_str(&line.text.as_str()[line.scroll_index..]);
-                    line.changed = false;
+                let text = current_text(Instant::now());
+                if shown_text.as_ref() != Some(&text) {
+                    //Update the display lines - any the host didn't send are left blank
+                    for (row, display_line) in display_lines.iter_mut().enumerate() {
+                        let mut new_line = text.get(row).cloned().unwrap_or_default();
+                        //Trailing padding is stripped off
+                        let len = new_line.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+                        new_line.truncate(len);
+
+                        display_line.text = new_line;
+                        display_line.changed = true;
+                        display_line.scroll_index = 0;
+                        //if line longer than the LCD is wide, it'll need to scroll
+                        display_line.scrolling = len > cols;
+                    }
+                    shown_text = Some(text);
                 }
-                //If line needs to be scrolled, scroll it.
-                if line.scrolling {
-                    if line.scroll_index == line.text.len() {
-                        line.scroll_index = 0;
+
+                for (row, line) in display_lines.iter_mut().take(rows).enumerate() {
+                    if line.changed || line.scrolling {
+                        let _ = lcd.set_cursor(row as u8, 0);
+                        //Codes are written as they are - each goes out as a single byte. The rest
+                        //of the row is blanked
+                        let visible = line.text[line.scroll_index..].iter().copied();
+                        for c in visible.chain(core::iter::repeat(b' ')).take(cols) {
+                            let _ = lcd.write_char(c as char);
+                        }
+                        line.changed = false;
                     }
-                    else {
-                        line.scroll_index +=1;
+                    //If line needs to be scrolled, scroll it.
+                    if line.scrolling {
+                        if line.scroll_index == line.text.len() {
+                            line.scroll_index = 0;
+                        }
+                        else {
+                            line.scroll_index +=1;
+                        }
                     }
                 }
                 Timer::after(Duration::from_millis(250)).await;
             }
+        } else {
+            if reported_present != Some(false) {
+                warn!("Unable to locate I2C LCD at address {:#04x}", config.address);
+                LCD_PRESENT.store(false, Ordering::Relaxed);
+                let _ = sender.publish::<LcdStatusTopic>(msg_count.into(), &false).await;
+                msg_count = msg_count.wrapping_add(1);
+                reported_present = Some(false);
+            }
+            //Keep looking, in case it is reseated or we're told to look elsewhere
+            let _ = with_timeout(LCD_PROBE_INTERVAL, LCD_CONFIG_CHANGED.wait()).await;
         }
-
-    } else {
-        warn!("Unable to locate I2C LCD at address 0x27");
+        LCD_CONFIG_CHANGED.reset();
     }
 }
 
 #[embassy_executor::task]
 async fn writer_task(
     mut writer: MyHidWriter,
-    mut col_pins: [Output<'static>; 3],
-    mut row_pins: [Input<'static>; 8],
+    mut keypad: KeypadPins,
     mut led_pin: Output<'static>,
+    sender: Sender<AppTx>,
 ) -> ! {
+    let mut scanner = KeyScanner::new();
+    let mut last_report = [0x00u8; 6];
+    let mut msg_count = 0u8;
     loop {
-        let pressed_keys = get_pressed_keys(&mut col_pins, &mut row_pins);
+        if let Some(keymap) = KEYMAP_CHANGED.try_take() {
+            info!("Applying new keymap");
+            keypad.configure(keymap);
+            scanner = KeyScanner::new();
+        }
+
+        let config = scan_config();
+        let now = Instant::now();
+        let mut key_state = keypad.scan();
+
+        let ghosting = is_ghosting(&key_state);
+        if ghosting != scanner.ghosting {
+            if ghosting {
+                warn!("Ghosting detected - ignoring new keypresses");
+            }
+            scanner.ghosting = ghosting;
+        }
+        if ghosting {
+            //Any key not already held down might be a phantom, so only releases are believed
+            for (keys, held_keys) in core::iter::zip(key_state.iter_mut(), scanner.debounced) {
+                for (pressed, held) in core::iter::zip(keys.iter_mut(), held_keys) {
+                    *pressed &= held;
+                }
+            }
+        }
+
+        //Publish a KeyEvent for every key pressed, released or repeated, so the host
+        //gets the keypad directly rather than via HID and whichever window has focus
+        for (row, keys) in key_state.iter().enumerate() {
+            for (col, pressed) in keys.iter().enumerate() {
+                if let Some(event) = scanner.update_key(row, col, *pressed, now, &keypad.keymap, &config) {
+                    let _ = sender.publish::<KeyEventTopic>(msg_count.into(), &event).await;
+                    msg_count = msg_count.wrapping_add(1);
+                }
+            }
+        }
+
+        let pressed_keys = get_pressed_keys(&scanner.debounced, &keypad.keymap, scanner.ghosting);
         if pressed_keys != [0x00u8; 6] {
             //flash led
             led_pin.set_high();
         }
 
-        let report = KeyboardReport {
-            keycodes: pressed_keys,
-            leds: 0,
-            modifier: 0,
-            reserved: 0,
+        //Only report changes - the OS does its own key repeat
+        if pressed_keys != last_report {
+            let report = KeyboardReport {
+                keycodes: pressed_keys,
+                leds: 0,
+                modifier: 0,
+                reserved: 0,
+            };
+
+            // Send the report.
+            match writer.write_serialize(&report).await {
+                Ok(()) => last_report = pressed_keys,
+                Err(e) => warn!("Failed to send report: {:?}", e),
+            };
+        }
+        Timer::after(Duration::from_millis(5)).await;
+        led_pin.set_low();
+    }
+}
+
+//Debounces the matrix readings for each key, and turns them into key events
+struct KeyScanner {
+    raw: KeyState,
+    raw_changed: [[Instant; MAX_SENSE_LINES]; MAX_DRIVE_LINES], //When each key's reading last changed
+    debounced: KeyState,
+    next_repeat: [[Option<Instant>; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+    ghosting: bool,
+}
+
+impl KeyScanner {
+    fn new() -> Self {
+        Self {
+            raw: [[false; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+            raw_changed: [[Instant::MIN; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+            debounced: [[false; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+            next_repeat: [[None; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+            ghosting: false,
+        }
+    }
+
+    //Takes the latest reading of a key - returns an event if it has now been pressed or released for
+    //long enough to be believed, or if it has been held long enough to repeat
+    fn update_key(
+        &mut self,
+        row: usize,
+        col: usize,
+        pressed: bool,
+        now: Instant,
+        keymap: &Keymap,
+        config: &ScanConfig,
+    ) -> Option<KeyEvent> {
+        let keycode = keymap.keys[row][col];
+        if keycode == NO_KEY {
+            return None;
+        }
+
+        if pressed != self.raw[row][col] {
+            self.raw[row][col] = pressed;
+            self.raw_changed[row][col] = now;
+        }
+
+        let event = |pressed, repeat| KeyEvent {
+            row: row as u8,
+            col: col as u8,
+            keycode,
+            pressed,
+            repeat,
         };
 
-        // Send the report.
-        match writer.write_serialize(&report).await {
-            Ok(()) => {}
-            Err(e) => warn!("Failed to send report: {:?}", e),
+        if pressed != self.debounced[row][col] {
+            if now.duration_since(self.raw_changed[row][col]) < Duration::from_millis(config.key_debounce_ms(row, col) as u64) {
+                //Still bouncing
+                return None;
+            }
+            self.debounced[row][col] = pressed;
+            self.next_repeat[row][col] = if pressed && REPEATING_KEYS.contains(&keycode) {
+                Some(now + Duration::from_millis(config.repeat_delay_ms as u64))
+            } else {
+                None
+            };
+            return Some(event(pressed, false));
+        }
+
+        match self.next_repeat[row][col] {
+            Some(at) if config.repeat_enabled && now >= at => {
+                self.next_repeat[row][col] = Some(now + Duration::from_millis(config.repeat_interval_ms as u64));
+                Some(event(true, true))
+            }
+            _ => None,
+        }
+    }
+}
+
+//The keypad header GPIOs, set up as the drive and sense lines of a keymap
+struct KeypadPins {
+    pins: [Flex<'static>; KEYPAD_GPIOS.len()],
+    keymap: Keymap,
+    drive: [usize; MAX_DRIVE_LINES], //Indices into pins
+    sense: [usize; MAX_SENSE_LINES],
+}
+
+impl KeypadPins {
+    fn new(pins: [Flex<'static>; KEYPAD_GPIOS.len()], keymap: Keymap) -> Self {
+        let mut keypad = Self {
+            pins,
+            keymap,
+            drive: [0; MAX_DRIVE_LINES],
+            sense: [0; MAX_SENSE_LINES],
         };
-        Timer::after(Duration::from_millis(5)).await;
-        led_pin.set_low();
+        keypad.configure(keymap);
+        keypad
+    }
+
+    fn configure(&mut self, keymap: Keymap) {
+        //Lines the keymap doesn't use are left as pulled down inputs, like the sense lines
+        for pin in self.pins.iter_mut() {
+            pin.set_as_input();
+            pin.set_pull(Pull::Down);
+        }
+        //Keymaps are validated before they get here, so every GPIO is on the header
+        let pin_index = |gpio: &u8| KEYPAD_GPIOS.iter().position(|g| g == gpio).unwrap();
+        for (drive, gpio) in core::iter::zip(self.drive.iter_mut(), keymap.drive_gpios()) {
+            *drive = pin_index(gpio);
+            self.pins[*drive].set_low();
+            self.pins[*drive].set_as_output();
+        }
+        for (sense, gpio) in core::iter::zip(self.sense.iter_mut(), keymap.sense_gpios()) {
+            *sense = pin_index(gpio);
+        }
+        self.keymap = keymap;
+    }
+
+    fn scan(&mut self) -> KeyState {
+        let mut key_state: KeyState = [[false; MAX_SENSE_LINES]; MAX_DRIVE_LINES];
+        let drive_lines = &self.drive[..self.keymap.drive_gpios().len()];
+        let sense_lines = &self.sense[..self.keymap.sense_gpios().len()];
+        for (drive, keys) in core::iter::zip(drive_lines, key_state.iter_mut()) {
+            //For each drive line of the matrix keypad, set it high, then read the sense lines to see
+            //which buttons are pressed
+            self.pins[*drive].set_high();
+            //Give the sense lines a moment to settle through the membrane's resistance
+            block_for(Duration::from_micros(10));
+            for (sense, pressed) in core::iter::zip(sense_lines, keys.iter_mut()) {
+                *pressed = self.pins[*sense].is_high();
+            }
+            self.pins[*drive].set_low();
+        }
+        key_state
     }
 }
 
-fn get_pressed_keys(col_pins: &mut [Output; 3], row_pins: &mut [Input; 8]) -> [u8; 6] {
+//The keypad has no diodes, so holding three keys on the corners of a rectangle in the matrix
+//makes the fourth corner read as pressed too. If any two drive lines share more than one pressed
+//sense line, there is no telling which of those keys are real
+fn is_ghosting(key_state: &KeyState) -> bool {
+    key_state.iter().enumerate().any(|(i, keys)| {
+        key_state[i + 1..].iter().any(|other_keys| {
+            core::iter::zip(keys, other_keys).filter(|(a, b)| **a && **b).count() > 1
+        })
+    })
+}
+
+fn get_pressed_keys(key_state: &KeyState, keymap: &Keymap, ghosting: bool) -> [u8; 6] {
+    if ghosting {
+        //Tell the OS we can't be sure which keys are pressed
+        return [KEY_ERR_OVF; 6];
+    }
     let mut pressed_keys = [0x00u8; 6];
     let mut pressed_key_count = 0;
-    for (col_pin, keypad_col) in core::iter::zip(col_pins.iter_mut(), KEYPAD_MATRIX) {
-        //For each column of the matrix keypad, set the column pin high, then scan the row pins to see
-        //which buttons are pressed
-        col_pin.set_high();
-        for (row_pin, keypad_val) in core::iter::zip(row_pins.iter(), keypad_col) {
-            if row_pin.is_high() {
+    for (keys, keymap_keys) in core::iter::zip(key_state, keymap.keys) {
+        for (pressed, keypad_val) in core::iter::zip(keys, keymap_keys) {
+            if *pressed && keypad_val != NO_KEY {
                 if pressed_key_count == 6 {
                     //If we already have 6 keys pressed, we cannot accept another keypress
                     //Return an array of KEY_ERR_OVF to indicate this to the OS
                     warn!("Too many keys pressed");
-                    return [0x01; 6];
+                    return [KEY_ERR_OVF; 6];
                 }
                 pressed_keys[pressed_key_count] = keypad_val;
                 pressed_key_count += 1;
             }
         }
-        col_pin.set_low();
     }
     pressed_keys
 }
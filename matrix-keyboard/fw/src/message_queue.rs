@@ -0,0 +1,124 @@
+use core::cell::RefCell;
+
+use defmt::*;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use postcard_rpc::header::VarHeader;
+
+use keyboard_icd::{DisplayLine, DisplayText, LcdMessage, QueueError, QueueResult, MAX_QUEUED_MESSAGES};
+
+use crate::Context;
+
+//When several messages share the top priority, each is shown for this long in turn
+const ROTATE_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Clone)]
+struct QueuedMessage {
+    id: u8,
+    priority: u8,
+    text: DisplayText,
+    expires: Option<Instant>,
+}
+
+struct MessageQueue {
+    idle_text: Option<DisplayText>, //None until the host sets one
+    messages: [Option<QueuedMessage>; MAX_QUEUED_MESSAGES],
+}
+
+const NO_MESSAGE: Option<QueuedMessage> = None;
+
+static MESSAGE_QUEUE: Mutex<ThreadModeRawMutex, RefCell<MessageQueue>> = Mutex::new(RefCell::new(MessageQueue {
+    idle_text: None,
+    messages: [NO_MESSAGE; MAX_QUEUED_MESSAGES],
+}));
+
+//Shown until the host sets its own idle message
+fn boot_text() -> DisplayText {
+    [&b"    SnackBot"[..], b"Initializing..."]
+        .iter()
+        .map(|line| DisplayLine::from_slice(line).unwrap_or_default())
+        .collect()
+}
+
+//The text the LCD should be showing now. Expired messages are dropped, so the display falls back
+//to the idle message by itself if the host stops sending
+pub fn current_text(now: Instant) -> DisplayText {
+    MESSAGE_QUEUE.lock(|q| {
+        let mut q = q.borrow_mut();
+        for slot in q.messages.iter_mut() {
+            if let Some(m) = slot.as_ref().filter(|m| m.expires.is_some_and(|expires| now >= expires)) {
+                debug!("LCD message {} expired", m.id);
+                *slot = None;
+            }
+        }
+
+        let queued = || q.messages.iter().flatten();
+        let Some(top_priority) = queued().map(|m| m.priority).max() else {
+            return q.idle_text.clone().unwrap_or_else(boot_text);
+        };
+        let top_count = queued().filter(|m| m.priority == top_priority).count();
+        let turn = (now.as_millis() / ROTATE_INTERVAL.as_millis()) as usize % top_count;
+        queued()
+            .filter(|m| m.priority == top_priority)
+            .nth(turn)
+            .map(|m| m.text.clone())
+            .unwrap_or_default()
+    })
+}
+
+pub fn set_idle_text(text: DisplayText) {
+    MESSAGE_QUEUE.lock(|q| q.borrow_mut().idle_text = Some(text));
+}
+
+pub fn queue_message(_context: &mut Context, _header: VarHeader, rqst: LcdMessage) -> QueueResult {
+    let message = QueuedMessage {
+        id: rqst.id,
+        priority: rqst.priority,
+        text: rqst.text,
+        expires: match rqst.duration_ms {
+            0 => None,
+            ms => Some(Instant::now() + Duration::from_millis(ms as u64)),
+        },
+    };
+
+    MESSAGE_QUEUE.lock(|q| {
+        let mut q = q.borrow_mut();
+        //Replace a message with the same id, or use a free slot, or failing that bump the least
+        //important message if it is less important than this one
+        let slot = match q.messages.iter().position(|m| m.as_ref().is_some_and(|m| m.id == message.id)) {
+            Some(index) => index,
+            None => match q.messages.iter().position(|m| m.is_none()) {
+                Some(index) => index,
+                None => {
+                    let (index, lowest) = q
+                        .messages
+                        .iter()
+                        .flatten()
+                        .enumerate()
+                        .min_by_key(|(_, m)| m.priority)
+                        .map(|(index, m)| (index, m.priority))
+                        .unwrap_or((0, 0));
+                    if lowest >= message.priority {
+                        warn!("LCD message queue full - dropped message {}", message.id);
+                        return Err(QueueError::QueueFull);
+                    }
+                    index
+                }
+            },
+        };
+        q.messages[slot] = Some(message);
+        Ok(())
+    })
+}
+
+pub fn cancel_message(_context: &mut Context, _header: VarHeader, rqst: u8) {
+    MESSAGE_QUEUE.lock(|q| {
+        for slot in q.borrow_mut().messages.iter_mut() {
+            if slot.as_ref().is_some_and(|m| m.id == rqst) {
+                *slot = None;
+            }
+        }
+    });
+}
@@ -0,0 +1,50 @@
+use core::cell::Cell;
+
+use defmt::*;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+
+use postcard_rpc::header::VarHeader;
+
+use keyboard_icd::{ScanConfig, ScanConfigError, ScanConfigResult};
+
+use crate::flash_storage::{StorageRecord, FLASH_STORAGE};
+use crate::Context;
+
+//Bump whenever ScanConfig changes - a config stored by older firmware is then ignored
+const SCAN_CONFIG_RECORD_VERSION: u16 = 1;
+
+static SCAN_CONFIG: Mutex<ThreadModeRawMutex, Cell<ScanConfig>> = Mutex::new(Cell::new(ScanConfig::DEFAULT));
+
+pub fn scan_config() -> ScanConfig {
+    SCAN_CONFIG.lock(|c| c.get())
+}
+
+//Loads the stored config, or leaves the default in place
+pub fn load_scan_config() {
+    match FLASH_STORAGE.load_record::<ScanConfig>(StorageRecord::ScanConfig, SCAN_CONFIG_RECORD_VERSION) {
+        Some(config) if config.validate().is_ok() => {
+            info!("Loaded scan config from flash");
+            SCAN_CONFIG.lock(|c| c.set(config));
+        }
+        _ => {
+            info!("No valid scan config in flash - using default");
+        }
+    }
+}
+
+pub fn get_scan_config(_context: &mut Context, _header: VarHeader, _rqst: ()) -> ScanConfig {
+    scan_config()
+}
+
+//The keyboard task picks up the new config on its next scan
+pub fn set_scan_config(_context: &mut Context, _header: VarHeader, new_config: ScanConfig) -> ScanConfigResult {
+    new_config.validate()?;
+    if let Err(e) = FLASH_STORAGE.store_record(StorageRecord::ScanConfig, SCAN_CONFIG_RECORD_VERSION, &new_config) {
+        error!("Failed to store scan config in flash - {}", e);
+        return Err(ScanConfigError::StorageError);
+    }
+    SCAN_CONFIG.lock(|c| c.set(new_config));
+    info!("Scan config updated");
+    Ok(())
+}
@@ -1,8 +1,239 @@
 #![cfg_attr(not(feature = "use-std"), no_std)]
 
 use postcard_rpc::{endpoints, topics, TopicDirection};
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+use heapless::Vec;
 
-pub type DisplayText =  [[u8;32];2];
+pub const MAX_LCD_ROWS: usize = 4;
+//Lines longer than the LCD is wide scroll
+pub const MAX_LINE_LEN: usize = 64;
+
+//HD44780 character codes (not UTF-8). Codes 0-7 show the custom glyphs uploaded with SetCustomGlyph
+pub type DisplayLine = Vec<u8, MAX_LINE_LEN>;
+//One line per LCD row, top first - rows the LCD doesn't have are ignored, so text for a 20x4 LCD
+//still shows its first two lines on a 16x2
+pub type DisplayText = Vec<DisplayLine, MAX_LCD_ROWS>;
+
+//I2C address and size of the character LCD fitted
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct LcdConfig {
+    pub address: u8,
+    pub rows: u8,
+    pub cols: u8,
+}
+
+impl LcdConfig {
+    //A 16x2 LCD on a PCF8574 backpack with its address jumpers left open
+    pub const DEFAULT: Self = Self {
+        address: 0x27,
+        rows: 2,
+        cols: 16,
+    };
+
+    pub fn validate(&self) -> LcdConfigResult {
+        //Outside the reserved I2C addresses
+        if !(0x08..=0x77).contains(&self.address) {
+            return Err(LcdConfigError::InvalidAddress);
+        }
+        //A single HD44780 drives up to 80 characters - 40x4 LCDs have two, which we don't support
+        if self.rows == 0 || self.rows as usize > MAX_LCD_ROWS || self.cols == 0 || self.cols > 40
+            || self.rows as usize * self.cols as usize > 80
+        {
+            return Err(LcdConfigError::InvalidGeometry);
+        }
+        Ok(())
+    }
+}
+
+impl Default for LcdConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum LcdConfigError {
+    InvalidAddress,
+    InvalidGeometry,
+    StorageError, //Valid, but could not be saved to flash
+}
+
+pub type LcdConfigResult = Result<(), LcdConfigError>;
+
+pub const MAX_QUEUED_MESSAGES: usize = 8;
+
+//A message for the LCD, shown instead of the idle message (set with SetText) until it expires
+//or is cancelled. Only the highest priority messages queued are shown - if there are several,
+//they take turns
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone)]
+pub struct LcdMessage {
+    pub id: u8, //Queuing a message with the same id as one already queued replaces it
+    pub priority: u8, //Higher goes first
+    pub duration_ms: u32, //From when it is queued - 0 shows it until it is cancelled
+    pub text: DisplayText,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum QueueError {
+    QueueFull, //Of messages of the same or higher priority
+}
+
+pub type QueueResult = Result<(), QueueError>;
+
+pub const CUSTOM_GLYPH_COUNT: usize = 8;
+
+//A 5x8 pixel character for the LCD's CGRAM, shown for character code `index`. Each row is
+//the bottom 5 bits of a byte, top row first
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct CustomGlyph {
+    pub index: u8, //0 to CUSTOM_GLYPH_COUNT - 1
+    pub rows: [u8; 8],
+}
+
+pub type CustomGlyphResult = Result<(), ()>;
+
+//The same for the VMC and keyboard - see icd_common
+pub use icd_common::{check_firmware, firmware_info, FirmwareInfo, IcdMismatch};
+
+//A key on the keypad being pressed or released. Row and col are the drive and sense lines
+//it joins in the Keymap, keycode the USB HID usage ID it is mapped to
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct KeyEvent {
+    pub row: u8,
+    pub col: u8,
+    pub keycode: u8,
+    pub pressed: bool,
+    pub repeat: bool, //A typematic repeat of a key that is still held down
+}
+
+//GPIOs wired to the keypad header on the matrix-keyboard PCB - a Keymap can use any of them
+//as drive or sense lines
+pub const KEYPAD_GPIOS: [u8; 11] = [0, 1, 2, 3, 4, 5, 6, 7, 19, 20, 21];
+pub const MAX_DRIVE_LINES: usize = 8;
+pub const MAX_SENSE_LINES: usize = 8;
+//Keymap entry for a matrix position with no key fitted
+pub const NO_KEY: u8 = 0x00;
+
+//Describes the membrane keypad fitted. The firmware drives each drive line high in turn, and
+//reads which sense lines follow it. keys[d][s] is the USB HID usage ID of the key joining
+//drive line d to sense line s, or NO_KEY
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct Keymap {
+    pub drive_count: u8,
+    pub drive_gpios: [u8; MAX_DRIVE_LINES], //Only the first drive_count are used
+    pub sense_count: u8,
+    pub sense_gpios: [u8; MAX_SENSE_LINES], //Only the first sense_count are used
+    pub keys: [[u8; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+}
+
+impl Keymap {
+    pub fn drive_gpios(&self) -> &[u8] {
+        &self.drive_gpios[..(self.drive_count as usize).min(MAX_DRIVE_LINES)]
+    }
+
+    pub fn sense_gpios(&self) -> &[u8] {
+        &self.sense_gpios[..(self.sense_count as usize).min(MAX_SENSE_LINES)]
+    }
+
+    pub fn validate(&self) -> KeymapResult {
+        if self.drive_count as usize > MAX_DRIVE_LINES || self.sense_count as usize > MAX_SENSE_LINES {
+            return Err(KeymapError::TooManyLines);
+        }
+        let gpios = self.drive_gpios().iter().chain(self.sense_gpios());
+        for (i, gpio) in gpios.clone().enumerate() {
+            if !KEYPAD_GPIOS.contains(gpio) {
+                return Err(KeymapError::InvalidGpio);
+            }
+            if gpios.clone().skip(i + 1).any(|other| other == gpio) {
+                return Err(KeymapError::GpioUsedTwice);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum KeymapError {
+    TooManyLines,
+    InvalidGpio,   //Not wired to the keypad header
+    GpioUsedTwice,
+    StorageError,  //Valid, but could not be saved to flash
+}
+
+pub type KeymapResult = Result<(), KeymapError>;
+
+//Keys are scanned every 5ms, so a shorter debounce time does nothing
+pub const MIN_DEBOUNCE_MS: u16 = 5;
+pub const MAX_DEBOUNCE_MS: u16 = 100;
+pub const MIN_REPEAT_DELAY_MS: u16 = 100;
+pub const MAX_REPEAT_DELAY_MS: u16 = 5000;
+pub const MIN_REPEAT_INTERVAL_MS: u16 = 20;
+pub const MAX_REPEAT_INTERVAL_MS: u16 = 5000;
+
+//key_debounce_ms entry for a key which uses the ScanConfig's debounce_ms
+pub const DEFAULT_KEY_DEBOUNCE: u16 = 0;
+
+//How the firmware scans the keypad matrix. Each key is debounced and repeated on its own timer.
+//The repeat timings are shared by every key, and so is the debounce time unless a key has its own
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct ScanConfig {
+    pub debounce_ms: u16, //A key must read the same for this long before a press or release is accepted
+    //Per key debounce times, eg for worn keys which bounce for longer - indexed like Keymap::keys,
+    //DEFAULT_KEY_DEBOUNCE for keys using debounce_ms
+    pub key_debounce_ms: [[u16; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+    pub repeat_enabled: bool, //Whether the up/down keys auto-repeat while held
+    pub repeat_delay_ms: u16, //Time held before the first repeat
+    pub repeat_interval_ms: u16, //Time between subsequent repeats
+}
+
+impl ScanConfig {
+    pub const DEFAULT: Self = Self {
+        debounce_ms: 20,
+        key_debounce_ms: [[DEFAULT_KEY_DEBOUNCE; MAX_SENSE_LINES]; MAX_DRIVE_LINES],
+        repeat_enabled: true,
+        repeat_delay_ms: 500,
+        repeat_interval_ms: 150,
+    };
+
+    //Debounce time of the key joining drive line `drive` to sense line `sense`
+    pub fn key_debounce_ms(&self, drive: usize, sense: usize) -> u16 {
+        match self.key_debounce_ms[drive][sense] {
+            DEFAULT_KEY_DEBOUNCE => self.debounce_ms,
+            ms => ms,
+        }
+    }
+
+    pub fn validate(&self) -> ScanConfigResult {
+        let debounce_ok = |ms: u16| (MIN_DEBOUNCE_MS..=MAX_DEBOUNCE_MS).contains(&ms);
+        if !debounce_ok(self.debounce_ms)
+            || self.key_debounce_ms.iter().flatten().any(|ms| *ms != DEFAULT_KEY_DEBOUNCE && !debounce_ok(*ms))
+        {
+            return Err(ScanConfigError::InvalidDebounce);
+        }
+        if !(MIN_REPEAT_DELAY_MS..=MAX_REPEAT_DELAY_MS).contains(&self.repeat_delay_ms)
+            || !(MIN_REPEAT_INTERVAL_MS..=MAX_REPEAT_INTERVAL_MS).contains(&self.repeat_interval_ms)
+        {
+            return Err(ScanConfigError::InvalidRepeat);
+        }
+        Ok(())
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum ScanConfigError {
+    InvalidDebounce,
+    InvalidRepeat,
+    StorageError, //Valid, but could not be saved to flash
+}
+
+pub type ScanConfigResult = Result<(), ScanConfigError>;
 
 endpoints! {
     list = ENDPOINT_LIST;
@@ -11,6 +242,18 @@ endpoints! {
     | ----------              | ---------        | ----------           | ----              |
     | SetBacklight            | bool             | ()                   | "setBacklight"    |
     | SetText                 | DisplayText      | ()                   | "setText"         |
+    | GetLcdConfig            | ()               | LcdConfig            | "lcdConfig"       |
+    | GetLcdStatus            | ()               | bool                 | "lcdStatus/get"   |
+    | SetLcdConfig            | LcdConfig        | LcdConfigResult      | "setLcdConfig"    |
+    | QueueMessage            | LcdMessage       | QueueResult          | "queueMessage"    |
+    | CancelMessage           | u8               | ()                   | "cancelMessage"   |
+    | SetCustomGlyph          | CustomGlyph      | CustomGlyphResult    | "setGlyph"        |
+    | GetFirmwareInfo         | ()               | FirmwareInfo         | "firmwareInfo"    |
+    | GetServiceMode          | ()               | bool                 | "serviceMode/get" |
+    | GetScanConfig           | ()               | ScanConfig           | "scanConfig"      |
+    | SetScanConfig           | ScanConfig       | ScanConfigResult     | "setScanConfig"   |
+    | GetKeymap               | ()               | Keymap               | "keymap"          |
+    | SetKeymap               | Keymap           | KeymapResult         | "setKeymap"       |
 }
 
 topics! {
@@ -26,5 +269,11 @@ topics! {
     | TopicTy                   | MessageTy     | Path              | Cfg                           |
     | -------                   | ---------     | ----              | ---                           |
     | ServiceModeTopic          | bool          | "serviceMode"     |                               |
+    | KeyEventTopic             | KeyEvent      | "keyEvent"        |                               |
+    | LcdStatusTopic            | bool          | "lcdStatus"       |                               |
 }
 
+//Hash of every endpoint and topic in this ICD - see icd_common::fingerprint
+pub fn icd_fingerprint() -> u64 {
+    icd_common::fingerprint(&ENDPOINT_LIST, &TOPICS_IN_LIST, &TOPICS_OUT_LIST)
+}
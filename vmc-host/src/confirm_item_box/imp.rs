@@ -8,6 +8,7 @@ pub struct ConfirmItemBox {
     pub item_image: Image,
     pub item_name: Label,
     pub item_price: Label,
+    pub item_description: Label,
 }
 
 #[glib::object_subclass]
@@ -32,10 +33,15 @@ impl ObjectImpl for ConfirmItemBox {
 
         self.item_price.set_use_markup(true);
 
-        //Add the three items to the child pane
+        self.item_description.set_justify(gtk4::Justification::Center);
+        self.item_description.set_use_markup(true);
+        self.item_description.set_wrap(true);
+
+        //Add the items to the child pane
         self.obj().append(&self.item_name);
         self.obj().append(&self.item_image);
         self.obj().append(&self.item_price);
+        self.obj().append(&self.item_description);
 
         self.obj().set_spacing(50);
         self.obj().append(
@@ -25,16 +25,29 @@ impl ConfirmItemBox {
         ));
     }
 
-    pub fn set_name(&self, label: String) {
+    pub fn set_name(&self, label: &str) {
         let i = imp::ConfirmItemBox::from_obj(self);
         i.item_name.set_label(&format!(
             "<span font=\"Arial Rounded MT 50\">{}</span>",
-            &label
+            glib::markup_escape_text(label)
         ));
     }
 
-    pub fn set_image(&self, path: String) {
+    pub fn set_description(&self, description: &str) {
         let i = imp::ConfirmItemBox::from_obj(self);
-        i.item_image.set_from_file(Some(&path));
+        i.item_description.set_label(&format!(
+            "<span font=\"Arial Rounded MT 30\">{}</span>",
+            glib::markup_escape_text(description)
+        ));
+        i.item_description.set_visible(!description.is_empty());
+    }
+
+    //None clears the picture, for items without one
+    pub fn set_image(&self, path: Option<&str>) {
+        let i = imp::ConfirmItemBox::from_obj(self);
+        match path {
+            Some(path) => i.item_image.set_from_file(Some(path)),
+            None => i.item_image.clear(),
+        }
     }
 }
@@ -0,0 +1,293 @@
+//Drives spawn_vmc_driver against the VMC emulator - run with cargo test --features emulator
+use std::sync::OnceLock;
+
+use async_channel::{Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+
+use vmc_emulator::{Emulator, EmulatorConfig, Jam};
+use vmc_icd::bill_validator::{BillInserted, BillRouting, BillValidatorCommand, BillValidatorEvent};
+use vmc_icd::cashless_device::{CashlessDeviceCommand, CashlessDeviceEvent};
+use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinInserted, CoinRouting};
+use vmc_icd::config::VmcConfig;
+use vmc_icd::dispenser::{DispenseError, DispensePhase, DispenserAddress};
+use vmc_icd::icd_fingerprint;
+
+use crate::rpc_shim::spawn_vmc_driver;
+use crate::{VmcCommand, VmcDriver, VmcResponse};
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+//The driver task always connects to vmc_emulator::shared(), so the tests share one driver,
+//and take turns with it
+static VMC_LOCK: Mutex<()> = Mutex::const_new(());
+
+struct Vmc {
+    commands: Sender<VmcCommand>,
+    responses: Receiver<VmcResponse>,
+}
+
+impl Vmc {
+    fn get() -> &'static Vmc {
+        static VMC: OnceLock<Vmc> = OnceLock::new();
+        let vmc = VMC.get_or_init(|| {
+            let (commands, command_rx) = async_channel::unbounded();
+            let (response_tx, responses) = async_channel::unbounded();
+            spawn_vmc_driver(response_tx, command_rx);
+            Vmc { commands, responses }
+        });
+        //Discard anything left over from an earlier test
+        while vmc.responses.try_recv().is_ok() {}
+        vmc
+    }
+
+    async fn send(&self, cmd: VmcCommand) {
+        self.commands.send(cmd).await.expect("VMC driver stopped");
+    }
+
+    //Skips responses until f picks one out
+    async fn wait_for<T>(&self, mut f: impl FnMut(VmcResponse) -> Option<T>) -> T {
+        timeout(RESPONSE_TIMEOUT, async {
+            loop {
+                let response = self.responses.recv().await.expect("VMC driver stopped");
+                if let Some(t) = f(response) {
+                    return t;
+                }
+            }
+        })
+        .await
+        .expect("Timed out waiting for the VMC driver")
+    }
+
+    async fn vend(&self, address: DispenserAddress) -> Result<(), DispenseError> {
+        self.send(VmcCommand::VendItem(address.row, address.col)).await;
+        self.wait_for(|r| match r {
+            VmcResponse::DispenseSuccessEvent => Some(Ok(())),
+            VmcResponse::DispenseFailedEvent(e) => Some(Err(e)),
+            _ => None,
+        })
+        .await
+    }
+
+    //Commands are handled in order, so once this answers, all those before it have been. The
+    //driver only sends the config when asked, unlike the coin acceptor info and machine map
+    async fn sync(&self) {
+        self.send(VmcCommand::GetConfig).await;
+        self.wait_for(|r| matches!(r, VmcResponse::Config(_)).then_some(())).await;
+    }
+}
+
+#[tokio::test]
+async fn vend_progress_is_reported_while_vending() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let address = DispenserAddress { row: 'A', col: '0' };
+    let vends_before = vmc_emulator::shared().vends().len();
+
+    vmc.send(VmcCommand::VendItem(address.row, address.col)).await;
+    let mut phases = Vec::new();
+    vmc.wait_for(|r| match r {
+        VmcResponse::DispenseProgress(progress) => {
+            if phases.is_empty() {
+                assert_eq!(vmc_emulator::shared().vends().len(), vends_before, "Progress arrived after the vend finished");
+            }
+            phases.push(progress.phase);
+            None
+        }
+        VmcResponse::DispenseSuccessEvent => Some(()),
+        VmcResponse::DispenseFailedEvent(e) => panic!("Vend failed - {:?}", e),
+        _ => None,
+    })
+    .await;
+    assert_eq!(phases, [DispensePhase::MotorStarted, DispensePhase::LeftHome, DispensePhase::ReturnedHome]);
+}
+
+#[tokio::test]
+async fn vend_failures_are_reported() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let emulator = vmc_emulator::shared();
+
+    //A missed drop can only be noticed with the drop sensor on
+    vmc.send(VmcCommand::SetConfig(VmcConfig { drop_sensor_enabled: true, ..VmcConfig::DEFAULT })).await;
+    vmc.sync().await;
+    for (address, jam, error) in [
+        (DispenserAddress { row: 'B', col: '0' }, Jam::StuckHome, DispenseError::MotorStuckHome),
+        (DispenserAddress { row: 'C', col: '1' }, Jam::NoDrop, DispenseError::NoDropDetected),
+        (DispenserAddress { row: 'C', col: '2' }, Jam::NotHome, DispenseError::MotorNotHome),
+    ] {
+        emulator.set_jam(address, Some(jam));
+        assert_eq!(vmc.vend(address).await, Err(error));
+        emulator.set_jam(address, None);
+    }
+    vmc.send(VmcCommand::SetConfig(VmcConfig::DEFAULT)).await;
+    vmc.sync().await;
+    assert_eq!(vmc.vend(DispenserAddress { row: 'D', col: '0' }).await, Err(DispenseError::MotorNotPresent));
+}
+
+#[tokio::test]
+async fn missed_drop_is_ignored_without_drop_sensor() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let emulator = vmc_emulator::shared();
+    let address = DispenserAddress { row: 'C', col: '3' };
+
+    emulator.set_jam(address, Some(Jam::NoDrop));
+    assert_eq!(vmc.vend(address).await, Ok(()));
+    emulator.set_jam(address, None);
+}
+
+#[tokio::test]
+async fn last_can_is_not_vended() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let emulator = vmc_emulator::shared();
+    let address = DispenserAddress { row: 'E', col: '0' };
+
+    emulator.set_cans(address, 1);
+    assert_eq!(vmc.vend(address).await, Err(DispenseError::OneOrNoCansLeft));
+    emulator.set_cans(address, EmulatorConfig::default().cans_per_slot);
+    assert_eq!(vmc.vend(address).await, Ok(()));
+}
+
+#[tokio::test]
+async fn card_payments_are_approved_or_denied() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let emulator = vmc_emulator::shared();
+    let address = DispenserAddress { row: 'A', col: '0' };
+    let cashless_event = |r| match r {
+        VmcResponse::CashlessEvent(event) => Some(event),
+        _ => None,
+    };
+
+    emulator.set_cashless_available(true).await;
+    vmc.send(VmcCommand::CashlessCmd(CashlessDeviceCommand::Enable)).await;
+
+    vmc.send(VmcCommand::CashlessCmd(CashlessDeviceCommand::StartTransaction(150, address))).await;
+    vmc.sync().await;
+    emulator.present_card(200).await;
+    assert_eq!(vmc.wait_for(cashless_event).await, CashlessDeviceEvent::VendApproved(150));
+    vmc.send(VmcCommand::CashlessCmd(CashlessDeviceCommand::VendSuccess(address))).await;
+
+    vmc.send(VmcCommand::CashlessCmd(CashlessDeviceCommand::StartTransaction(150, address))).await;
+    vmc.sync().await;
+    emulator.present_card(100).await;
+    assert_eq!(vmc.wait_for(cashless_event).await, CashlessDeviceEvent::VendDenied);
+    vmc.send(VmcCommand::CashlessCmd(CashlessDeviceCommand::CancelTransaction)).await;
+    vmc.send(VmcCommand::CashlessCmd(CashlessDeviceCommand::Disable)).await;
+    vmc.sync().await;
+}
+
+#[tokio::test]
+async fn refunds_report_the_amount_paid() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let coins_refunded = |r| match r {
+        VmcResponse::CoinsRefunded(requested, paid) => Some((requested, paid)),
+        _ => None,
+    };
+
+    vmc.send(VmcCommand::RefundCoins(85)).await;
+    assert_eq!(vmc.wait_for(coins_refunded).await, (85, 85));
+    //Nothing smaller than 5p in the tubes
+    vmc.send(VmcCommand::RefundCoins(87)).await;
+    assert_eq!(vmc.wait_for(coins_refunded).await, (87, 85));
+}
+
+#[tokio::test]
+async fn coins_are_reported() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let emulator = vmc_emulator::shared();
+
+    vmc.send(VmcCommand::SetCoinAcceptorEnabled(true)).await;
+    vmc.sync().await;
+    emulator.insert_coin(200).await;
+    let coin = vmc.wait_for(|r| match r {
+        VmcResponse::CoinInsertedEvent(coin) => Some(coin),
+        _ => None,
+    })
+    .await;
+    assert_eq!(coin, CoinInserted { value: 200, routing: CoinRouting::CashBox });
+
+    vmc.send(VmcCommand::SetCoinAcceptorEnabled(false)).await;
+    vmc.sync().await;
+    emulator.insert_coin(200).await;
+    let coin = vmc.wait_for(|r| match r {
+        VmcResponse::CoinInsertedEvent(coin) => Some(coin),
+        _ => None,
+    })
+    .await;
+    assert_eq!(coin.routing, CoinRouting::Reject);
+
+    emulator.coin_acceptor_event(CoinAcceptorEvent::EscrowPressed).await;
+    let event = vmc.wait_for(|r| match r {
+        VmcResponse::CoinAcceptorEvent(event) => Some(event),
+        _ => None,
+    })
+    .await;
+    assert_eq!(event, CoinAcceptorEvent::EscrowPressed);
+}
+
+#[tokio::test]
+async fn bills_are_reported() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+    let emulator = vmc_emulator::shared();
+    let bill_inserted = |r| match r {
+        VmcResponse::BillInsertedEvent(bill) => Some(bill),
+        _ => None,
+    };
+
+    vmc.send(VmcCommand::BillValidatorCmd(BillValidatorCommand::Enable)).await;
+    vmc.sync().await;
+    emulator.insert_bill(500).await;
+    assert_eq!(vmc.wait_for(bill_inserted).await, BillInserted { value: 500, routing: BillRouting::Escrow });
+    vmc.send(VmcCommand::BillValidatorCmd(BillValidatorCommand::AcceptEscrow)).await;
+    assert_eq!(vmc.wait_for(bill_inserted).await, BillInserted { value: 500, routing: BillRouting::Stacked });
+
+    emulator.insert_bill(1000).await;
+    assert_eq!(vmc.wait_for(bill_inserted).await.routing, BillRouting::Escrow);
+    vmc.send(VmcCommand::BillValidatorCmd(BillValidatorCommand::RejectEscrow)).await;
+    assert_eq!(vmc.wait_for(bill_inserted).await, BillInserted { value: 1000, routing: BillRouting::Returned });
+    vmc.send(VmcCommand::BillValidatorCmd(BillValidatorCommand::Disable)).await;
+
+    emulator.bill_validator_event(BillValidatorEvent::Jammed).await;
+    let event = vmc.wait_for(|r| match r {
+        VmcResponse::BillValidatorEvent(event) => Some(event),
+        _ => None,
+    })
+    .await;
+    assert_eq!(event, BillValidatorEvent::Jammed);
+}
+
+#[tokio::test]
+async fn chiller_info_is_published_periodically() {
+    let _lock = VMC_LOCK.lock().await;
+    let vmc = Vmc::get();
+
+    vmc.send(VmcCommand::SetConfig(VmcConfig { chiller_measure_interval_ms: 100, ..VmcConfig::DEFAULT })).await;
+    for _ in 0..2 {
+        vmc.wait_for(|r| matches!(r, VmcResponse::ChillerInfo(_)).then_some(())).await;
+    }
+    vmc.send(VmcCommand::SetConfig(VmcConfig::DEFAULT)).await;
+}
+
+#[tokio::test]
+async fn matching_firmware_is_accepted() {
+    let emulator = Emulator::new(EmulatorConfig::default());
+    assert!(VmcDriver::from_client(emulator.connect()).await.is_ok());
+}
+
+#[tokio::test]
+async fn mismatched_firmware_is_refused() {
+    let emulator = Emulator::new(EmulatorConfig {
+        icd_fingerprint: Some(!icd_fingerprint()),
+        ..EmulatorConfig::default()
+    });
+    match VmcDriver::from_client(emulator.connect()).await {
+        Ok(_) => panic!("Firmware built against a different ICD was accepted"),
+        Err(e) => assert!(e.contains("different ICD"), "Unexpected error - {}", e),
+    }
+}
@@ -0,0 +1,55 @@
+//Maps text onto the HD44780's character set. The LCD's character ROM (A00) is ASCII from 0x20 to
+//0x7D (except 0x5C, which is a Yen sign), plus some katakana and symbols above 0x80. Anything
+//else we want on screen is drawn as a custom glyph, uploaded to the LCD's CGRAM on connection.
+
+use keyboard_icd::{DisplayLine, DisplayText, MAX_LCD_ROWS, MAX_LINE_LEN};
+
+use crate::lcd_driver::{KEY_DOWN, KEY_UP};
+
+//Displayed as character codes 0 upwards, in this order - at most 8
+pub const CUSTOM_GLYPHS: [(char, [u8; 8]); 6] = [
+    ('£', [0b00110, 0b01001, 0b01000, 0b11110, 0b01000, 0b01000, 0b11111, 0b00000]),
+    ('€', [0b00011, 0b00100, 0b11110, 0b01000, 0b11110, 0b00100, 0b00011, 0b00000]),
+    (KEY_UP, [0b00100, 0b01110, 0b10101, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000]),
+    (KEY_DOWN, [0b00100, 0b00100, 0b00100, 0b00100, 0b10101, 0b01110, 0b00100, 0b00000]),
+    ('✓', [0b00000, 0b00001, 0b00011, 0b10110, 0b11100, 0b01000, 0b00000, 0b00000]),
+    ('✗', [0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b00000, 0b00000]),
+];
+
+//Shown for anything the LCD can't display
+const UNKNOWN_CHAR: u8 = b'?';
+
+pub fn encode_char(c: char) -> u8 {
+    if let Some(index) = CUSTOM_GLYPHS.iter().position(|(glyph, _)| *glyph == c) {
+        return index as u8;
+    }
+    match c {
+        '\\' => UNKNOWN_CHAR,
+        ' '..='}' => c as u8,
+        '→' => 0x7E,
+        '←' => 0x7F,
+        '°' => 0xDF,
+        'α' => 0xE0,
+        'ä' => 0xE1,
+        'β' => 0xE2,
+        'µ' => 0xE4,
+        'ö' => 0xEF,
+        'Ω' => 0xF4,
+        'ü' => 0xF5,
+        '÷' => 0xFD,
+        '✔' => encode_char('✓'),
+        '✘' | '×' => encode_char('✗'),
+        _ => UNKNOWN_CHAR,
+    }
+}
+
+//Encodes a line of text for the LCD, cut to the longest line it will take. Works in characters
+//rather than bytes, so multibyte text can't be cut mid-character
+pub fn encode_line(text: &str) -> DisplayLine {
+    text.chars().take(MAX_LINE_LEN).map(encode_char).collect()
+}
+
+//Lines beyond the most the LCD could have are dropped
+pub fn encode_text(lines: &[String]) -> DisplayText {
+    lines.iter().take(MAX_LCD_ROWS).map(|line| encode_line(line)).collect()
+}
@@ -4,14 +4,55 @@ use postcard_rpc::{
     standard_icd::{PingEndpoint, WireError, ERROR_PATH},
 };
 
-use keyboard_icd::{SetBacklight, SetText};
+use keyboard_icd::{
+    check_firmware, icd_fingerprint, CancelMessage, CustomGlyph, GetFirmwareInfo, GetLcdStatus, GetServiceMode, LcdMessage, QueueError,
+    QueueMessage, SetBacklight, SetCustomGlyph, SetText,
+};
+
+use crate::lcd_charset::{encode_text, CUSTOM_GLYPHS};
 
 use std::convert::Infallible;
+use std::time::Duration;
 
 
+pub const KEY_UP: char = '\u{2191}';
+pub const KEY_DOWN: char = '\u{2193}';
+
 pub enum LcdCommand {
-    SetText(String, String),
+    SetText(Vec<String>), //The idle message - shown when no others are queued
     SetBackLight(bool),
+    QueueMessage(LcdQueuedMessage),
+    CancelMessage(u8),
+}
+
+//Shown in place of the idle message until it expires or is cancelled - see keyboard_icd::LcdMessage
+pub struct LcdQueuedMessage {
+    pub id: u8,
+    pub priority: u8,
+    pub duration: Duration, //Zero to show it until cancelled
+    pub lines: Vec<String>, //Top first - any beyond the LCD's rows are ignored
+}
+
+//Events reported by the keyboard's topics
+pub enum LcdEvent {
+    Keypress(char),
+    ServiceMode(bool),
+    LcdPresent(bool), //Whether the keyboard can see its LCD
+}
+
+//Maps the USB HID usage ID of a keypad key to the character the app expects
+pub fn keycode_to_char(keycode: u8) -> Option<char> {
+    match keycode {
+        0x04..=0x1D => Some((b'A' + (keycode - 0x04)) as char),
+        0x1E..=0x26 => Some((b'1' + (keycode - 0x1E)) as char),
+        0x27 => Some('0'),
+        0x28 => Some('\n'),
+        0x29 => Some('\x1B'),
+        0x51 => Some(KEY_DOWN),
+        0x52 => Some(KEY_UP),
+        0x75 => Some('?'), //Help - for keypads with an info key
+        _ => None,
+    }
 }
 
 #[derive(Debug)]
@@ -31,16 +72,36 @@ pub struct LcdDriver {
 }
 
 impl LcdDriver {
-    pub fn new() -> Result<Self, String> {
-        match HostClient::try_new_raw_nusb(
+    pub async fn new() -> Result<Self, String> {
+        let driver = HostClient::try_new_raw_nusb(
             |c| c.product_string() == Some("matrix-keyboard"),
             ERROR_PATH,
             8,
             VarSeqKind::Seq2,
-        ) {
-            Ok(driver) => Ok(Self { driver }),
-            Err(x) => Err(x),
+        )?;
+        let mut lcd = Self { driver };
+        if let Err(e) = lcd.check_firmware().await {
+            lcd.driver.close();
+            return Err(e);
         }
+        if let Err(e) = lcd.upload_glyphs().await {
+            println!("Error - failed to upload custom LCD glyphs - {:?}", e);
+        }
+        Ok(lcd)
+    }
+
+    //Refuse firmware built against a different keyboard-icd - its endpoints and topics
+    //would otherwise fail later in confusing ways
+    async fn check_firmware(&mut self) -> Result<(), String> {
+        let info = self
+            .driver
+            .send_resp::<GetFirmwareInfo>(&())
+            .await
+            .map_err(|e| format!("Keyboard firmware did not report its version ({:?}) - it is too old and needs reflashing", e))?;
+
+        check_firmware(info, icd_fingerprint()).map_err(|e| format!("Keyboard {} - reflash the keyboard or rebuild the host", e))?;
+        println!("Keyboard firmware v{}.{}.{}", info.version_major, info.version_minor, info.version_patch);
+        Ok(())
     }
 
     pub async fn ping(&mut self, seq: u32) -> bool {
@@ -58,25 +119,52 @@ impl LcdDriver {
         }
     }
 
+    pub async fn get_service_mode(&mut self) -> Result<bool, LcdClientError<Infallible>> {
+        Ok(self.driver.send_resp::<GetServiceMode>(&()).await?)
+    }
+
+    pub async fn get_lcd_present(&mut self) -> Result<bool, LcdClientError<Infallible>> {
+        Ok(self.driver.send_resp::<GetLcdStatus>(&()).await?)
+    }
+
+    //The LCD shows these for the characters in CUSTOM_GLYPHS, eg £
+    pub async fn upload_glyphs(&mut self) -> Result<(), LcdClientError<()>> {
+        for (index, (_, rows)) in CUSTOM_GLYPHS.iter().enumerate() {
+            let glyph = CustomGlyph {
+                index: index as u8,
+                rows: *rows,
+            };
+            self.driver
+                .send_resp::<SetCustomGlyph>(&glyph)
+                .await?
+                .map_err(LcdClientError::Endpoint)?;
+        }
+        Ok(())
+    }
+
     pub async fn set_backlight(&mut self, on: bool) -> Result<(), LcdClientError<Infallible>> {
         let _res = self.driver.send_resp::<SetBacklight>(&on).await?;
         Ok(())
     }
 
-    pub async fn set_text(
-        &mut self,
-        line1: String,
-        line2: String,
-    ) -> Result<(), LcdClientError<Infallible>> {
-        let mut l1_copy = line1;
-        l1_copy.truncate(32);
-        let mut l2_copy = line2;
-        l2_copy.truncate(32);
-
+    pub async fn set_text(&mut self, lines: &[String]) -> Result<(), LcdClientError<Infallible>> {
         //Trailing whitespace stripped off at remote end
-        let l1: [u8; 32] = format!("{: <32}", l1_copy).as_bytes().try_into().unwrap();
-        let l2: [u8; 32] = format!("{: <32}", l2_copy).as_bytes().try_into().unwrap();
-        let _res = self.driver.send_resp::<SetText>(&([l1, l2])).await?;
+        let _res = self.driver.send_resp::<SetText>(&encode_text(lines)).await?;
+        Ok(())
+    }
+
+    pub async fn queue_message(&mut self, message: LcdQueuedMessage) -> Result<(), LcdClientError<QueueError>> {
+        let message = LcdMessage {
+            id: message.id,
+            priority: message.priority,
+            duration_ms: message.duration.as_millis().try_into().unwrap_or(u32::MAX),
+            text: encode_text(&message.lines),
+        };
+        self.driver.send_resp::<QueueMessage>(&message).await?.map_err(LcdClientError::Endpoint)
+    }
+
+    pub async fn cancel_message(&mut self, id: u8) -> Result<(), LcdClientError<Infallible>> {
+        let _res = self.driver.send_resp::<CancelMessage>(&id).await?;
         Ok(())
     }
 }
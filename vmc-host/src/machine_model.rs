@@ -0,0 +1,47 @@
+use vmc_icd::dispenser::{Dispenser, DispenserAddress};
+
+//Host-side cache of the dispensers fitted to the machine, as last reported by the VMC.
+//Populated from a machine map when the VMC connects, and refreshed per-dispenser after vends
+#[derive(Default)]
+pub struct MachineModel {
+    dispensers: Vec<Dispenser>,
+    mapped: bool,
+}
+
+impl MachineModel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //Replace the whole model with a freshly received machine map
+    pub fn set_map(&mut self, dispensers: Vec<Dispenser>) {
+        self.dispensers = dispensers;
+        self.mapped = true;
+    }
+
+    //Update (or add) the status of a single dispenser
+    pub fn update(&mut self, dispenser: Dispenser) {
+        match self.dispensers.iter_mut().find(|d| d.address == dispenser.address) {
+            Some(d) => *d = dispenser,
+            None => self.dispensers.push(dispenser),
+        }
+    }
+
+    //Whether a map has been received from the VMC yet
+    pub fn is_mapped(&self) -> bool {
+        self.mapped
+    }
+
+    pub fn dispenser(&self, address: DispenserAddress) -> Option<&Dispenser> {
+        self.dispensers.iter().find(|d| d.address == address)
+    }
+
+    //Until the machine has been mapped, every address is assumed to be fitted
+    pub fn is_fitted(&self, address: DispenserAddress) -> bool {
+        !self.mapped || self.dispenser(address).is_some()
+    }
+
+    pub fn dispensers(&self) -> &[Dispenser] {
+        &self.dispensers
+    }
+}
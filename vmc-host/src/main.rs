@@ -1,5 +1,9 @@
 mod stock_info;
-use crate::stock_info::get_stock_item;
+use crate::stock_info::{Catalogue, DEFAULT_CATALOGUE_PATH};
+mod stock_levels;
+use crate::stock_levels::{address_key, SlotStock, StockLevels, DEFAULT_STOCK_PATH};
+mod sales_ledger;
+use crate::sales_ledger::{export_sales_command, SaleRecord, SalesLedger, SessionOutcome, DEFAULT_LEDGER_PATH};
 
 mod make_selection_box;
 use crate::make_selection_box::MakeSelectionBox;
@@ -18,20 +22,38 @@ use vend_ok_box::VendOkBox;
 mod vend_failed_box;
 use vend_failed_box::VendFailedBox;
 
+mod machine_model;
+use machine_model::MachineModel;
+
+mod service_menu;
+use service_menu::{ServiceAction, ServiceMenu, ServiceView};
+mod service_mode_box;
+use service_mode_box::ServiceModeBox;
+
+mod lcd_charset;
 mod lcd_driver;
 use gtk4::builders::ImageBuilder;
-use lcd_driver::{LcdCommand, LcdDriver};
+use lcd_driver::{LcdCommand, LcdDriver, LcdEvent, LcdQueuedMessage};
 
 mod vmc_driver;
 use vmc_driver::{VmcCommand, VmcDriver, VmcResponse};
 
 mod rpc_shim;
 use rpc_shim::{spawn_lcd_driver, spawn_vmc_driver};
+#[cfg(all(test, feature = "emulator"))]
+mod emulator_tests;
 
-use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinInserted, CoinRouting};
-use vmc_icd::dispenser::{Dispenser, DispenserAddress};
+mod management_api;
+mod metrics;
+use metrics::VendMode;
+use management_api::{spawn_management_api, MachineStatus, ManagementApi, SlotStatus, DEFAULT_API_ADDR};
+
+use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinAcceptorInfo, CoinInserted, CoinRouting};
+use vmc_icd::bill_validator::{BillRouting, BillValidatorCommand};
+use vmc_icd::dispenser::{DispenseError, DispenseProgress, Dispenser, DispenserAddress};
 use vmc_icd::EventTopic;
 use vmc_icd::cashless_device::{CashlessDeviceCommand, CashlessDeviceEvent};
+use vmc_icd::chiller::ChillerInfo;
 
 const APP_ID: &str = "uk.org.makerspace.snackbot";
 
@@ -41,7 +63,18 @@ const VMC_DEVICE_NAME: &str = "vmc";
 const IDLE_MESSAGE_L1: &str = "Plz buy m0ar";
 const IDLE_MESSAGE_L2: &str = "snackz kthx";
 
+const MAINTENANCE_MESSAGE_L1: &str = "Out of service";
+const MAINTENANCE_MESSAGE_L2: &str = "Back soon!";
+
 const PAY_MESSAGE_L1: &str = "Please pay:";
+const PAY_MESSAGE_L4: &str = "Coins, notes or card";
+
+//LCD message ids and priorities - the keyboard firmware shows the idle message once these expire
+const LCD_MESSAGE_PAYMENT: u8 = 1;
+const LCD_MESSAGE_VEND: u8 = 2;
+const LCD_PRIORITY_PAYMENT: u8 = 1;
+const LCD_PRIORITY_VEND: u8 = 2;
+const LCD_VEND_RESULT_SECONDS: u64 = 5;
 
 const APP_TIMEOUT_SECONDS: u16 = 30;
 
@@ -51,13 +84,21 @@ use gtk4::prelude::*;
 use gtk4::{Application, ApplicationWindow, Box, Button, Image, Label, Stack};
 
 use async_channel::{Receiver, Sender};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 
+//Keypresses normally come straight from the keypad via the keyboard's KeyEventTopic. With the
+//emulated VMC there's usually no keypad plugged in, so take them from the desktop keyboard instead
+#[cfg(feature = "emulator")]
 fn keypress_listener(sender: Sender<Event>) -> gtk4::EventControllerKey {
     let event_controller = gtk4::EventControllerKey::new();
     event_controller.connect_key_pressed(move |_, key, _, _| {
         let c = match key {
             gdk4::Key::Escape => '\x1B',
             gdk4::Key::Return => '\n',
+            gdk4::Key::Up => lcd_driver::KEY_UP,
+            gdk4::Key::Down => lcd_driver::KEY_DOWN,
             gdk4::Key::a => 'A',
             gdk4::Key::b => 'B',
             gdk4::Key::c => 'C',
@@ -79,7 +120,7 @@ fn keypress_listener(sender: Sender<Event>) -> gtk4::EventControllerKey {
             _ => ' ',
         };
 
-        if c.is_ascii_alphanumeric() || c == '\n' || c == '\x1B' {
+        if c.is_ascii_alphanumeric() || c == '\n' || c == '\x1B' || c == lcd_driver::KEY_UP || c == lcd_driver::KEY_DOWN {
             match sender.send_blocking(Event::Keypress(c)) {
                 Ok(()) => {}
                 Err(e) => {
@@ -93,17 +134,46 @@ fn keypress_listener(sender: Sender<Event>) -> gtk4::EventControllerKey {
 }
 
 //These are events the main loop should respond to
+#[derive(Serialize, Debug)]
 enum Event {
     Keypress(char),
+    ServiceMode(bool),
+    Maintenance(bool), //Out of service, set from the management API
+    LcdPresent(bool),
     EscrowPressed,
     CoinInserted(u16),
+    BillInserted(u16),  //Bill stacked - now credit
+    BillEscrowed(u16),  //Bill held in escrow, awaiting accept/reject
+    CoinAcceptorInfo(Option<CoinAcceptorInfo>),
+    CoinsRefunded(u16, u16), //Amount requested, amount actually paid out
     Timeout_Poll_Event,
     ChangeState(AppState),
     CashlessEvent(CashlessDeviceEvent),
     VendSuccess,
-    VendFailed,
+    VendFailed(DispenseError),
+    DispenseProgress(DispenseProgress),
+    ChillerInfo(ChillerInfo),
+    MachineMap(Vec<Dispenser>),
+    DispenserStatus(Dispenser),
 }
 
+//How the current selection is being paid for
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum PaymentMethod {
+    Cash, //Coins and/or notes
+    Cashless,
+}
+
+//Sent on the management API's event stream, so whoever restocks the machine can be told
+#[derive(Serialize, Debug)]
+struct LowStockAlert<'a> {
+    slot: &'a str,
+    count: u16,
+    sold_out: bool,
+}
+
+#[derive(Serialize, Debug)]
 enum AppState {
     Idle,
     MakeAnotherSelection,
@@ -112,20 +182,43 @@ enum AppState {
     Vending,
     VendSuccess,
     VendFailed,
+    ServiceMode, //Service switch on - the keypad drives the service menu instead
 }
 
 struct App {
     pub state: AppState,
+    pub service_mode: bool,
+    pub maintenance: bool,
+    pub lcd_present: bool,
+    pub chiller_info: Option<ChillerInfo>,
     pub credit: u16,
     pub amount_due: u16,
+    pub payment_method: Option<PaymentMethod>,
+    pub coin_acceptor_info: Option<CoinAcceptorInfo>,
+    pub cashless_available: Option<bool>,
     pub row_selected: Option<char>,
     pub col_selected: Option<char>,
 
+    pub machine: MachineModel,
+    pub service_menu: ServiceMenu,
+    pub catalogue: Catalogue,
+    pub stock: StockLevels,
+    pub ledger: SalesLedger,
+    pub session_started: Option<DateTime<Utc>>, //When the customer confirmed their selection
+    pub cashless_approved: Option<u16>,
+    pub pending_refunds: VecDeque<Option<DispenserAddress>>, //The slot each refund was for, oldest first
+    pub escrowed_bill: Option<u16>, //Waiting on the coin acceptor info to decide whether to take it
+
     pub stack: Stack,
     pub make_selection_box: MakeSelectionBox,
     pub confirm_item_box: ConfirmItemBox,
     pub make_payment_box: MakePaymentBox,
     pub make_another_selection_box: MakeAnotherSelectionBox,
+    pub vend_in_progress_box: VendInProgressBox,
+    pub vend_failed_box: VendFailedBox,
+    pub service_mode_box: ServiceModeBox,
+
+    pub api: ManagementApi,
 
     pub lcd_channel: Sender<LcdCommand>,
     pub vmc_command_channel: Sender<VmcCommand>,
@@ -142,6 +235,10 @@ impl App {
         event_channel_rx: Receiver<Event>,
         lcd_channel: Sender<LcdCommand>,
         vmc_command_channel: Sender<VmcCommand>,
+        catalogue: Catalogue,
+        stock: StockLevels,
+        ledger: SalesLedger,
+        api: ManagementApi,
     ) -> Self {
         //All the pages are stored in this widget stack
         let stack = Stack::builder().build();
@@ -153,6 +250,7 @@ impl App {
         let vend_in_progress_box = VendInProgressBox::new();
         let vend_ok_box = VendOkBox::new();
         let vend_failed_box = VendFailedBox::new();
+        let service_mode_box = ServiceModeBox::new();
 
         stack.add_named(&make_selection_box, Some("make_selection_box"));
         stack.add_named(&confirm_item_box, Some("confirm_item_box"));
@@ -161,6 +259,7 @@ impl App {
         stack.add_named(&vend_in_progress_box, Some("vend_in_progress_box"));
         stack.add_named(&vend_ok_box, Some("vend_ok_box"));
         stack.add_named(&vend_failed_box, Some("vend_failed_box"));
+        stack.add_named(&service_mode_box, Some("service_mode_box"));
 
         let window = ApplicationWindow::builder()
             .application(app)
@@ -170,24 +269,49 @@ impl App {
             .height_request(800)
             .build();
 
+        #[cfg(feature = "emulator")]
         window.add_controller(keypress_listener(event_channel_tx.clone()));
 
         window.present();
 
-        let _ = lcd_channel.send_blocking(LcdCommand::SetText(String::from(IDLE_MESSAGE_L1), String::from(IDLE_MESSAGE_L2)));
+        let _ = lcd_channel.send_blocking(LcdCommand::SetText(vec![String::from(IDLE_MESSAGE_L1), String::from(IDLE_MESSAGE_L2)]));
+
+        //Ask for the drinks temperature now, rather than waiting for the next periodic update
+        let _ = vmc_command_channel.send_blocking(VmcCommand::GetChillerInfo);
 
         Self {
             state: AppState::Idle,
+            service_mode: false,
+            maintenance: false,
+            lcd_present: true, //Until the keyboard tells us otherwise
+            chiller_info: None,
             credit: 0,
             amount_due: 0,
+            payment_method: None,
+            coin_acceptor_info: None,
+            cashless_available: None,
             row_selected: None,
             col_selected: None,
+            machine: MachineModel::new(),
+            service_menu: ServiceMenu::new(),
+            catalogue,
+            stock,
+            ledger,
+            session_started: None,
+            cashless_approved: None,
+            pending_refunds: VecDeque::new(),
+            escrowed_bill: None,
             stack,
 
             make_selection_box,
             confirm_item_box,
             make_payment_box,
             make_another_selection_box,
+            vend_in_progress_box,
+            vend_failed_box,
+            service_mode_box,
+
+            api,
 
             lcd_channel,
             vmc_command_channel,
@@ -202,9 +326,20 @@ impl App {
         //Handle timeout events separately from main state machine
         match event {
             Event::Timeout_Poll_Event => {
-                if !matches!(self.state, AppState::Idle) {
+                //Pick up any edits to the catalogue or stock levels, and show them if an item is on screen
+                let catalogue_changed = self.catalogue.reload_if_changed();
+                let stock_changed = self.stock.reload_if_changed();
+                if (catalogue_changed || stock_changed) && matches!(self.state, AppState::AwaitingConfirmation) {
+                    self.update_ui();
+                }
+                //Service mode lasts as long as the switch is on. A vend always ends with the VMC's
+                //success or failure, which records the sale (or refunds) - don't abandon it before then
+                if !matches!(self.state, AppState::Idle | AppState::ServiceMode | AppState::Vending) {
                     if self.seconds_since_last_event == APP_TIMEOUT_SECONDS {
                         println!("Timeout - return to idle state");
+                        if matches!(self.state, AppState::AwaitingPayment) {
+                            self.cancel_payment(SessionOutcome::TimedOut);
+                        }
                         self.state = AppState::Idle;
                         self.seconds_since_last_event = 0;
                         self.update_ui();
@@ -216,10 +351,119 @@ impl App {
                 return;
             }
             Event::ChangeState(state) => {
+                //Eg a vend result screen timing out - don't leave service mode for it
+                if matches!(self.state, AppState::ServiceMode) {
+                    return;
+                }
+                if self.service_mode && matches!(state, AppState::Idle) {
+                    //Switched on while a customer was vending
+                    self.enter_service_mode();
+                    return;
+                }
                 self.state = state;
                 self.update_ui();
                 return;
             }
+            Event::DispenseProgress(progress) => {
+                println!("Vend {}{}: {:?} after {}ms", progress.address.row, progress.address.col, progress.phase, progress.elapsed_ms);
+                self.vend_in_progress_box.set_phase(progress.phase);
+                return;
+            }
+            Event::ServiceMode(enabled) => {
+                println!("Service mode switch {}", if enabled { "on" } else { "off" });
+                self.service_mode = enabled;
+                if enabled {
+                    self.enter_service_mode();
+                } else if matches!(self.state, AppState::ServiceMode) {
+                    self.row_selected = None;
+                    self.col_selected = None;
+                    self.state = AppState::Idle;
+                    self.update_ui();
+                }
+                return;
+            }
+            Event::LcdPresent(present) => {
+                if present != self.lcd_present {
+                    if present {
+                        println!("Keypad LCD found");
+                    } else {
+                        println!("Fault - keypad LCD not responding");
+                    }
+                }
+                self.lcd_present = present;
+                self.update_fault();
+                return;
+            }
+            Event::Maintenance(enabled) => {
+                println!("Maintenance mode {}", if enabled { "on" } else { "off" });
+                self.maintenance = enabled;
+                self.update_fault();
+                //Stop selling, but let a customer already paying finish - they can't be
+                //sent away with their money in the machine
+                if enabled && matches!(self.state, AppState::AwaitingConfirmation) {
+                    self.row_selected = None;
+                    self.col_selected = None;
+                    self.state = AppState::Idle;
+                }
+                if matches!(self.state, AppState::Idle) {
+                    self.update_ui();
+                }
+                return;
+            }
+            Event::ChillerInfo(info) => {
+                //Status update only - not a user interaction, so doesn't reset the timeout
+                self.make_selection_box.set_drinks_temperature(info.current_temp);
+                self.chiller_info = Some(info);
+                return;
+            }
+            Event::CoinAcceptorInfo(info) => {
+                self.coin_acceptor_info = info;
+                if let Some(value) = self.escrowed_bill.take() {
+                    self.accept_or_return_bill(value);
+                }
+                self.refresh_service_ui();
+                return;
+            }
+            Event::CoinsRefunded(requested, paid) => {
+                //Refunds are answered in the order they were asked for
+                let address = self.pending_refunds.pop_front().flatten();
+                if paid < requested {
+                    self.record_short_refund(address, requested, paid);
+                }
+                return;
+            }
+            Event::CashlessEvent(CashlessDeviceEvent::Available) | Event::CashlessEvent(CashlessDeviceEvent::Unavailable) => {
+                self.cashless_available = Some(matches!(event, Event::CashlessEvent(CashlessDeviceEvent::Available)));
+                self.refresh_service_ui();
+                return;
+            }
+            Event::BillEscrowed(value) if !matches!(self.state, AppState::AwaitingPayment) => {
+                //Not expecting payment - hand it straight back
+                println!("Returning escrowed bill of {} - not awaiting payment", value);
+                let _ = self.vmc_command_channel.send_blocking(VmcCommand::BillValidatorCmd(BillValidatorCommand::RejectEscrow));
+                return;
+            }
+            Event::CoinInserted(value) | Event::BillInserted(value) if !matches!(self.state, AppState::AwaitingPayment) => {
+                //Taken just as the acceptors were being disabled - the customer gets it back
+                //with their change once the vend finishes, or straight away if there isn't one
+                println!("Cash of {} inserted when not awaiting payment", value);
+                self.credit += value;
+                if !matches!(self.state, AppState::Vending) {
+                    self.refund_credit();
+                }
+                return;
+            }
+            Event::MachineMap(dispensers) => {
+                println!("Machine mapped - {} dispensers fitted", dispensers.len());
+                self.machine.set_map(dispensers);
+                self.refresh_service_ui();
+                return;
+            }
+            Event::DispenserStatus(dispenser) => {
+                self.machine.update(dispenser);
+                self.refresh_service_ui();
+                return;
+            }
             _ => {
                 //Another event occurred - reset timer
                 self.seconds_since_last_event = 0;
@@ -230,6 +474,9 @@ impl App {
             AppState::Idle => {
                 //In idle, we are waiting for key press events to select an item
                 match event {
+                    Event::Keypress(_) if self.maintenance => {
+                        println!("Out of service - ignoring keypress");
+                    }
                     Event::Keypress(key) => {
                         if key.is_alphabetic() {
                             //Row selected
@@ -265,23 +512,35 @@ impl App {
                     Event::Keypress(key) => {
                         match key {
                             '\n' => {
-                                //Into payment sate
-                                self.state = AppState::AwaitingPayment;
-
-                                //Find the item and set the balance
-                                match get_stock_item(DispenserAddress {
+                                let address = DispenserAddress {
                                     row: self.row_selected.unwrap(),
                                     col: self.col_selected.unwrap(),
-                                }) {
+                                };
+                                //Find the item and set the balance
+                                match self.catalogue.get_stock_item(address) {
+                                    Some(_) if self.stock.is_sold_out(address) => {
+                                        //Sold out while on screen
+                                        self.offer_another_selection("Sold out");
+                                        return;
+                                    }
                                     Some(item) => {
                                         self.amount_due = item.price;
                                     }
                                     None => {
-                                        println!("Error - item no longer found - shouldnt happen!");
+                                        //Removed from the catalogue while on screen
+                                        println!("Item no longer in the catalogue");
+                                        self.offer_another_selection("");
+                                        return;
                                     }
                                 }
-                                //Enable coin acceptor
-                                //let _ = self.vmc_command_channel.send_blocking(VmcCommand::SetCoinAcceptorEnabled(true));
+                                //Into payment sate
+                                self.state = AppState::AwaitingPayment;
+                                self.session_started = Some(Utc::now());
+                                self.cashless_approved = None;
+
+                                //Enable coin acceptor and bill validator, and find out how much change we can give
+                                self.set_cash_acceptors_enabled(true);
+                                let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetCoinAcceptorInfo);
 
                                 //Set amount for card reader
                                 let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(
@@ -312,15 +571,7 @@ impl App {
                         match key {
                             '\x1b' => {
                                 //Cancel
-                                self.row_selected = None;
-                                self.col_selected = None;
-                                self.state = AppState::Idle;
-                                //Disable coin acceptor
-                                let _ = self.vmc_command_channel.send_blocking(VmcCommand::SetCoinAcceptorEnabled(false));
-
-                                //Cancel card reader transaction
-                                let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(
-                                    vmc_icd::cashless_device::CashlessDeviceCommand::CancelTransaction));            
+                                self.cancel_payment(SessionOutcome::Cancelled);
                             },
                             _=> {},
                         }
@@ -328,33 +579,32 @@ impl App {
                     Event::EscrowPressed => {
                         println!("Got escrow");
                         //Also acts as cancel.
-                         //Cancel
-                         self.row_selected = None;
-                         self.col_selected = None;
-                         self.state = AppState::Idle;
-                         self.amount_due = 0;
-                         //Disable coin acceptor
-                         let _ = self.vmc_command_channel.send_blocking(VmcCommand::SetCoinAcceptorEnabled(false));
-                         //Cancel the cashless transaction
-                         let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::CancelTransaction));
-                         //Need to refund coins if any inserted
-                    },/*
-                    Event::CoinInserted(value) => {
-                        //Update the credit
-                        self.credit += value;
-                        if self.credit >= self.amount_due {
-                            //Move to vend
-                            self.state = AppState::Vending;
-                            let _ = self.vmc_command_channel.send_blocking(VmcCommand::VendItem(self.row_selected.unwrap(), self.col_selected.unwrap()));
+                        self.cancel_payment(SessionOutcome::Cancelled);
+                    },
+                    Event::CoinInserted(value) | Event::BillInserted(value) => {
+                        self.add_cash_credit(value);
+                    }
+                    Event::BillEscrowed(value) => {
+                        if self.coin_acceptor_info.is_some() {
+                            self.accept_or_return_bill(value);
+                        } else {
+                            //Held in escrow until we know what's in the tubes
+                            println!("Holding bill of {} until the coin tube levels are known", value);
+                            self.escrowed_bill = Some(value);
+                            let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetCoinAcceptorInfo);
                         }
-                    }*/
+                    }
                     Event::CashlessEvent(e) => {
                         match e {
                             CashlessDeviceEvent::VendApproved(amount) => {
                                 println!("Vend approved for amount: {}",amount);
+                                self.cashless_approved = Some(amount);
                                 if amount == self.amount_due {
-                                    let _ = self.vmc_command_channel.send_blocking(VmcCommand::VendItem(self.row_selected.unwrap(), self.col_selected.unwrap()));
-                                    self.state = AppState::Vending;
+                                    //Card is paying in full - give back any cash inserted so far
+                                    self.set_cash_acceptors_enabled(false);
+                                    self.refund_credit();
+                                    self.payment_method = Some(PaymentMethod::Cashless);
+                                    self.start_vend();
                                 }
                                 else {
                                     //This shouldn't happen - cancel.
@@ -378,40 +628,414 @@ impl App {
                 //Only two events acceptable here - success or failed.
                 match event {
                     Event::VendSuccess => {
-                        //Send massage to cashless device to confirm vend successful, to end transaction
-                        let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::VendSuccess(DispenserAddress { row: self.row_selected.unwrap(), col: self.col_selected.unwrap() })));
+                        let address = DispenserAddress { row: self.row_selected.unwrap(), col: self.col_selected.unwrap() };
+                        self.record_sale(address, None);
+                        match self.payment_method {
+                            Some(PaymentMethod::Cash) => {
+                                //Let the cashless device know about the cash sale (for its' audit records), and give change
+                                let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::RecordCashTransaction(self.amount_due, address)));
+                                self.credit -= self.amount_due;
+                            }
+                            _ => {
+                                //Send massage to cashless device to confirm vend successful, to end transaction
+                                let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::VendSuccess(address)));
+                            }
+                        }
+                        //The change, plus any cash inserted during the vend
+                        self.refund_credit();
+                        self.take_stock(address);
+                        //Refresh our view of the dispenser, eg in case that was the last can
+                        let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetDispenser(address.row, address.col));
+                        self.payment_method = None;
+                        self.amount_due = 0;
                         self.state = AppState::VendSuccess;              
                     },
-                    Event::VendFailed => {
-                        //Cancel the cashless device transaction with vend failed (not sure what it will say if it didnt handle the transaction)
-                        let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::VendFailed));
+                    Event::VendFailed(e) => {
+                        let address = DispenserAddress { row: self.row_selected.unwrap(), col: self.col_selected.unwrap() };
+                        self.record_sale(address, Some(e));
+                        //Any failure - including no drop being detected - means the customer isn't charged
+                        self.vend_failed_box.set_reason(String::from(match e {
+                            DispenseError::NoDropDetected => "Nothing dropped\nYou have not\nbeen charged",
+                            DispenseError::OneOrNoCansLeft => "Sold out",
+                            _ => "You have not\nbeen charged",
+                        }));
+                        if matches!(e, DispenseError::OneOrNoCansLeft) && self.stock.get(address).is_some_and(|slot| slot.count > 0) {
+                            //The VMC knows better - our count was out
+                            println!("Alert - {} is empty, but was counted as having stock", address_key(address));
+                            self.stock.set_count(address, 0);
+                        }
+                        if !matches!(self.payment_method, Some(PaymentMethod::Cash)) {
+                            //Cancel the cashless device transaction with vend failed (not sure what it will say if it didnt handle the transaction)
+                            let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::VendFailed));
+                        }
+                        //Give the customer their money back, including any cash inserted during the vend
+                        self.refund_credit();
+                        let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetDispenser(address.row, address.col));
+                        self.payment_method = None;
+                        self.amount_due = 0;
                         self.state = AppState::VendFailed;                   
                     },
                     //Fixme - need a timeout if vmc has gone wrong
                     _ => {},
                 }
             }
+            AppState::ServiceMode => {
+                match event {
+                    Event::Keypress(key) => {
+                        let action = self.service_menu.handle_key(key, &self.catalogue, &self.stock);
+                        self.do_service_action(action);
+                    }
+                    Event::VendSuccess if self.service_menu.is_vending() => {
+                        //A test vend still takes an item out of the slot
+                        if let Some(address) = self.service_menu.vending_address() {
+                            self.take_stock(address);
+                            let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetDispenser(address.row, address.col));
+                            self.api.metrics().vend(&address_key(address), VendMode::Service, Ok(()));
+                        }
+                        self.service_menu.vend_result(Ok(()));
+                    }
+                    Event::VendFailed(e) if self.service_menu.is_vending() => {
+                        if let Some(address) = self.service_menu.vending_address() {
+                            let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetDispenser(address.row, address.col));
+                            self.api.metrics().vend(&address_key(address), VendMode::Service, Err(e));
+                        }
+                        self.service_menu.vend_result(Err(e));
+                    }
+                    _ => {}
+                }
+            }
             _ => {}
             
         }
         self.update_ui();
     }
 
+    //Abandons any customer transaction, except a vend already under way - service
+    //mode is entered once that finishes
+    fn enter_service_mode(&mut self) {
+        match self.state {
+            AppState::ServiceMode => return,
+            AppState::Vending => {
+                println!("Entering service mode once the current vend finishes");
+                return;
+            }
+            AppState::AwaitingPayment => self.cancel_payment(SessionOutcome::Cancelled),
+            _ => {}
+        }
+        self.row_selected = None;
+        self.col_selected = None;
+        self.service_menu = ServiceMenu::new();
+        self.state = AppState::ServiceMode;
+        let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetMachineMap());
+        self.update_ui();
+    }
+
+    //Redraw the service screen when the status it shows changes
+    fn refresh_service_ui(&mut self) {
+        if matches!(self.state, AppState::ServiceMode) {
+            self.update_ui();
+        }
+    }
+
+    fn do_service_action(&mut self, action: ServiceAction) {
+        match action {
+            ServiceAction::None => {}
+            ServiceAction::RefreshMap => {
+                let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetMachineMap());
+            }
+            ServiceAction::RefreshPaymentDevices => {
+                let _ = self.vmc_command_channel.send_blocking(VmcCommand::GetCoinAcceptorInfo);
+            }
+            ServiceAction::Vend(address, force) => {
+                println!("Service mode {}vend - {}", if force { "force " } else { "test " }, address_key(address));
+                let cmd = if force {
+                    VmcCommand::ForceVendItem(address.row, address.col)
+                } else {
+                    VmcCommand::VendItem(address.row, address.col)
+                };
+                let _ = self.vmc_command_channel.send_blocking(cmd);
+            }
+            ServiceAction::SetStock(address, count) => {
+                self.stock.set_count(address, count);
+                println!("Service mode - {} stock set to {}", address_key(address), count);
+                self.service_menu.show_message(format!("{} stock set to {}", address_key(address), count));
+            }
+            ServiceAction::SetPrice(address, price) => {
+                let message = match self.catalogue.set_price(address, price) {
+                    Ok(()) => format!("{} price set to £{}.{:02}", address_key(address), price / 100, price % 100),
+                    Err(e) => format!("Price not changed - {}", e),
+                };
+                println!("Service mode - {}", message);
+                self.service_menu.show_message(message);
+            }
+        }
+    }
+
+    //Clear the selection, and ask for a different one
+    fn offer_another_selection(&mut self, reason: &str) {
+        self.make_another_selection_box.set_reason(String::from(reason));
+        self.row_selected = None;
+        self.col_selected = None;
+        self.state = AppState::MakeAnotherSelection;
+        self.update_ui();
+    }
+
+    //Add the vend that's just finished to the sales ledger
+    fn record_sale(&mut self, address: DispenserAddress, dispense_error: Option<DispenseError>) {
+        self.api.metrics().vend(&address_key(address), VendMode::Customer, dispense_error.map_or(Ok(()), Err));
+        let outcome = match dispense_error {
+            Some(_) => SessionOutcome::Failed,
+            None => SessionOutcome::Vended,
+        };
+        self.record_session(address, outcome, dispense_error);
+    }
+
+    //Called before the credit is refunded, so the cash taken is still known
+    fn record_session(&mut self, address: DispenserAddress, outcome: SessionOutcome, dispense_error: Option<DispenseError>) {
+        let finished = Utc::now();
+        self.ledger.record(&SaleRecord {
+            started: self.session_started.take().unwrap_or(finished),
+            finished,
+            slot: address_key(address),
+            product: self.catalogue.get_stock_item(address).map(|item| item.name.clone()).unwrap_or_default(),
+            price: self.amount_due,
+            payment_method: self.payment_method,
+            cash_paid: self.credit,
+            cashless_approved: self.cashless_approved.take(),
+            dispense_error,
+            outcome: Some(outcome),
+            refund_shortfall: None,
+        });
+    }
+
+    //Log the slots that need restocking
+    fn report_low_stock(&self) {
+        for (address, slot) in self.stock.low_stock() {
+            self.alert_low_stock(&address, slot);
+        }
+    }
+
+    //Count an item out of its slot, and raise an alert if that leaves the slot low
+    fn take_stock(&mut self, address: DispenserAddress) {
+        if let Some(slot) = self.stock.decrement(address) {
+            self.alert_low_stock(&address_key(address), slot);
+        }
+    }
+
+    fn alert_low_stock(&self, slot: &str, stock: SlotStock) {
+        println!("Alert - {} low on stock - {} left", slot, stock.count);
+        self.api.broadcast("stock", &LowStockAlert { slot, count: stock.count, sold_out: stock.count == 0 });
+    }
+
+    //Abandon the current transaction, returning any coins inserted so far. It still goes in the
+    //ledger, as money may have been taken or approved
+    fn cancel_payment(&mut self, outcome: SessionOutcome) {
+        if let (Some(row), Some(col)) = (self.row_selected, self.col_selected) {
+            self.record_session(DispenserAddress { row, col }, outcome, None);
+        }
+        self.refund_credit();
+        if let Some(value) = self.escrowed_bill.take() {
+            println!("Returning bill of {} held in escrow", value);
+            let _ = self.vmc_command_channel.send_blocking(VmcCommand::BillValidatorCmd(BillValidatorCommand::RejectEscrow));
+        }
+        self.row_selected = None;
+        self.col_selected = None;
+        self.state = AppState::Idle;
+        self.amount_due = 0;
+        self.payment_method = None;
+        self.session_started = None;
+        self.cashless_approved = None;
+        //Disable coin acceptor and bill validator
+        self.set_cash_acceptors_enabled(false);
+        //Cancel the cashless transaction
+        let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::CancelTransaction));
+    }
+
+    fn add_cash_credit(&mut self, value: u16) {
+        //Update the credit
+        self.credit += value;
+        if self.credit >= self.amount_due {
+            //Paid in cash - stop taking any more, and take the card reader out of the transaction
+            self.set_cash_acceptors_enabled(false);
+            let _ = self.vmc_command_channel.send_blocking(VmcCommand::CashlessCmd(CashlessDeviceCommand::CancelTransaction));
+            //Move to vend
+            self.payment_method = Some(PaymentMethod::Cash);
+            self.start_vend();
+        }
+    }
+
+    fn start_vend(&mut self) {
+        self.vend_in_progress_box.reset();
+        self.state = AppState::Vending;
+        let _ = self.vmc_command_channel.send_blocking(VmcCommand::VendItem(self.row_selected.unwrap(), self.col_selected.unwrap()));
+    }
+
+    fn set_cash_acceptors_enabled(&self, enable: bool) {
+        let _ = self.vmc_command_channel.send_blocking(VmcCommand::SetCoinAcceptorEnabled(enable));
+        let cmd = if enable {
+            BillValidatorCommand::Enable
+        } else {
+            BillValidatorCommand::Disable
+        };
+        let _ = self.vmc_command_channel.send_blocking(VmcCommand::BillValidatorCmd(cmd));
+    }
+
+    //Only take the note if the tubes could pay all the credit back in coins, as they must if the
+    //customer cancels or the card reader ends up paying instead
+    fn accept_or_return_bill(&mut self, value: u16) {
+        let refund = self.credit + value;
+        let cmd = if !matches!(self.state, AppState::AwaitingPayment) {
+            println!("Returning bill of {} - no longer awaiting payment", value);
+            BillValidatorCommand::RejectEscrow
+        } else if self.can_give_change(refund) {
+            BillValidatorCommand::AcceptEscrow
+        } else {
+            println!("Returning bill of {} - unable to refund {} in coins", value, refund);
+            BillValidatorCommand::RejectEscrow
+        };
+        let _ = self.vmc_command_channel.send_blocking(VmcCommand::BillValidatorCmd(cmd));
+    }
+
+    //Whether the coin tubes hold enough to pay out this much change. Approximate, as it
+    //doesn't account for whether the available coin denominations can make up the amount
+    fn can_give_change(&self, change: u16) -> bool {
+        if change == 0 {
+            return true;
+        }
+        match &self.coin_acceptor_info {
+            Some(info) => {
+                let tube_value: u32 = core::iter::zip(info.coin_values, info.tube_levels)
+                    .map(|(value, level)| value as u32 * level as u32)
+                    .sum();
+                tube_value >= change as u32
+            }
+            None => false,
+        }
+    }
+
+    fn refund_credit(&mut self) {
+        if self.credit > 0 {
+            println!("Refunding {} in coins", self.credit);
+            let _ = self.vmc_command_channel.send_blocking(VmcCommand::RefundCoins(self.credit));
+            self.pending_refunds.push_back(self.row_selected.zip(self.col_selected).map(|(row, col)| DispenserAddress { row, col }));
+            self.credit = 0;
+        }
+    }
+
+    //The tubes couldn't pay back all the customer was owed - it goes in the ledger, so it can be
+    //made good
+    fn record_short_refund(&mut self, address: Option<DispenserAddress>, requested: u16, paid: u16) {
+        let shortfall = requested - paid;
+        println!("Error - coin refund short by {} (asked for {}, paid {})", shortfall, requested, paid);
+        self.api.metrics().refund_shortfall(shortfall);
+        let now = Utc::now();
+        self.ledger.record(&SaleRecord {
+            started: now,
+            finished: now,
+            slot: address.map(address_key).unwrap_or_default(),
+            product: address.and_then(|a| self.catalogue.get_stock_item(a)).map(|item| item.name.clone()).unwrap_or_default(),
+            price: 0,
+            payment_method: Some(PaymentMethod::Cash),
+            cash_paid: requested,
+            cashless_approved: None,
+            dispense_error: None,
+            outcome: Some(SessionOutcome::ShortRefund),
+            refund_shortfall: Some(shortfall),
+        });
+    }
+
+    //Replaces any earlier vend message
+    fn show_vend_message(&self, lines: &[&str], seconds: u64) {
+        let _ = self.lcd_channel.send_blocking(LcdCommand::QueueMessage(LcdQueuedMessage {
+            id: LCD_MESSAGE_VEND,
+            priority: LCD_PRIORITY_VEND,
+            duration: std::time::Duration::from_secs(seconds),
+            lines: lines.iter().map(|line| String::from(*line)).collect(),
+        }));
+    }
+
     async fn main_loop(&mut self) {
         loop {
             if let Ok(event) = self.event_channel_rx.recv().await {
+                //The once-a-second poll would just be noise
+                if !matches!(event, Event::Timeout_Poll_Event) {
+                    self.api.broadcast("app", &event);
+                }
                 self.handle_event(event);
+                self.api.metrics().observe_state(&format!("{:?}", self.state));
+                self.api.set_status(self.status());
             }
         }
     }
 
+    //Snapshot for the management API
+    fn status(&self) -> MachineStatus {
+        let mut stock: Vec<SlotStatus> = self
+            .catalogue
+            .items()
+            .iter()
+            .map(|item| {
+                let slot = self.stock.get(item.address);
+                SlotStatus {
+                    slot: address_key(item.address),
+                    product: Some(item.name.clone()),
+                    price: Some(item.price),
+                    count: slot.map(|slot| slot.count),
+                    low: slot.is_some_and(|slot| slot.is_low()),
+                }
+            })
+            .collect();
+        //Counted slots with nothing in the catalogue
+        for (key, slot) in self.stock.slots() {
+            if !stock.iter().any(|s| &s.slot == key) {
+                stock.push(SlotStatus { slot: key.clone(), product: None, price: None, count: Some(slot.count), low: slot.is_low() });
+            }
+        }
+        stock.sort_by(|a, b| a.slot.cmp(&b.slot));
+
+        MachineStatus {
+            state: format!("{:?}", self.state),
+            service_mode: self.service_mode,
+            maintenance: self.maintenance,
+            selection: self.row_selected.zip(self.col_selected).map(|(row, col)| format!("{}{}", row, col)),
+            credit: self.credit,
+            amount_due: self.amount_due,
+            lcd_present: self.lcd_present,
+            chiller: self.chiller_info,
+            coin_acceptor: self.coin_acceptor_info,
+            cashless_available: self.cashless_available,
+            dispensers: self.machine.dispensers().to_vec(),
+            stock,
+        }
+    }
+
+    fn idle_text(&self) -> Vec<String> {
+        if self.maintenance {
+            vec![String::from(MAINTENANCE_MESSAGE_L1), String::from(MAINTENANCE_MESSAGE_L2)]
+        } else {
+            vec![String::from(IDLE_MESSAGE_L1), String::from(IDLE_MESSAGE_L2)]
+        }
+    }
+
+    //Out of service takes priority, as it explains why nothing can be bought
+    fn update_fault(&self) {
+        self.make_selection_box.set_fault(if self.maintenance {
+            Some("Out of service")
+        } else if !self.lcd_present {
+            Some("Keypad display fault")
+        } else {
+            None
+        });
+    }
+
     fn update_ui(&mut self) {
         //Display appropriate state
         match self.state {
             AppState::Idle => {
                 
-                let _ = self.lcd_channel.send_blocking(LcdCommand::SetText(String::from(IDLE_MESSAGE_L1),
-                    String::from(IDLE_MESSAGE_L2)));
+                let _ = self.lcd_channel.send_blocking(LcdCommand::SetText(self.idle_text()));
+                //Any vend result message is left to time out by itself
+                let _ = self.lcd_channel.send_blocking(LcdCommand::CancelMessage(LCD_MESSAGE_PAYMENT));
 
                 //In this state, we should be showing the select item widgetstack 'page'
                 self.stack.set_visible_child(
@@ -435,17 +1059,23 @@ impl App {
                     }
                 };
                 //Display idle message
-                let _ = self.lcd_channel.send_blocking(LcdCommand::SetText(String::from(IDLE_MESSAGE_L1), String::from(IDLE_MESSAGE_L2)));
+                let _ = self.lcd_channel.send_blocking(LcdCommand::SetText(self.idle_text()));
             }
             AppState::AwaitingConfirmation => {
-                match get_stock_item(DispenserAddress {
+                let address = DispenserAddress {
                     row: self.row_selected.unwrap(),
                     col: self.col_selected.unwrap(),
-                }) {
+                };
+                //Only offer items the VMC reports as fitted, and that haven't sold out
+                match self.catalogue.get_stock_item(address).filter(|_| self.machine.is_fitted(address)) {
+                    Some(_) if self.stock.is_sold_out(address) => {
+                        self.offer_another_selection("Sold out");
+                    }
                     Some(item) => {
-                        self.confirm_item_box.set_name(item.name);
-                        self.confirm_item_box.set_image(item.image_url);
+                        self.confirm_item_box.set_name(&item.name);
+                        self.confirm_item_box.set_image(item.image_url.as_deref());
                         self.confirm_item_box.set_price(item.price);
+                        self.confirm_item_box.set_description(&item.description);
                         self.stack.set_visible_child(
                             &self
                                 .stack
@@ -455,19 +1085,27 @@ impl App {
                     }
                     None => {
                         //Invalid, should say so.
-                        self.row_selected = None;
-                        self.col_selected = None;
-                        self.state = AppState::MakeAnotherSelection;
-                        self.update_ui();
+                        self.offer_another_selection("");
                     }
                 }
             }
             AppState::AwaitingPayment => {
 
-                let balance_due = self.amount_due - self.credit;
+                let balance_due = self.amount_due.saturating_sub(self.credit);
                 
-                let _ = self.lcd_channel.send_blocking(LcdCommand::SetText(String::from(PAY_MESSAGE_L1), 
-                format!("{}.{:02}", balance_due/100, balance_due%100)));
+                //Times out with the app, in case we stop updating it
+                let _ = self.lcd_channel.send_blocking(LcdCommand::QueueMessage(LcdQueuedMessage {
+                    id: LCD_MESSAGE_PAYMENT,
+                    priority: LCD_PRIORITY_PAYMENT,
+                    duration: std::time::Duration::from_secs(APP_TIMEOUT_SECONDS as u64),
+                    //The last two lines only show on 4 row LCDs
+                    lines: vec![
+                        String::from(PAY_MESSAGE_L1),
+                        format!("£{}.{:02}", balance_due/100, balance_due%100),
+                        format!("Paid: £{}.{:02}", self.credit/100, self.credit%100),
+                        String::from(PAY_MESSAGE_L4),
+                    ],
+                }));
 
                 self.stack.set_visible_child(
                     &self
@@ -493,10 +1131,13 @@ impl App {
             AppState::Vending => {
                  self.stack.set_visible_child(
                     &self.stack.child_by_name("vend_in_progress_box").expect("vend_in_progress_box missing from stack"));
+                let _ = self.lcd_channel.send_blocking(LcdCommand::CancelMessage(LCD_MESSAGE_PAYMENT));
+                self.show_vend_message(&["Vending..."], APP_TIMEOUT_SECONDS as u64);
             }
             AppState::VendSuccess => {
                 self.stack.set_visible_child(
                     &self.stack.child_by_name("vend_ok_box").expect("Vendsuccess missing from stack"));
+                self.show_vend_message(&["Thank you!", "Enjoy!"], LCD_VEND_RESULT_SECONDS);
                 //Queue a message to leave this state after 3 seconds
                 let ch = self.event_channel_tx.clone();
                 glib::timeout_add_seconds(2, move || {
@@ -504,9 +1145,23 @@ impl App {
                     glib::ControlFlow::Break
                 }); 
             }
+            AppState::ServiceMode => {
+                let screen = self.service_menu.render(&ServiceView {
+                    machine: &self.machine,
+                    catalogue: &self.catalogue,
+                    stock: &self.stock,
+                    coin_acceptor: self.coin_acceptor_info.as_ref(),
+                    cashless_available: self.cashless_available,
+                });
+                self.service_mode_box.show(&screen);
+                self.stack.set_visible_child(
+                    &self.stack.child_by_name("service_mode_box").expect("service_mode_box missing from stack"));
+                let _ = self.lcd_channel.send_blocking(LcdCommand::SetText(vec![String::from("Service mode"), screen.title]));
+            }
             AppState::VendFailed => {
                 self.stack.set_visible_child(
                     &self.stack.child_by_name("vend_failed_box").expect("Vendfailed missing from stack"));
+                self.show_vend_message(&["Sorry - vend", "failed"], LCD_VEND_RESULT_SECONDS);
                 //Queue a message to leave this state after 3 seconds
                 let ch = self.event_channel_tx.clone();
                 glib::timeout_add_seconds(2, move || {
@@ -520,6 +1175,55 @@ impl App {
 }
 
 fn main() -> glib::ExitCode {
+    //Export the sales ledger as CSV, eg for reconciling card reader payouts, rather than running the machine
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("export-sales") {
+        return match export_sales_command(&args[2..]) {
+            Ok(()) => glib::ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{}", e);
+                glib::ExitCode::FAILURE
+            }
+        };
+    }
+
+    //Refuse to start with a broken catalogue, rather than finding out when someone tries to buy something
+    let catalogue_path = std::env::var("SNACKBOT_CATALOGUE").unwrap_or_else(|_| String::from(DEFAULT_CATALOGUE_PATH));
+    let catalogue = match Catalogue::load(&catalogue_path) {
+        Ok(catalogue) => {
+            println!("Catalogue {} loaded - {} items", catalogue_path, catalogue.items().len());
+            catalogue
+        }
+        Err(e) => {
+            println!("Error - catalogue {}: {}", catalogue_path, e);
+            return glib::ExitCode::FAILURE;
+        }
+    };
+    let stock_path = std::env::var("SNACKBOT_STOCK").unwrap_or_else(|_| String::from(DEFAULT_STOCK_PATH));
+    let stock = match StockLevels::load(&stock_path) {
+        Ok(stock) => {
+            println!("Stock levels {} loaded - {} slots counted", stock_path, stock.slots().len());
+            stock
+        }
+        Err(e) => {
+            println!("Error - stock levels {}: {}", stock_path, e);
+            return glib::ExitCode::FAILURE;
+        }
+    };
+    let ledger_path = std::env::var("SNACKBOT_LEDGER").unwrap_or_else(|_| String::from(DEFAULT_LEDGER_PATH));
+    println!("Recording sales in {}", ledger_path);
+
+    //Handed to the App on activation
+    let catalogue = std::cell::Cell::new(Some((catalogue, stock)));
+
+    let (event_channel_tx, event_channel_rx) = async_channel::unbounded::<Event>();
+
+    //Serve the management API alongside the device drivers
+    let ledger = SalesLedger::new(&ledger_path);
+    let api = ManagementApi::new(event_channel_tx.clone(), ledger.clone());
+    let api_addr = std::env::var("SNACKBOT_API_ADDR").unwrap_or_else(|_| String::from(DEFAULT_API_ADDR));
+    spawn_management_api(api.clone(), api_addr);
+
     //Create VMC command and response channels
     let (vmc_response_channel_tx, vmc_response_channel_rx) =
         async_channel::unbounded::<VmcResponse>();
@@ -532,20 +1236,26 @@ fn main() -> glib::ExitCode {
 
     //Lcd command channel
     let (lcd_command_channel_tx, lcd_command_channel_rx) = async_channel::unbounded::<LcdCommand>();
-    //Spawn LCD driver with its' one-way command channel
-    spawn_lcd_driver(lcd_command_channel_rx);
-
-    let (event_channel_tx, event_channel_rx) = async_channel::unbounded::<Event>();
+    //Lcd event channel - keypresses and the service mode switch
+    let (lcd_event_channel_tx, lcd_event_channel_rx) = async_channel::unbounded::<LcdEvent>();
+    //Spawn LCD driver with its command and event channels
+    spawn_lcd_driver(lcd_command_channel_rx, lcd_event_channel_tx);
 
     let app = Application::builder().application_id(APP_ID).build();
     app.connect_activate(move |app| {
+        let (catalogue, stock) = catalogue.take().expect("Application activated more than once");
         let mut app = App::new(
             &app,
             event_channel_tx.clone(),
             event_channel_rx.clone(),
             lcd_command_channel_tx.clone(),
             vmc_command_channel_tx.clone(),
+            catalogue,
+            stock,
+            ledger.clone(),
+            api.clone(),
         );
+        app.report_low_stock();
 
         //Spawn the main loop onto the GLib event loop
         glib::MainContext::default().spawn_local(async move {
@@ -555,25 +1265,62 @@ fn main() -> glib::ExitCode {
         //Spawn a task to receive events from the VMC response channel, and repost them onto the app's main event loop
         let rx = vmc_response_channel_rx.clone();
         let tx = event_channel_tx.clone();
+        let api = api.clone();
         glib::MainContext::default().spawn_local( async move {
             loop {
                 match rx.recv().await {
                     Ok(event) => {
+                        api.broadcast("vmc", &event);
                         match event {
                             VmcResponse::CoinInsertedEvent(coin) => {
-                                let _ = tx.send(Event::CoinInserted(coin.value)).await;
+                                api.metrics().coin_inserted(coin.value, coin.routing);
+                                //Rejected coins are returned to the customer, so aren't credit
+                                if coin.routing != CoinRouting::Reject {
+                                    let _ = tx.send(Event::CoinInserted(coin.value)).await;
+                                }
                             },
                             VmcResponse::CoinAcceptorEvent(CoinAcceptorEvent::EscrowPressed) => {
                                 let _ = tx.send(Event::EscrowPressed).await;
                             }
                             VmcResponse::CashlessEvent(e) => {
+                                api.metrics().cashless_event(e);
                                 let _ = tx.send(Event::CashlessEvent(e)).await;
                             }
                             VmcResponse::DispenseSuccessEvent => {
                                 let _ = tx.send(Event::VendSuccess).await;
                             }
-                            VmcResponse::DispenseFailedEvent => {
-                                let _ = tx.send(Event::VendFailed).await;
+                            VmcResponse::DispenseProgress(progress) => {
+                                let _ = tx.send(Event::DispenseProgress(progress)).await;
+                            }
+                            VmcResponse::DispenseFailedEvent(e) => {
+                                let _ = tx.send(Event::VendFailed(e)).await;
+                            }
+                            VmcResponse::ChillerInfo(info) => {
+                                api.metrics().chiller(info);
+                                let _ = tx.send(Event::ChillerInfo(info)).await;
+                            }
+                            VmcResponse::BillInsertedEvent(bill) => {
+                                match bill.routing {
+                                    BillRouting::Stacked => {
+                                        let _ = tx.send(Event::BillInserted(bill.value)).await;
+                                    }
+                                    BillRouting::Escrow => {
+                                        let _ = tx.send(Event::BillEscrowed(bill.value)).await;
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            VmcResponse::CoinAcceptorInfo(info) => {
+                                let _ = tx.send(Event::CoinAcceptorInfo(info)).await;
+                            }
+                            VmcResponse::MachineMap(dispensers) => {
+                                let _ = tx.send(Event::MachineMap(dispensers)).await;
+                            }
+                            VmcResponse::Dispenser(dispenser) => {
+                                let _ = tx.send(Event::DispenserStatus(dispenser)).await;
+                            }
+                            VmcResponse::CoinsRefunded(requested, paid) => {
+                                let _ = tx.send(Event::CoinsRefunded(requested, paid)).await;
                             }
                             _ => {
                                 println!("Ignored an event");
@@ -587,6 +1334,19 @@ fn main() -> glib::ExitCode {
             }
         });
 
+        //Likewise repost the keyboard's events
+        let rx = lcd_event_channel_rx.clone();
+        let tx = event_channel_tx.clone();
+        glib::MainContext::default().spawn_local( async move {
+            while let Ok(event) = rx.recv().await {
+                let _ = match event {
+                    LcdEvent::Keypress(c) => tx.send(Event::Keypress(c)).await,
+                    LcdEvent::ServiceMode(enabled) => tx.send(Event::ServiceMode(enabled)).await,
+                    LcdEvent::LcdPresent(present) => tx.send(Event::LcdPresent(present)).await,
+                };
+            }
+        });
+
         let ch = event_channel_tx.clone();
         glib::timeout_add_seconds(1, move || {
             let _ = ch.send_blocking(Event::Timeout_Poll_Event);
@@ -37,14 +37,13 @@ impl ObjectImpl for MakePaymentBox {
                 .width_request(140)
                 .build(),
         );
-    /*    self.obj().append(
+        self.obj().append(
             &Label::builder()
                 .use_markup(true)
                 .justify(gtk4::Justification::Center)
-                .label("<span font=\"Arial Rounded MT 50\">or\nInsert Coins</span>")
+                .label("<span font=\"Arial Rounded MT 50\">or\nInsert Cash</span>")
                 .build(),
         );
-     */
         self.obj().append(
             &Image::builder()
                 .file("./coins.jpeg")
@@ -5,6 +5,8 @@ use gtk4::{Box, Image, Label};
 #[derive(Default)]
 pub struct MakeSelectionBox {
     pub row_col: Label,
+    pub drinks_temp: Label,
+    pub fault: Label,
 }
 
 #[glib::object_subclass]
@@ -36,6 +38,15 @@ impl ObjectImpl for MakeSelectionBox {
                 .label("<span font=\"Arial Rounded MT 50\">Please select\nan item</span>")
                 .build(),
         );
+
+        //Blank until the first chiller status arrives from the VMC
+        self.drinks_temp.set_use_markup(true);
+        self.obj().append(&self.drinks_temp);
+
+        //Only shown while something needs attention
+        self.fault.set_use_markup(true);
+        self.fault.set_visible(false);
+        self.obj().append(&self.fault);
     }
 }
 
@@ -15,4 +15,26 @@ impl MakeSelectionBox {
     pub fn new() -> Self {
         Object::builder().build()
     }
+
+    pub fn set_drinks_temperature(&self, temp: i8) {
+        let i = imp::MakeSelectionBox::from_obj(self);
+        i.drinks_temp.set_label(&format!(
+            "<span font=\"Arial Rounded MT 30\">Drinks: {}°C</span>",
+            temp
+        ));
+    }
+
+    pub fn set_fault(&self, fault: Option<&str>) {
+        let i = imp::MakeSelectionBox::from_obj(self);
+        match fault {
+            Some(fault) => {
+                i.fault.set_label(&format!(
+                    "<span font=\"Arial Rounded MT 30\" foreground=\"red\">{}</span>",
+                    glib::markup_escape_text(fault)
+                ));
+                i.fault.set_visible(true);
+            }
+            None => i.fault.set_visible(false),
+        }
+    }
 }
@@ -0,0 +1,244 @@
+//Local HTTP management API, for the makerspace dashboard:
+//  GET  /api/status        - everything below in one go, plus the app state
+//  GET  /api/connectivity  - whether the VMC, keyboard and LCD are connected
+//  GET  /api/chiller       - drinks chiller temperature and compressor state
+//  GET  /api/stock         - stock level, product and price for each slot
+//  GET  /api/sales?count=N - the most recent vend sessions and short refunds from the ledger (default 20)
+//  GET  /api/maintenance   - whether the machine is out of service
+//  PUT  /api/maintenance   - {"enabled": true} takes the machine out of service
+//  GET  /api/events        - WebSocket streaming VMC responses, app events and low stock alerts as JSON
+//  GET  /metrics           - Prometheus metrics
+//It only listens on localhost unless SNACKBOT_API_ADDR says otherwise, as nothing is authenticated
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use vmc_icd::chiller::ChillerInfo;
+use vmc_icd::coin_acceptor::CoinAcceptorInfo;
+use vmc_icd::dispenser::Dispenser;
+
+use crate::metrics::Metrics;
+use crate::rpc_shim::runtime;
+use crate::sales_ledger::{SaleRecord, SalesLedger};
+use crate::Event;
+
+pub const DEFAULT_API_ADDR: &str = "127.0.0.1:8080";
+
+const DEFAULT_RECENT_SALES: usize = 20;
+//Slow WebSocket clients miss messages beyond this, rather than holding up the app
+const EVENT_STREAM_CAPACITY: usize = 64;
+
+//Whether each USB device is currently connected - kept up to date by the rpc_shim driver tasks
+pub static VMC_CONNECTED: AtomicBool = AtomicBool::new(false);
+pub static KEYBOARD_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+//A snapshot of the app, refreshed after every event it handles
+#[derive(Serialize, Clone, Default)]
+pub struct MachineStatus {
+    pub state: String,
+    pub service_mode: bool,
+    pub maintenance: bool,
+    pub selection: Option<String>,
+    pub credit: u16,
+    pub amount_due: u16,
+    pub lcd_present: bool,
+    pub chiller: Option<ChillerInfo>,
+    pub coin_acceptor: Option<CoinAcceptorInfo>,
+    pub cashless_available: Option<bool>,
+    pub dispensers: Vec<Dispenser>,
+    pub stock: Vec<SlotStatus>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SlotStatus {
+    pub slot: String,
+    pub product: Option<String>,
+    pub price: Option<u16>,
+    pub count: Option<u16>, //None if the slot isn't counted
+    pub low: bool,
+}
+
+#[derive(Serialize)]
+struct Connectivity {
+    vmc: bool,
+    keyboard: bool,
+    lcd: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Maintenance {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct SalesQuery {
+    count: Option<usize>,
+}
+
+struct Shared {
+    status: Mutex<MachineStatus>,
+    events: broadcast::Sender<String>,
+    app_events: async_channel::Sender<Event>,
+    ledger: SalesLedger,
+    metrics: Metrics,
+}
+
+//Shared between the app, which keeps it up to date, and the HTTP server
+#[derive(Clone)]
+pub struct ManagementApi {
+    shared: Arc<Shared>,
+}
+
+impl ManagementApi {
+    pub fn new(app_events: async_channel::Sender<Event>, ledger: SalesLedger) -> Self {
+        let (events, _) = broadcast::channel(EVENT_STREAM_CAPACITY);
+        Self {
+            shared: Arc::new(Shared {
+                status: Mutex::new(MachineStatus::default()),
+                events,
+                app_events,
+                ledger,
+                metrics: Metrics::default(),
+            }),
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.shared.metrics
+    }
+
+    pub fn set_status(&self, status: MachineStatus) {
+        *self.shared.status.lock().unwrap() = status;
+    }
+
+    fn status(&self) -> MachineStatus {
+        self.shared.status.lock().unwrap().clone()
+    }
+
+    //Send a message to any connected WebSocket clients, tagged with where it came from
+    pub fn broadcast(&self, source: &str, message: &impl Serialize) {
+        if self.shared.events.receiver_count() == 0 {
+            return;
+        }
+        #[derive(Serialize)]
+        struct Tagged<'a, T> {
+            source: &'a str,
+            message: &'a T,
+        }
+        match serde_json::to_string(&Tagged { source, message }) {
+            Ok(text) => {
+                let _ = self.shared.events.send(text);
+            }
+            Err(e) => println!("Error - unable to encode {} event for the API: {}", source, e),
+        }
+    }
+}
+
+pub(crate) fn spawn_management_api(api: ManagementApi, addr: String) {
+    let app = Router::new()
+        .route("/api/status", get(get_status))
+        .route("/api/connectivity", get(get_connectivity))
+        .route("/api/chiller", get(get_chiller))
+        .route("/api/stock", get(get_stock))
+        .route("/api/sales", get(get_sales))
+        .route("/api/maintenance", get(get_maintenance).put(set_maintenance))
+        .route("/api/events", get(events))
+        .route("/metrics", get(get_metrics))
+        .with_state(api);
+
+    runtime().spawn(async move {
+        let listener = match tokio::net::TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Error - management API unable to listen on {}: {}", addr, e);
+                return;
+            }
+        };
+        println!("Management API listening on {}", addr);
+        if let Err(e) = axum::serve(listener, app).await {
+            println!("Error - management API stopped: {}", e);
+        }
+    });
+}
+
+async fn get_status(State(api): State<ManagementApi>) -> Json<MachineStatus> {
+    Json(api.status())
+}
+
+async fn get_connectivity(State(api): State<ManagementApi>) -> Json<Connectivity> {
+    Json(Connectivity {
+        vmc: VMC_CONNECTED.load(Ordering::Relaxed),
+        keyboard: KEYBOARD_CONNECTED.load(Ordering::Relaxed),
+        lcd: KEYBOARD_CONNECTED.load(Ordering::Relaxed) && api.status().lcd_present,
+    })
+}
+
+//Unavailable until the VMC has reported it
+async fn get_chiller(State(api): State<ManagementApi>) -> Result<Json<ChillerInfo>, StatusCode> {
+    api.status().chiller.map(Json).ok_or(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn get_stock(State(api): State<ManagementApi>) -> Json<Vec<SlotStatus>> {
+    Json(api.status().stock)
+}
+
+async fn get_sales(State(api): State<ManagementApi>, Query(query): Query<SalesQuery>) -> Json<Vec<SaleRecord>> {
+    let count = query.count.unwrap_or(DEFAULT_RECENT_SALES);
+    Json(api.shared.ledger.recent(count))
+}
+
+async fn get_maintenance(State(api): State<ManagementApi>) -> Json<Maintenance> {
+    Json(Maintenance { enabled: api.status().maintenance })
+}
+
+//Handed to the app to act on - the change shows up in the status once it has
+async fn set_maintenance(State(api): State<ManagementApi>, Json(maintenance): Json<Maintenance>) -> StatusCode {
+    match api.shared.app_events.send(Event::Maintenance(maintenance.enabled)).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn get_metrics(State(api): State<ManagementApi>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], api.metrics().render())
+}
+
+async fn events(ws: WebSocketUpgrade, State(api): State<ManagementApi>) -> impl IntoResponse {
+    let rx = api.shared.events.subscribe();
+    ws.on_upgrade(move |socket| stream_events(socket, rx))
+}
+
+async fn stream_events(mut socket: WebSocket, mut rx: broadcast::Receiver<String>) {
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                match msg {
+                    Ok(text) => {
+                        if socket.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        println!("Management API client fell behind - {} events dropped", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            //Nothing is expected from the client, but watch for it going away
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
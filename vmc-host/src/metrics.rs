@@ -0,0 +1,170 @@
+//Counters and gauges for Prometheus, served as text from the management API's /metrics
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use vmc_icd::cashless_device::CashlessDeviceEvent;
+use vmc_icd::chiller::ChillerInfo;
+use vmc_icd::coin_acceptor::CoinRouting;
+use vmc_icd::dispenser::DispenseError;
+
+use crate::management_api::{KEYBOARD_CONNECTED, VMC_CONNECTED};
+
+//Times a USB device has come back after being disconnected - counted by the rpc_shim driver tasks
+pub static VMC_RECONNECTS: AtomicU64 = AtomicU64::new(0);
+pub static KEYBOARD_RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+//Service mode test and force vends are counted apart from sales
+#[derive(Debug, Copy, Clone)]
+pub enum VendMode {
+    Customer,
+    Service,
+}
+
+#[derive(Default)]
+struct MetricsData {
+    vends: BTreeMap<(String, &'static str, String), u64>, //(slot, mode, result)
+    dispense_errors: BTreeMap<String, u64>,
+    coins: BTreeMap<(u16, String), u64>, //(value, routing)
+    cashless_approvals: u64,
+    cashless_approved_value: u64,
+    cashless_denials: u64,
+    refund_shortfall: u64,
+    chiller: Option<ChillerInfo>,
+    state_seconds: BTreeMap<String, f64>,
+    current_state: Option<(String, Instant)>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    data: Mutex<MetricsData>,
+}
+
+impl Metrics {
+    pub fn vend(&self, slot: &str, mode: VendMode, result: Result<(), DispenseError>) {
+        let mut data = self.data.lock().unwrap();
+        let result = match result {
+            Ok(()) => String::from("vended"),
+            Err(e) => {
+                let error = format!("{:?}", e);
+                *data.dispense_errors.entry(error.clone()).or_default() += 1;
+                error
+            }
+        };
+        let mode = match mode {
+            VendMode::Customer => "customer",
+            VendMode::Service => "service",
+        };
+        *data.vends.entry((String::from(slot), mode, result)).or_default() += 1;
+    }
+
+    pub fn coin_inserted(&self, value: u16, routing: CoinRouting) {
+        *self.data.lock().unwrap().coins.entry((value, format!("{:?}", routing))).or_default() += 1;
+    }
+
+    pub fn cashless_event(&self, event: CashlessDeviceEvent) {
+        let mut data = self.data.lock().unwrap();
+        match event {
+            CashlessDeviceEvent::VendApproved(amount) => {
+                data.cashless_approvals += 1;
+                data.cashless_approved_value += amount as u64;
+            }
+            CashlessDeviceEvent::VendDenied => data.cashless_denials += 1,
+            _ => {}
+        }
+    }
+
+    pub fn refund_shortfall(&self, amount: u16) {
+        self.data.lock().unwrap().refund_shortfall += amount as u64;
+    }
+
+    pub fn chiller(&self, info: ChillerInfo) {
+        self.data.lock().unwrap().chiller = Some(info);
+    }
+
+    //Called after every app event, so the time spent in each state is accurate to the
+    //once-a-second poll at worst
+    pub fn observe_state(&self, state: &str) {
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        if let Some((previous, since)) = data.current_state.take() {
+            *data.state_seconds.entry(previous).or_default() += now.duration_since(since).as_secs_f64();
+        }
+        data.current_state = Some((String::from(state), now));
+    }
+
+    //In the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let data = self.data.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "snackbot_vends_total", "counter", "Vends attempted, by slot, mode (customer or service) and result");
+        for ((slot, mode, result), count) in &data.vends {
+            let _ = writeln!(out, "snackbot_vends_total{{slot=\"{}\",mode=\"{}\",result=\"{}\"}} {}", slot, mode, result, count);
+        }
+
+        header(&mut out, "snackbot_dispense_errors_total", "counter", "Failed vends, by DispenseError");
+        for (error, count) in &data.dispense_errors {
+            let _ = writeln!(out, "snackbot_dispense_errors_total{{error=\"{}\"}} {}", error, count);
+        }
+
+        header(&mut out, "snackbot_coins_inserted_total", "counter", "Coins inserted, by value (pence) and where they went");
+        for ((value, routing), count) in &data.coins {
+            let _ = writeln!(out, "snackbot_coins_inserted_total{{value=\"{}\",routing=\"{}\"}} {}", value, routing, count);
+        }
+
+        header(&mut out, "snackbot_cashless_approvals_total", "counter", "Vends approved by the card reader");
+        let _ = writeln!(out, "snackbot_cashless_approvals_total {}", data.cashless_approvals);
+        header(&mut out, "snackbot_cashless_approved_pence_total", "counter", "Total value approved by the card reader");
+        let _ = writeln!(out, "snackbot_cashless_approved_pence_total {}", data.cashless_approved_value);
+        header(&mut out, "snackbot_cashless_denials_total", "counter", "Vends denied by the card reader");
+        let _ = writeln!(out, "snackbot_cashless_denials_total {}", data.cashless_denials);
+        header(&mut out, "snackbot_refund_shortfall_pence_total", "counter", "Change and refunds owed that the coin tubes couldn't pay");
+        let _ = writeln!(out, "snackbot_refund_shortfall_pence_total {}", data.refund_shortfall);
+
+        //Left out until the VMC has reported, rather than pretending it's 0'C
+        if let Some(info) = data.chiller {
+            header(&mut out, "snackbot_chiller_temperature_celsius", "gauge", "Drinks chiller temperature");
+            let _ = writeln!(out, "snackbot_chiller_temperature_celsius {}", info.current_temp);
+            header(&mut out, "snackbot_chiller_target_celsius", "gauge", "Drinks chiller setpoint");
+            let _ = writeln!(out, "snackbot_chiller_target_celsius {}", info.target_temp);
+            header(&mut out, "snackbot_chiller_compressor_on", "gauge", "Whether the compressor is running");
+            let _ = writeln!(out, "snackbot_chiller_compressor_on {}", info.compressor_status as u8);
+            header(&mut out, "snackbot_chiller_duty_cycle_percent", "gauge", "Recent compressor duty cycle");
+            let _ = writeln!(out, "snackbot_chiller_duty_cycle_percent {}", info.duty_cycle);
+        }
+
+        //Each metric's samples must follow its own header, so the devices are gone through twice
+        let devices = [("vmc", &VMC_CONNECTED, &VMC_RECONNECTS), ("keyboard", &KEYBOARD_CONNECTED, &KEYBOARD_RECONNECTS)];
+        header(&mut out, "snackbot_usb_connected", "gauge", "Whether each USB device is connected");
+        for (device, connected, _) in devices {
+            let _ = writeln!(out, "snackbot_usb_connected{{device=\"{}\"}} {}", device, connected.load(Ordering::Relaxed) as u8);
+        }
+        header(&mut out, "snackbot_usb_reconnects_total", "counter", "Times each USB device has reconnected");
+        for (device, _, reconnects) in devices {
+            let _ = writeln!(out, "snackbot_usb_reconnects_total{{device=\"{}\"}} {}", device, reconnects.load(Ordering::Relaxed));
+        }
+
+        //Including the time so far in the current state
+        let mut state_seconds = data.state_seconds.clone();
+        if let Some((state, since)) = &data.current_state {
+            *state_seconds.entry(state.clone()).or_default() += since.elapsed().as_secs_f64();
+        }
+        header(&mut out, "snackbot_app_state_seconds_total", "counter", "Time spent in each app state");
+        for (state, seconds) in &state_seconds {
+            let _ = writeln!(out, "snackbot_app_state_seconds_total{{state=\"{}\"}} {:.3}", state, seconds);
+        }
+        header(&mut out, "snackbot_app_state", "gauge", "The current app state");
+        if let Some((state, _)) = &data.current_state {
+            let _ = writeln!(out, "snackbot_app_state{{state=\"{}\"}} 1", state);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
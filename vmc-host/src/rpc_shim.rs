@@ -1,20 +1,26 @@
 use tokio::runtime::Runtime;  //We use the Tokio runtime to run the postcard-rpc async functions
 use tokio::time::{sleep, Duration};
+use postcard_rpc::host_client::IoClosed;
 
 use std::sync::OnceLock;
+use std::sync::atomic::Ordering;
 use glib_macros::clone;
 use async_channel::{Sender, Receiver};
 
 use crate::EventTopic;
 
 use crate::{VmcDriver, VmcCommand, VmcResponse};
-use crate::{LcdDriver, LcdCommand};
+use crate::{LcdDriver, LcdCommand, LcdEvent};
+use crate::lcd_driver::keycode_to_char;
 use crate::DispenserAddress;
+use crate::management_api::{KEYBOARD_CONNECTED, VMC_CONNECTED};
+use crate::metrics::{KEYBOARD_RECONNECTS, VMC_RECONNECTS};
 
-use vmc_icd::CashlessEventTopic;
+use keyboard_icd::{KeyEventTopic, LcdStatusTopic, ServiceModeTopic};
+use vmc_icd::{BillInsertedTopic, BillValidatorEventTopic, CashlessEventTopic, ChillerInfoTopic, DispenseProgressTopic};
 
 //Spawn a tokio runtime instance for the postcard-rpc device handlers
-fn runtime() -> &'static Runtime {
+pub(crate) fn runtime() -> &'static Runtime {
     static RUNTIME: OnceLock<Runtime> = OnceLock::new();
     RUNTIME.get_or_init(|| {
         Runtime::new().expect("Failed to spawn tokio runtime")
@@ -23,13 +29,17 @@ fn runtime() -> &'static Runtime {
 
 async fn get_vmc_driver() -> VmcDriver {
     loop {
-        match VmcDriver::new() {
+        #[cfg(not(feature = "emulator"))]
+        let driver = VmcDriver::new().await;
+        #[cfg(feature = "emulator")]
+        let driver = VmcDriver::new_emulated().await;
+        match driver {
             Ok(driver) => {
                 println!("VMC driver connected OK");
                 return driver;
             }
-            Err(_e) => {
-                println!("VMC driver init failed, retrying in 15 seconds");
+            Err(e) => {
+                println!("VMC driver init failed ({}), retrying in 15 seconds", e);
                 tokio::time::sleep(Duration::from_secs(15)).await;   
             }
         }
@@ -45,12 +55,62 @@ pub(crate) fn spawn_vmc_driver(vmc_response_channel_tx:Sender<VmcResponse>, vmc_
         vmc_command_channel_rx,
         async move {
 
+            let mut connected_before = false;
             'outer: loop {
                 let mut vmc = get_vmc_driver().await;
+                VMC_CONNECTED.store(true, Ordering::Relaxed);
+                if connected_before {
+                    VMC_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                }
+                connected_before = true;
                 //Await a message
-                let mut cashless_topic = vmc.driver.subscribe_multi::<CashlessEventTopic>(8).await.unwrap();
-                let mut event_topic = vmc.driver.subscribe_multi::<EventTopic>(8).await.unwrap();
-                let mut coin_inserted_topic = vmc.driver.subscribe_multi::<vmc_icd::CoinInsertedTopic>(8).await.unwrap();
+                let topics = async {
+                    Ok::<_, IoClosed>((
+                        vmc.driver.subscribe_multi::<CashlessEventTopic>(8).await?,
+                        vmc.driver.subscribe_multi::<EventTopic>(8).await?,
+                        vmc.driver.subscribe_multi::<vmc_icd::CoinInsertedTopic>(8).await?,
+                        vmc.driver.subscribe_multi::<ChillerInfoTopic>(8).await?,
+                        vmc.driver.subscribe_multi::<DispenseProgressTopic>(8).await?,
+                        vmc.driver.subscribe_multi::<BillInsertedTopic>(8).await?,
+                        vmc.driver.subscribe_multi::<BillValidatorEventTopic>(8).await?,
+                    ))
+                }.await;
+                let (
+                    mut cashless_topic,
+                    mut event_topic,
+                    mut coin_inserted_topic,
+                    mut chiller_topic,
+                    mut dispense_progress_topic,
+                    mut bill_inserted_topic,
+                    mut bill_event_topic,
+                ) = match topics {
+                    Ok(topics) => topics,
+                    Err(_e) => {
+                        //The connection went away before we could listen to it
+                        println!("Error - failed to subscribe to VMC topics, reconnecting");
+                        vmc.driver.close();
+                        VMC_CONNECTED.store(false, Ordering::Relaxed);
+                        continue 'outer;
+                    }
+                };
+                //(Re)build the host's machine model whenever the VMC connects
+                match vmc.map_machine().await {
+                    Ok(dispensers) => {
+                        let _ = vmc_response_channel_tx.send(VmcResponse::MachineMap(dispensers)).await;
+                    },
+                    Err(_e) => {
+                        println!("Error - failed to map machine");
+                    },
+                }
+                //The app needs the tube levels before it can take a note
+                match vmc.get_coin_acceptor_info().await {
+                    Ok(info) => {
+                        let _ = vmc_response_channel_tx.send(VmcResponse::CoinAcceptorInfo(info)).await;
+                    },
+                    Err(_e) => {
+                        println!("Error - failed to get coin acceptor info");
+                    },
+                }
                 'recvpoll: loop {
                     tokio::select! {
                         val = event_topic.recv()  => {
@@ -77,20 +137,71 @@ pub(crate) fn spawn_vmc_driver(vmc_response_channel_tx:Sender<VmcResponse>, vmc_
                                 let _ = vmc_response_channel_tx.send(VmcResponse::CashlessEvent(event)).await;
                             }
                         }
+                        val = dispense_progress_topic.recv() => {
+                            if let Ok(progress) = val {
+                                let _ = vmc_response_channel_tx.send(VmcResponse::DispenseProgress(progress)).await;
+                            }
+                        }
+                        val = bill_inserted_topic.recv() => {
+                            if let Ok(bill) = val {
+                                let _ = vmc_response_channel_tx.send(VmcResponse::BillInsertedEvent(bill)).await;
+                            }
+                            else {
+                                println!("Error receiving billinserted event");
+                                break 'recvpoll;
+                            }
+                        }
+                        val = bill_event_topic.recv() => {
+                            if let Ok(event) = val {
+                                let _ = vmc_response_channel_tx.send(VmcResponse::BillValidatorEvent(event)).await;
+                            }
+                        }
+                        val = chiller_topic.recv() => {
+                            if let Ok(info) = val {
+                                let _ = vmc_response_channel_tx.send(VmcResponse::ChillerInfo(info)).await;
+                            }
+                        }
                         val = vmc_command_channel_rx.recv() => {
                             if let Ok(cmd) = val {
                                 match cmd {
-                                    VmcCommand::VendItem(row, col) => {
-                                        println!("Vend command received - {}{}",row,col);
+                                    VmcCommand::VendItem(row, col) | VmcCommand::ForceVendItem(row, col) => {
+                                        let force = matches!(cmd, VmcCommand::ForceVendItem(..));
+                                        println!("{} command received - {}{}", if force { "Force vend" } else { "Vend" }, row, col);
                                         //Send VMC command
-                                        match vmc.dispense(DispenserAddress {row, col}).await {
+                                        let vend = async {
+                                            if force {
+                                                vmc.force_dispense(DispenserAddress {row, col}).await
+                                            } else {
+                                                vmc.dispense(DispenserAddress {row, col}).await
+                                            }
+                                        };
+                                        tokio::pin!(vend);
+                                        //Keep passing on progress while the vend runs, so it's shown live. Progress
+                                        //is checked first, so none is left queued behind the result
+                                        let mut progress_open = true;
+                                        let result = loop {
+                                            tokio::select! {
+                                                biased;
+                                                val = dispense_progress_topic.recv(), if progress_open => {
+                                                    match val {
+                                                        Ok(progress) => {
+                                                            let _ = vmc_response_channel_tx.send(VmcResponse::DispenseProgress(progress)).await;
+                                                        }
+                                                        //Leave it to the vend to report the comms error
+                                                        Err(_) => progress_open = false,
+                                                    }
+                                                }
+                                                result = &mut vend => break result,
+                                            }
+                                        };
+                                        match result {
                                             Ok(()) => {
                                                 println!("Vend success");
                                                 let _ = vmc_response_channel_tx.send(VmcResponse::DispenseSuccessEvent).await;
                                             },
                                             Err(e) => {
-                                                println!("Error - failed to vend");
-                                                let _ = vmc_response_channel_tx.send(VmcResponse::DispenseFailedEvent).await;
+                                                println!("Error - failed to vend - {:?}", e);
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::DispenseFailedEvent(e)).await;
                                             },
                                         }
                                     },
@@ -101,6 +212,111 @@ pub(crate) fn spawn_vmc_driver(vmc_response_channel_tx:Sender<VmcResponse>, vmc_
                                         println!("Sending cashless command");
                                         let _ = vmc.send_cashless_device_command(cmd).await;
                                     }
+                                    VmcCommand::BillValidatorCmd(cmd) => {
+                                        let _ = vmc.send_bill_validator_command(cmd).await;
+                                    }
+                                    VmcCommand::GetMachineMap() => {
+                                        match vmc.map_machine().await {
+                                            Ok(dispensers) => {
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::MachineMap(dispensers)).await;
+                                            },
+                                            Err(_e) => {
+                                                println!("Error - failed to map machine");
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::GetDispenser(row, col) => {
+                                        match vmc.get_dispenser(DispenserAddress {row, col}).await {
+                                            Ok(Some(dispenser)) => {
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::Dispenser(dispenser)).await;
+                                            },
+                                            Ok(None) => {
+                                                println!("No dispenser fitted at {}{}", row, col);
+                                            },
+                                            Err(_e) => {
+                                                println!("Error - failed to get dispenser status");
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::RefundCoins(amount) => {
+                                        match vmc.dispense_coins(amount).await {
+                                            Ok(paid) => {
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::CoinsRefunded(amount, paid)).await;
+                                            },
+                                            Err(_e) => {
+                                                println!("Error - failed to refund coins");
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::CoinsRefunded(amount, 0)).await;
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::GetCoinAcceptorInfo => {
+                                        match vmc.get_coin_acceptor_info().await {
+                                            Ok(info) => {
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::CoinAcceptorInfo(info)).await;
+                                            },
+                                            Err(_e) => {
+                                                println!("Error - failed to get coin acceptor info");
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::GetChillerInfo => {
+                                        match vmc.get_chiller_info().await {
+                                            Ok(info) => {
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::ChillerInfo(info)).await;
+                                            },
+                                            Err(_e) => {
+                                                println!("Error - failed to get chiller info");
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::SetChillerSetpoint(setpoint) => {
+                                        if vmc.set_chiller_setpoint(setpoint).await.is_err() {
+                                            println!("Error - chiller setpoint of {}'C not accepted", setpoint);
+                                        }
+                                    }
+                                    VmcCommand::GetMachineLayout => {
+                                        match vmc.get_machine_layout().await {
+                                            Ok(layout) => {
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::MachineLayout(layout)).await;
+                                            },
+                                            Err(_e) => {
+                                                println!("Error - failed to get machine layout");
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::SetMachineLayout(layout) => {
+                                        match vmc.set_machine_layout(layout).await {
+                                            Ok(()) => {
+                                                //Fitted dispensers may have changed, so remap
+                                                if let Ok(dispensers) = vmc.map_machine().await {
+                                                    let _ = vmc_response_channel_tx.send(VmcResponse::MachineMap(dispensers)).await;
+                                                }
+                                            },
+                                            Err(e) => {
+                                                println!("Error - machine layout not accepted - {:?}", e);
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::GetConfig => {
+                                        match vmc.get_config().await {
+                                            Ok(config) => {
+                                                let _ = vmc_response_channel_tx.send(VmcResponse::Config(config)).await;
+                                            },
+                                            Err(_e) => {
+                                                println!("Error - failed to get VMC config");
+                                            },
+                                        }
+                                    }
+                                    VmcCommand::SetConfig(config) => {
+                                        match vmc.set_config(config).await {
+                                            Ok(()) => {
+                                                println!("VMC config updated");
+                                            },
+                                            Err(e) => {
+                                                println!("Error - VMC config not accepted - {:?}", e);
+                                            },
+                                        }
+                                    }
                                     _ => {},
                                 }
                             }  
@@ -111,6 +327,7 @@ pub(crate) fn spawn_vmc_driver(vmc_response_channel_tx:Sender<VmcResponse>, vmc_
                         } 
                     }
                 }
+                VMC_CONNECTED.store(false, Ordering::Relaxed);
             }
         }
     ));
@@ -118,51 +335,154 @@ pub(crate) fn spawn_vmc_driver(vmc_response_channel_tx:Sender<VmcResponse>, vmc_
 
 pub async fn get_lcd_driver() -> LcdDriver {
     loop {
-        match LcdDriver::new() {
+        match LcdDriver::new().await {
             Ok(driver) => {
                 println!("LCD driver connected OK");
                 return driver;
             }
-            Err(_e) => {
-                println!("LCD driver init failed, retrying in 15 seconds");
+            Err(e) => {
+                println!("LCD driver init failed ({}), retrying in 15 seconds", e);
                 tokio::time::sleep(Duration::from_secs(15)).await;   
             }
         }
     }
 }
 
-pub(crate) fn spawn_lcd_driver(lcd_command_channel_rx:Receiver<LcdCommand>) {
+pub(crate) fn spawn_lcd_driver(lcd_command_channel_rx:Receiver<LcdCommand>, lcd_event_channel_tx:Sender<LcdEvent>) {
     runtime().spawn(clone!(
         #[strong] 
         lcd_command_channel_rx,
+        #[strong]
+        lcd_event_channel_tx,
         async move {
-            let mut lcd = get_lcd_driver().await;
-            loop {
-                if let Ok(cmd) = lcd_command_channel_rx.recv().await {
-                    match cmd {
-                        LcdCommand::SetText(l1,l2) => {
-                            match lcd.set_text(l1,l2).await {
-                                Ok(_x) => {},
-                                Err(_x) => {
-                                    println!("LCD set text error");
+            let mut connected_before = false;
+            'outer: loop {
+                let mut lcd = get_lcd_driver().await;
+                KEYBOARD_CONNECTED.store(true, Ordering::Relaxed);
+                if connected_before {
+                    KEYBOARD_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                }
+                connected_before = true;
+                let topics = async {
+                    Ok::<_, IoClosed>((
+                        lcd.driver.subscribe_multi::<KeyEventTopic>(8).await?,
+                        lcd.driver.subscribe_multi::<ServiceModeTopic>(8).await?,
+                        lcd.driver.subscribe_multi::<LcdStatusTopic>(8).await?,
+                    ))
+                }.await;
+                let (mut key_event_topic, mut service_mode_topic, mut lcd_status_topic) = match topics {
+                    Ok(topics) => topics,
+                    Err(_e) => {
+                        println!("Error - failed to subscribe to keyboard topics, reconnecting");
+                        lcd.driver.close();
+                        KEYBOARD_CONNECTED.store(false, Ordering::Relaxed);
+                        continue 'outer;
+                    }
+                };
+                //The switch may have been changed while we weren't listening
+                match lcd.get_service_mode().await {
+                    Ok(state) => {
+                        let _ = lcd_event_channel_tx.send(LcdEvent::ServiceMode(state)).await;
+                    },
+                    Err(_e) => {
+                        println!("Error - failed to get service mode switch state");
+                    },
+                }
+                match lcd.get_lcd_present().await {
+                    Ok(present) => {
+                        let _ = lcd_event_channel_tx.send(LcdEvent::LcdPresent(present)).await;
+                    },
+                    Err(_e) => {
+                        println!("Error - failed to get LCD status");
+                    },
+                }
+                'recvpoll: loop {
+                    tokio::select! {
+                        val = key_event_topic.recv() => {
+                            if let Ok(event) = val {
+                                //Only presses matter to the app - releases are ignored
+                                if event.pressed {
+                                    match keycode_to_char(event.keycode) {
+                                        Some(c) => {
+                                            let _ = lcd_event_channel_tx.send(LcdEvent::Keypress(c)).await;
+                                        },
+                                        None => {
+                                            println!("Unmapped key {:#04x} at row {} col {}", event.keycode, event.row, event.col);
+                                        },
+                                    }
                                 }
                             }
-                        },
-                        LcdCommand::SetBackLight(state) => {
-                            match lcd.set_backlight(state).await {
-                                Ok(_x) => {},
-                                Err(_x) => {   
-                                    println!("LCD set backlight error");
+                            else {
+                                println!("Error receiving key event");
+                                break 'recvpoll;
+                            }
+                        }
+                        val = service_mode_topic.recv() => {
+                            if let Ok(state) = val {
+                                let _ = lcd_event_channel_tx.send(LcdEvent::ServiceMode(state)).await;
+                            }
+                            else {
+                                println!("Error receiving service mode event");
+                                break 'recvpoll;
+                            }
+                        }
+                        val = lcd_status_topic.recv() => {
+                            if let Ok(present) = val {
+                                let _ = lcd_event_channel_tx.send(LcdEvent::LcdPresent(present)).await;
+                            }
+                            else {
+                                println!("Error receiving LCD status event");
+                                break 'recvpoll;
+                            }
+                        }
+                        val = lcd_command_channel_rx.recv() => {
+                            if let Ok(cmd) = val {
+                                match cmd {
+                                    LcdCommand::SetText(lines) => {
+                                        match lcd.set_text(&lines).await {
+                                            Ok(_x) => {},
+                                            Err(_x) => {
+                                                println!("LCD set text error");
+                                            }
+                                        }
+                                    },
+                                    LcdCommand::SetBackLight(state) => {
+                                        match lcd.set_backlight(state).await {
+                                            Ok(_x) => {},
+                                            Err(_x) => {   
+                                                println!("LCD set backlight error");
+                                            }
+                                        }
+                                    },
+                                    LcdCommand::QueueMessage(message) => {
+                                        match lcd.queue_message(message).await {
+                                            Ok(_x) => {},
+                                            Err(e) => {
+                                                println!("LCD queue message error - {:?}", e);
+                                            }
+                                        }
+                                    },
+                                    LcdCommand::CancelMessage(id) => {
+                                        match lcd.cancel_message(id).await {
+                                            Ok(_x) => {},
+                                            Err(_x) => {
+                                                println!("LCD cancel message error");
+                                            }
+                                        }
+                                    },
                                 }
                             }
-                        },
+                            else {
+                                //Every sender has gone, so no more commands can ever arrive
+                                println!("LCD driver command channel closed - stopping LCD driver");
+                                KEYBOARD_CONNECTED.store(false, Ordering::Relaxed);
+                                return;
+                            }
+                        }
                     }
                 }
-                else {
-                    println!("LCD driver command rx err");
-                }             
+                KEYBOARD_CONNECTED.store(false, Ordering::Relaxed);
             }
         }
     ));
 }
- 
\ No newline at end of file
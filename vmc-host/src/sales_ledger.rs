@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use vmc_icd::dispenser::DispenseError;
+
+use crate::PaymentMethod;
+
+//Where sales are recorded, unless overridden by SNACKBOT_LEDGER
+pub const DEFAULT_LEDGER_PATH: &str = "sales.jsonl";
+
+//The most recent sessions are kept in memory, so the management API needn't read the ledger
+const RECENT_SALES_KEPT: usize = 500;
+
+const CSV_HEADER: &str =
+    "started,finished,slot,product,price,payment_method,cash_paid,cashless_approved,result,refund_shortfall";
+
+//How a vend session ended. Any money taken or approved is refunded unless the item was vended
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionOutcome {
+    Vended,
+    Failed,    //See dispense_error
+    Cancelled, //By the customer, or by the service switch
+    TimedOut,  //Waiting for payment
+    //Not a session of its own - the coin tubes paid back less than a session's customer was
+    //owed (cash_paid), by refund_shortfall. slot is blank if the cash wasn't for a session
+    ShortRefund,
+}
+
+//One vend session - from the customer confirming their selection to the dispense result,
+//or to it being abandoned
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SaleRecord {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub slot: String,
+    pub product: String,
+    pub price: u16,
+    pub payment_method: Option<PaymentMethod>,
+    pub cash_paid: u16,                 //Coins and notes taken, before change
+    pub cashless_approved: Option<u16>, //As approved by the card reader
+    pub dispense_error: Option<DispenseError>, //Only if the vend failed
+    //Missing from ledgers written when only vends were recorded - read_ledger fills it in
+    #[serde(default)]
+    pub outcome: Option<SessionOutcome>,
+    #[serde(default)]
+    pub refund_shortfall: Option<u16>, //Only for ShortRefund
+}
+
+//Append-only record of every vend session, one JSON object per line so a power cut can only
+//ever lose the line being written. Clones share the in-memory list of recent sessions
+#[derive(Clone)]
+pub struct SalesLedger {
+    path: PathBuf,
+    recent: Arc<Mutex<VecDeque<SaleRecord>>>,
+}
+
+impl SalesLedger {
+    //Reads the ledger once, to pick up the sessions recorded before a restart
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut recent: VecDeque<SaleRecord> = read_ledger(&path).unwrap_or_default().into();
+        let skip = recent.len().saturating_sub(RECENT_SALES_KEPT);
+        recent.drain(..skip);
+        Self { path, recent: Arc::new(Mutex::new(recent)) }
+    }
+
+    pub fn record(&self, sale: &SaleRecord) {
+        let result = serde_json::to_string(sale).map_err(std::io::Error::from).and_then(|line| {
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            writeln!(file, "{}", line)?;
+            file.sync_data()
+        });
+        if let Err(e) = result {
+            println!("Error - unable to record sale in {}: {}", self.path.display(), e);
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_SALES_KEPT {
+            recent.pop_front();
+        }
+        recent.push_back(sale.clone());
+    }
+
+    //The most recent sessions (no more than RECENT_SALES_KEPT), newest last
+    pub fn recent(&self, count: usize) -> Vec<SaleRecord> {
+        let recent = self.recent.lock().unwrap();
+        recent.iter().skip(recent.len().saturating_sub(count)).cloned().collect()
+    }
+}
+
+fn read_ledger(path: &Path) -> std::io::Result<Vec<SaleRecord>> {
+    let file = std::fs::File::open(path)?;
+    let mut sales = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<SaleRecord>(&line) {
+            Ok(mut sale) => {
+                sale.outcome.get_or_insert(match sale.dispense_error {
+                    Some(_) => SessionOutcome::Failed,
+                    None => SessionOutcome::Vended,
+                });
+                sales.push(sale);
+            }
+            //Most likely cut short by a power cut - report it, but carry on
+            Err(e) => eprintln!("Skipping ledger line {}: {}", n + 1, e),
+        }
+    }
+    Ok(sales)
+}
+
+//Write the sessions that finished between two dates (inclusive, local time) as CSV
+pub fn export_csv(path: &Path, from: NaiveDate, to: NaiveDate, out: &mut impl Write) -> std::io::Result<usize> {
+    let mut count = 0;
+    writeln!(out, "{}", CSV_HEADER)?;
+    for sale in read_ledger(path)? {
+        let date = sale.finished.with_timezone(&Local).date_naive();
+        if date < from || date > to {
+            continue;
+        }
+        writeln!(
+            out,
+            "{},{},{},{},{}.{:02},{},{}.{:02},{},{},{}",
+            sale.started.with_timezone(&Local).to_rfc3339(),
+            sale.finished.with_timezone(&Local).to_rfc3339(),
+            sale.slot,
+            csv_escape(&sale.product),
+            sale.price / 100,
+            sale.price % 100,
+            match sale.payment_method {
+                Some(PaymentMethod::Cash) => "cash",
+                Some(PaymentMethod::Cashless) => "cashless",
+                None => "",
+            },
+            sale.cash_paid / 100,
+            sale.cash_paid % 100,
+            sale.cashless_approved.map(|a| format!("{}.{:02}", a / 100, a % 100)).unwrap_or_default(),
+            match (sale.outcome, sale.dispense_error) {
+                (_, Some(e)) => format!("{:?}", e),
+                (Some(SessionOutcome::Cancelled), _) => String::from("cancelled"),
+                (Some(SessionOutcome::TimedOut), _) => String::from("timedout"),
+                (Some(SessionOutcome::ShortRefund), _) => String::from("shortrefund"),
+                _ => String::from("vended"),
+            },
+            sale.refund_shortfall.map(|a| format!("{}.{:02}", a / 100, a % 100)).unwrap_or_default(),
+        )?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+//vmc-host export-sales <from> <to> [ledger] - dates are YYYY-MM-DD, output goes to stdout
+pub fn export_sales_command(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: vmc-host export-sales <from YYYY-MM-DD> <to YYYY-MM-DD> [ledger file]";
+    let (from, to) = match args {
+        [from, to] | [from, to, _] => (from, to),
+        _ => return Err(String::from(usage)),
+    };
+    let parse = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|e| format!("Invalid date {}: {}\n{}", s, e, usage));
+    let (from, to) = (parse(from)?, parse(to)?);
+    let path = match args.get(2) {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(std::env::var("SNACKBOT_LEDGER").unwrap_or_else(|_| String::from(DEFAULT_LEDGER_PATH))),
+    };
+
+    let mut out = std::io::stdout().lock();
+    let count = export_csv(&path, from, to, &mut out).map_err(|e| format!("Unable to export {}: {}", path.display(), e))?;
+    eprintln!("Exported {} sales", count);
+    Ok(())
+}
@@ -0,0 +1,367 @@
+use std::fmt::Write;
+
+use gtk4::glib;
+use vmc_icd::coin_acceptor::CoinAcceptorInfo;
+use vmc_icd::dispenser::{CanStatus, DispenseError, DispenserAddress, MotorStatus, DISPENSER_COLS, DISPENSER_ROWS};
+
+use crate::lcd_driver::{KEY_DOWN, KEY_UP};
+use crate::machine_model::MachineModel;
+use crate::stock_info::Catalogue;
+use crate::stock_levels::{address_key, StockLevels};
+
+//Up/down change prices by this much
+const PRICE_STEP: u16 = 10;
+
+#[derive(Copy, Clone, PartialEq)]
+enum MenuItem {
+    MachineMap,
+    PaymentDevices,
+    Slot(SlotAction),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SlotAction {
+    TestVend,
+    ForceVend, //Vends even if the dispenser reports it shouldn't, eg the last can
+    SetStock,
+    SetPrice,
+}
+
+const MENU: [(&str, MenuItem); 6] = [
+    ("Machine map", MenuItem::MachineMap),
+    ("Payment devices", MenuItem::PaymentDevices),
+    ("Test vend", MenuItem::Slot(SlotAction::TestVend)),
+    ("Force vend", MenuItem::Slot(SlotAction::ForceVend)),
+    ("Set stock", MenuItem::Slot(SlotAction::SetStock)),
+    ("Set price", MenuItem::Slot(SlotAction::SetPrice)),
+];
+
+enum ServicePage {
+    Menu,
+    MachineMap,
+    PaymentDevices,
+    SelectSlot(SlotAction, Option<char>),
+    ConfirmVend(SlotAction, DispenserAddress),
+    Edit { action: SlotAction, address: DispenserAddress, value: u16, typed: bool },
+    Vending(DispenserAddress),
+    Message(String),
+}
+
+//What the app needs to do in response to a key press in service mode
+pub enum ServiceAction {
+    None,
+    RefreshMap,
+    RefreshPaymentDevices,
+    Vend(DispenserAddress, bool), //Address, and whether to force the vend
+    SetStock(DispenserAddress, u16),
+    SetPrice(DispenserAddress, u16),
+}
+
+//Everything the service screens show, other than the menu's own state
+pub struct ServiceView<'a> {
+    pub machine: &'a MachineModel,
+    pub catalogue: &'a Catalogue,
+    pub stock: &'a StockLevels,
+    pub coin_acceptor: Option<&'a CoinAcceptorInfo>,
+    pub cashless_available: Option<bool>, //None until the card reader has said either way
+}
+
+pub struct ServiceScreen {
+    pub title: String,
+    pub body: String, //Pango markup
+    pub help: &'static str,
+}
+
+//The service mode menu, driven entirely from the keypad: up/down (or 1-6) and enter to pick
+//an item, esc to go back. Slots are picked by row letter then column number, as when buying
+pub struct ServiceMenu {
+    selected: usize,
+    page: ServicePage,
+}
+
+impl ServiceMenu {
+    pub fn new() -> Self {
+        Self { selected: 0, page: ServicePage::Menu }
+    }
+
+    pub fn is_vending(&self) -> bool {
+        matches!(self.page, ServicePage::Vending(_))
+    }
+
+    pub fn vending_address(&self) -> Option<DispenserAddress> {
+        match self.page {
+            ServicePage::Vending(address) => Some(address),
+            _ => None,
+        }
+    }
+
+    //Shown until the next key press
+    pub fn show_message(&mut self, message: String) {
+        self.page = ServicePage::Message(message);
+    }
+
+    pub fn vend_result(&mut self, result: Result<(), DispenseError>) {
+        if let ServicePage::Vending(address) = self.page {
+            self.show_message(match result {
+                Ok(()) => format!("{} vended OK", address_key(address)),
+                Err(e) => format!("{} failed - {:?}", address_key(address), e),
+            });
+        }
+    }
+
+    pub fn handle_key(&mut self, key: char, catalogue: &Catalogue, stock: &StockLevels) -> ServiceAction {
+        match self.page {
+            ServicePage::Menu => match key {
+                KEY_UP => self.selected = (self.selected + MENU.len() - 1) % MENU.len(),
+                KEY_DOWN => self.selected = (self.selected + 1) % MENU.len(),
+                '1'..='6' => {
+                    self.selected = key as usize - '1' as usize;
+                    return self.open(MENU[self.selected].1);
+                }
+                '\n' => return self.open(MENU[self.selected].1),
+                _ => {}
+            },
+            ServicePage::MachineMap => match key {
+                '\n' => return ServiceAction::RefreshMap,
+                '\x1b' => self.page = ServicePage::Menu,
+                _ => {}
+            },
+            ServicePage::PaymentDevices => match key {
+                '\n' => return ServiceAction::RefreshPaymentDevices,
+                '\x1b' => self.page = ServicePage::Menu,
+                _ => {}
+            },
+            ServicePage::SelectSlot(action, row) => {
+                if key.is_ascii_alphabetic() {
+                    self.page = ServicePage::SelectSlot(action, Some(key.to_ascii_uppercase()));
+                } else if key.is_ascii_digit() {
+                    if let Some(row) = row {
+                        self.slot_selected(action, DispenserAddress { row, col: key }, catalogue, stock);
+                    }
+                } else if key == '\x1b' {
+                    self.page = ServicePage::Menu;
+                }
+            }
+            ServicePage::ConfirmVend(action, address) => match key {
+                '\n' => {
+                    self.page = ServicePage::Vending(address);
+                    return ServiceAction::Vend(address, action == SlotAction::ForceVend);
+                }
+                '\x1b' => self.page = ServicePage::SelectSlot(action, None),
+                _ => {}
+            },
+            ServicePage::Edit { action, address, ref mut value, ref mut typed } => {
+                let step = if action == SlotAction::SetPrice { PRICE_STEP } else { 1 };
+                match key {
+                    '0'..='9' => {
+                        let digit = key as u16 - '0' as u16;
+                        //The first digit typed replaces the current value
+                        let base = if *typed { *value } else { 0 };
+                        if let Some(v) = base.checked_mul(10).and_then(|v| v.checked_add(digit)) {
+                            *value = v;
+                            *typed = true;
+                        }
+                    }
+                    KEY_UP => *value = value.saturating_add(step),
+                    KEY_DOWN => *value = value.saturating_sub(step),
+                    '\n' => {
+                        let value = *value;
+                        self.page = ServicePage::Menu;
+                        return match action {
+                            SlotAction::SetPrice => ServiceAction::SetPrice(address, value),
+                            _ => ServiceAction::SetStock(address, value),
+                        };
+                    }
+                    '\x1b' => self.page = ServicePage::SelectSlot(action, None),
+                    _ => {}
+                }
+            }
+            //Nothing to do until the VMC reports the result
+            ServicePage::Vending(_) => {}
+            ServicePage::Message(_) => self.page = ServicePage::Menu,
+        }
+        ServiceAction::None
+    }
+
+    fn open(&mut self, item: MenuItem) -> ServiceAction {
+        match item {
+            MenuItem::MachineMap => {
+                self.page = ServicePage::MachineMap;
+                ServiceAction::RefreshMap
+            }
+            MenuItem::PaymentDevices => {
+                self.page = ServicePage::PaymentDevices;
+                ServiceAction::RefreshPaymentDevices
+            }
+            MenuItem::Slot(action) => {
+                self.page = ServicePage::SelectSlot(action, None);
+                ServiceAction::None
+            }
+        }
+    }
+
+    fn slot_selected(&mut self, action: SlotAction, address: DispenserAddress, catalogue: &Catalogue, stock: &StockLevels) {
+        self.page = match action {
+            SlotAction::TestVend | SlotAction::ForceVend => ServicePage::ConfirmVend(action, address),
+            SlotAction::SetStock => ServicePage::Edit {
+                action,
+                address,
+                value: stock.get(address).map(|slot| slot.count).unwrap_or(0),
+                typed: false,
+            },
+            //Prices live in the catalogue, so the item has to be listed there first
+            SlotAction::SetPrice => match catalogue.get_stock_item(address) {
+                Some(item) => ServicePage::Edit { action, address, value: item.price, typed: false },
+                None => ServicePage::Message(format!("{} is not in the catalogue", address_key(address))),
+            },
+        };
+    }
+
+    pub fn render(&self, view: &ServiceView) -> ServiceScreen {
+        let (title, body, help) = match &self.page {
+            ServicePage::Menu => {
+                let mut body = String::new();
+                for (i, (name, _)) in MENU.iter().enumerate() {
+                    let marker = if i == self.selected { '>' } else { ' ' };
+                    let _ = writeln!(body, "{} {}. {}", marker, i + 1, name);
+                }
+                (String::from("Menu"), body, "\u{2191}\u{2193} and enter, or 1-6\nTurn the service switch off to exit")
+            }
+            ServicePage::MachineMap => (String::from("Machine map"), render_machine_map(view), "Enter to refresh, esc to go back"),
+            ServicePage::PaymentDevices => {
+                (String::from("Payment devices"), render_payment_devices(view), "Enter to refresh, esc to go back")
+            }
+            ServicePage::SelectSlot(action, row) => (
+                String::from(action_name(*action)),
+                format!("Slot: {}{}", row.unwrap_or('_'), '_'),
+                "Enter the row letter then column number\nEsc to go back",
+            ),
+            ServicePage::ConfirmVend(action, address) => (
+                String::from(action_name(*action)),
+                render_slot(view, *address),
+                "Enter to vend, esc to cancel",
+            ),
+            ServicePage::Edit { action, address, value, .. } => {
+                let mut body = render_slot(view, *address);
+                let _ = match action {
+                    SlotAction::SetPrice => write!(body, "\nNew price: £{}.{:02}", value / 100, value % 100),
+                    _ => write!(body, "\nNew stock: {}", value),
+                };
+                (String::from(action_name(*action)), body, "Type a value, or \u{2191}\u{2193} to adjust\nEnter to save, esc to cancel")
+            }
+            ServicePage::Vending(address) => (
+                String::from("Vending"),
+                format!("Vending {}...", address_key(*address)),
+                "Please wait",
+            ),
+            ServicePage::Message(message) => (String::from("Info"), String::from(glib::markup_escape_text(message)), "Press any key"),
+        };
+        ServiceScreen { title, body, help }
+    }
+}
+
+fn action_name(action: SlotAction) -> &'static str {
+    match action {
+        SlotAction::TestVend => "Test vend",
+        SlotAction::ForceVend => "Force vend",
+        SlotAction::SetStock => "Set stock",
+        SlotAction::SetPrice => "Set price",
+    }
+}
+
+//What's known about one slot - product, price, stock and dispenser status
+fn render_slot(view: &ServiceView, address: DispenserAddress) -> String {
+    let mut body = format!("Slot {}\n", address_key(address));
+    match view.catalogue.get_stock_item(address) {
+        Some(item) => {
+            let _ = writeln!(body, "{}", glib::markup_escape_text(&item.name));
+            let _ = writeln!(body, "Price: £{}.{:02}", item.price / 100, item.price % 100);
+        }
+        None => body.push_str("Not in the catalogue\n"),
+    }
+    match view.stock.get(address) {
+        Some(slot) => {
+            let _ = writeln!(body, "Stock: {}{}", slot.count, if slot.is_low() { " (low)" } else { "" });
+        }
+        None => body.push_str("Stock: not counted\n"),
+    }
+    match view.machine.dispenser(address) {
+        Some(d) => {
+            let _ = write!(body, "{:?} dispenser, motor {:?}", d.dispenser_type, d.motor_status);
+            if let Some(can) = d.can_status {
+                let _ = write!(body, ", cans {:?}", can);
+            }
+        }
+        None if view.machine.is_mapped() => body.push_str("No dispenser fitted"),
+        None => body.push_str("Machine not mapped yet"),
+    }
+    body
+}
+
+//One cell per address: the stock count (or "ok" if not counted), M if the motor isn't home,
+//L for the last can, and . where nothing is fitted
+fn render_machine_map(view: &ServiceView) -> String {
+    if !view.machine.is_mapped() {
+        return String::from("Waiting for the VMC...");
+    }
+    let mut body = String::from("  ");
+    for col in DISPENSER_COLS {
+        let _ = write!(body, "{:>4}", col);
+    }
+    body.push('\n');
+    for row in DISPENSER_ROWS {
+        let _ = write!(body, "{} ", row);
+        for col in DISPENSER_COLS {
+            let address = DispenserAddress { row, col };
+            let cell = match view.machine.dispenser(address) {
+                None => String::from("."),
+                Some(d) if d.motor_status == MotorStatus::MotorNotHome => String::from("M"),
+                Some(d) if d.can_status == Some(CanStatus::LastCan) => String::from("L"),
+                Some(_) => match view.stock.get(address) {
+                    Some(slot) if slot.is_low() => format!("<span foreground=\"red\">{:>3}</span>", slot.count),
+                    Some(slot) => slot.count.to_string(),
+                    None => String::from("ok"),
+                },
+            };
+            //Pad by hand - the markup would throw out format!'s widths
+            let visible = if cell.starts_with('<') { 3 } else { cell.len() };
+            let _ = write!(body, "{}{}", " ".repeat(4 - visible.min(4)), cell);
+        }
+        body.push('\n');
+    }
+    body.push_str("\nNumber = stock, ok = not counted\nM = motor not home, L = last can, . = empty");
+    body
+}
+
+fn render_payment_devices(view: &ServiceView) -> String {
+    let mut body = String::new();
+    match view.coin_acceptor {
+        Some(info) => {
+            body.push_str("Coin acceptor: OK\n");
+            for ((value, level), full) in info.coin_values.iter().zip(info.tube_levels).zip(info.tube_full) {
+                if *value == 0 {
+                    continue;
+                }
+                let _ = writeln!(
+                    body,
+                    "  £{}.{:02}  {:>3} coins{}",
+                    value / 100,
+                    value % 100,
+                    level,
+                    if full { " (full)" } else { "" }
+                );
+            }
+        }
+        None => body.push_str("Coin acceptor: not responding\n"),
+    }
+    let _ = write!(
+        body,
+        "\nCard reader: {}",
+        match view.cashless_available {
+            Some(true) => "available",
+            Some(false) => "unavailable",
+            None => "no status yet",
+        }
+    );
+    body
+}
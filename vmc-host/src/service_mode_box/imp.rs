@@ -0,0 +1,45 @@
+use gtk4::prelude::{BoxExt, OrientableExt, WidgetExt};
+use gtk4::subclass::prelude::*;
+use gtk4::Label;
+
+#[derive(Default)]
+pub struct ServiceModeBox {
+    pub title: Label,
+    pub body: Label,
+    pub help: Label,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for ServiceModeBox {
+    const NAME: &'static str = "SnackBoxServiceModeBox";
+    type Type = super::ServiceModeBox;
+    type ParentType = gtk4::Box;
+}
+
+// Trait shared by all GObjects
+impl ObjectImpl for ServiceModeBox {
+    fn constructed(&self) {
+        self.parent_constructed();
+        self.obj().set_orientation(gtk4::Orientation::Vertical);
+        self.obj().set_spacing(30);
+
+        self.title.set_use_markup(true);
+        self.obj().append(&self.title);
+
+        //Monospaced, so the machine map lines up
+        self.body.set_use_markup(true);
+        self.body.set_xalign(0.0);
+        self.body.set_vexpand(true);
+        self.body.set_valign(gtk4::Align::Start);
+        self.obj().append(&self.body);
+
+        self.help.set_use_markup(true);
+        self.help.set_justify(gtk4::Justification::Center);
+        self.obj().append(&self.help);
+    }
+}
+
+// Trait shared by all widgets
+impl WidgetImpl for ServiceModeBox {}
+
+impl BoxImpl for ServiceModeBox {}
@@ -0,0 +1,34 @@
+use glib::clone::Upgrade;
+use glib::object::Cast;
+use gtk4::glib;
+use gtk4::glib::Object;
+use gtk4::subclass::prelude::*;
+mod imp;
+
+use crate::service_menu::ServiceScreen;
+
+glib::wrapper! {
+    pub struct ServiceModeBox(ObjectSubclass<imp::ServiceModeBox>)
+        @extends gtk4::Box, gtk4::Widget,
+        @implements gtk4::Accessible, gtk4::Orientable, gtk4::Buildable, gtk4::ConstraintTarget;
+}
+
+impl ServiceModeBox {
+    pub fn new() -> Self {
+        Object::builder().build()
+    }
+
+    //The body is already markup - anything from the catalogue must have been escaped
+    pub fn show(&self, screen: &ServiceScreen) {
+        let i = imp::ServiceModeBox::from_obj(self);
+        i.title.set_label(&format!(
+            "<span font=\"Arial Rounded MT 40\" foreground=\"orange\">Service: {}</span>",
+            glib::markup_escape_text(&screen.title)
+        ));
+        i.body.set_label(&format!("<span font=\"Monospace 18\">{}</span>", screen.body));
+        i.help.set_label(&format!(
+            "<span font=\"Arial Rounded MT 20\">{}</span>",
+            glib::markup_escape_text(screen.help)
+        ));
+    }
+}
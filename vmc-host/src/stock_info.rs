@@ -1,190 +1,203 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use vmc_icd::dispenser::{DISPENSER_COLS, DISPENSER_ROWS};
+
 use crate::DispenserAddress;
+
+//Where the catalogue is read from, unless overridden by SNACKBOT_CATALOGUE
+pub const DEFAULT_CATALOGUE_PATH: &str = "catalogue.toml";
+
 pub struct StockItem {
     pub address: DispenserAddress,
     pub name: String,
-    pub image_url: String,
+    pub image_url: Option<String>, //None shows no picture
     pub price: u16,
+    pub category: String,
+    pub description: String,
 }
 
-pub fn get_stock_item(address: DispenserAddress) -> Option<StockItem> {
-    match address {
-        DispenserAddress{row:'A',col:'0'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Scampi Fries"),
-                image_url: String::from("images/scampi.jpg"),
-                price: 90,
-            })
-        },
-        DispenserAddress{row:'A',col:'2'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Bacon Fries"),
-                image_url: String::from("images/baconfries.jpg"),
-                price: 90,
-            })
-        },
-        DispenserAddress{row:'A',col:'4'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Crinklies"),
-                image_url: String::from("images/crinklies.jpg"),
-                price: 100,
-            })
-        },        
-        DispenserAddress{row:'A',col:'6'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Monster Munch"),
-                image_url: String::from("images/monstermunch.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'B',col:'0'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Tangy Cheese Doritos"),
-                image_url: String::from("images/tangycheesedoritos.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'B',col:'2'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Chilli Doritos"),
-                image_url: String::from("images/chilliheatwavedoritos.jpg"),
-                price: 100,
-            })
-        },        
-        DispenserAddress{row:'B',col:'4'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Soba Noodles"),
-                image_url: String::from("./doritos.jpg"),
-                price: 150,
-            })
-        },        
-        DispenserAddress{row:'B',col:'6'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Super Noodles"),
-                image_url: String::from("./doritos.jpg"),
-                price: 130,
-            })
-        },
-        DispenserAddress{row:'C',col:'0'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Nature Valley Bar"),
-                image_url: String::from("./doritos.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'C',col:'1'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Crunchie"),
-                image_url: String::from("./doritos.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'C',col:'2'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Cadbury's Snack"),
-                image_url: String::from("./doritos.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'C',col:'3'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Reese's Nutrageous"),
-                image_url: String::from("./doritos.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'C',col:'4'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Reeses' Peanut Butter Cups"),
-                image_url: String::from("./doritos.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'C',col:'6'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("M&Ms"),
-                image_url: String::from("./doritos.jpg"),
-                price: 100,
-            })
-        },
-        DispenserAddress{row:'C',col:'7'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Lion Bar"),
-                image_url: String::from("./doritos.jpg"),
-                price: 100,
-            })
-        },
+#[derive(Debug)]
+pub enum CatalogueError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    InvalidAddress(String),
+    DuplicateAddress(String),
+    EmptyName(String),
+    ZeroPrice(String),
+    MissingImage(String, String),
+    Edit(toml_edit::TomlError),
+    NotListed(String),
+}
 
-        
+impl fmt::Display for CatalogueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CatalogueError::Io(e) => write!(f, "unable to read catalogue: {}", e),
+            CatalogueError::Parse(e) => write!(f, "unable to parse catalogue: {}", e),
+            CatalogueError::InvalidAddress(addr) => write!(f, "{} is not a valid dispenser address", addr),
+            CatalogueError::DuplicateAddress(addr) => write!(f, "{} is listed more than once", addr),
+            CatalogueError::EmptyName(addr) => write!(f, "{} has no name", addr),
+            CatalogueError::ZeroPrice(addr) => write!(f, "{} has no price", addr),
+            CatalogueError::MissingImage(addr, image) => write!(f, "{} image {} not found", addr, image),
+            CatalogueError::Edit(e) => write!(f, "unable to edit catalogue: {}", e),
+            CatalogueError::NotListed(addr) => write!(f, "{} is not in the catalogue", addr),
+        }
+    }
+}
 
-        DispenserAddress{row:'E',col:'1'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Cream Soda"),
-                image_url: String::from("./doritos.jpg"),
-                price: 90,
-            })
-        },
-
-        DispenserAddress{row:'E',col:'2'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Doctor Pepper"),
-                image_url: String::from("./doritos.jpg"),
-                price: 90,
-            })
-        },
-        DispenserAddress{row:'E',col:'3'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Diet Coke"),
-                image_url: String::from("./doritos.jpg"),
-                price: 90,
-            })
-        },
-
-        DispenserAddress{row:'F',col:'1'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Fanta Sugar Free"),
-                image_url: String::from("./doritos.jpg"),
-                price: 90,
-            })
-        },
-        DispenserAddress{row:'F',col:'2'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("Irn Bru Sugar Free"),
-                image_url: String::from("./doritos.jpg"),
-                price: 90,
-            })
-        },
-        DispenserAddress{row:'F',col:'3'} => {
-            Some(StockItem { 
-                address,
-                name: String::from("7UP Sugar Free"),
-                image_url: String::from("./doritos.jpg"),
-                price: 90,
-            })
-        },
+//As written in the catalogue file
+#[derive(Deserialize)]
+struct CatalogueFile {
+    #[serde(default)]
+    item: Vec<CatalogueEntry>,
+}
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CatalogueEntry {
+    address: String, //eg "A0"
+    name: String,
+    price: u16, //In pence
+    image: Option<String>,
+    #[serde(default)]
+    category: String,
+    #[serde(default)]
+    description: String,
+}
+
+//The products on sale, loaded from a TOML file so they can be changed without rebuilding.
+//The file is checked for changes (by its modification time) with reload_if_changed
+pub struct Catalogue {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    items: Vec<StockItem>,
+}
+
+impl Catalogue {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, CatalogueError> {
+        let path = path.into();
+        let modified = modified_time(&path);
+        let items = read_catalogue(&path)?;
+        Ok(Self { path, modified, items })
+    }
+
+    //Reload the catalogue if the file has been modified since it was last read. An invalid
+    //file is reported and ignored, so a mistake while editing doesn't take the machine down
+    pub fn reload_if_changed(&mut self) -> bool {
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        match read_catalogue(&self.path) {
+            Ok(items) => {
+                println!("Catalogue reloaded - {} items", items.len());
+                self.items = items;
+                true
+            }
+            Err(e) => {
+                println!("Error - {} - keeping the previous catalogue", e);
+                false
+            }
+        }
+    }
+
+    pub fn get_stock_item(&self, address: DispenserAddress) -> Option<&StockItem> {
+        self.items.iter().find(|item| item.address == address)
+    }
 
-        _ => None,
+    pub fn items(&self) -> &[StockItem] {
+        &self.items
     }
 
-}
\ No newline at end of file
+    //Change an item's price and write it back to the catalogue file. The file is edited in
+    //place, so any comments and layout in it are kept
+    pub fn set_price(&mut self, address: DispenserAddress, price: u16) -> Result<(), CatalogueError> {
+        let key = format!("{}{}", address.row, address.col);
+        if price == 0 {
+            return Err(CatalogueError::ZeroPrice(key));
+        }
+        let text = std::fs::read_to_string(&self.path).map_err(CatalogueError::Io)?;
+        let mut doc: toml_edit::DocumentMut = text.parse().map_err(CatalogueError::Edit)?;
+        let entry = doc
+            .get_mut("item")
+            .and_then(|items| items.as_array_of_tables_mut())
+            .and_then(|items| {
+                items
+                    .iter_mut()
+                    .find(|entry| entry.get("address").and_then(|a| a.as_str()).and_then(parse_address) == Some(address))
+            })
+            .ok_or(CatalogueError::NotListed(key))?;
+        entry["price"] = toml_edit::value(price as i64);
+
+        //Written to a temporary file then renamed into place, so a crash mid-write can't lose the catalogue
+        let tmp = self.path.with_extension("toml.tmp");
+        std::fs::write(&tmp, doc.to_string())
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .map_err(CatalogueError::Io)?;
+        //Re-read rather than just patching our copy, so it matches the file exactly
+        self.modified = modified_time(&self.path);
+        self.items = read_catalogue(&self.path)?;
+        Ok(())
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_catalogue(path: &Path) -> Result<Vec<StockItem>, CatalogueError> {
+    let text = std::fs::read_to_string(path).map_err(CatalogueError::Io)?;
+    let file: CatalogueFile = toml::from_str(&text).map_err(CatalogueError::Parse)?;
+    //Image paths are relative to the catalogue file
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut seen = HashSet::new();
+    let mut items = Vec::with_capacity(file.item.len());
+    for entry in file.item {
+        let address = parse_address(&entry.address).ok_or_else(|| CatalogueError::InvalidAddress(entry.address.clone()))?;
+        if !seen.insert((address.row, address.col)) {
+            return Err(CatalogueError::DuplicateAddress(entry.address));
+        }
+        if entry.name.trim().is_empty() {
+            return Err(CatalogueError::EmptyName(entry.address));
+        }
+        if entry.price == 0 {
+            return Err(CatalogueError::ZeroPrice(entry.address));
+        }
+        let image_url = match entry.image {
+            Some(image) => {
+                let image_path = base.join(&image);
+                if !image_path.is_file() {
+                    return Err(CatalogueError::MissingImage(entry.address, image));
+                }
+                Some(image_path.to_string_lossy().into_owned())
+            }
+            None => None,
+        };
+        items.push(StockItem {
+            address,
+            name: entry.name,
+            image_url,
+            price: entry.price,
+            category: entry.category,
+            description: entry.description,
+        });
+    }
+    Ok(items)
+}
+
+//Row letter then column digit, eg "A0"
+pub fn parse_address(s: &str) -> Option<DispenserAddress> {
+    let mut chars = s.trim().chars();
+    let row = chars.next()?.to_ascii_uppercase();
+    let col = chars.next()?;
+    if chars.next().is_some() || !DISPENSER_ROWS.contains(&row) || !DISPENSER_COLS.contains(&col) {
+        return None;
+    }
+    Some(DispenserAddress { row, col })
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::stock_info::parse_address;
+use crate::DispenserAddress;
+
+//Where the stock levels are kept, unless overridden by SNACKBOT_STOCK
+pub const DEFAULT_STOCK_PATH: &str = "stock.toml";
+
+//Alert once a slot is down to this many, unless the slot sets its own threshold
+pub const DEFAULT_LOW_STOCK_THRESHOLD: u16 = 2;
+
+#[derive(Debug)]
+pub enum StockError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Edit(toml_edit::TomlError),
+    InvalidAddress(String),
+    InvalidSlot(String),
+    DuplicateAddress(String),
+}
+
+impl fmt::Display for StockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StockError::Io(e) => write!(f, "unable to access stock file: {}", e),
+            StockError::Parse(e) => write!(f, "unable to parse stock file: {}", e),
+            StockError::Edit(e) => write!(f, "unable to edit stock file: {}", e),
+            StockError::InvalidAddress(addr) => write!(f, "{} is not a valid dispenser address", addr),
+            StockError::InvalidSlot(addr) => write!(f, "{} is not a table", addr),
+            StockError::DuplicateAddress(addr) => write!(f, "{} is listed more than once", addr),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SlotStock {
+    pub count: u16,
+    pub low_threshold: Option<u16>,
+}
+
+impl SlotStock {
+    pub fn is_low(&self) -> bool {
+        self.count <= self.low_threshold.unwrap_or(DEFAULT_LOW_STOCK_THRESHOLD)
+    }
+}
+
+//How many items are left in each slot, kept in a TOML file keyed by address, eg
+//  [A0]
+//  count = 8
+//  low_threshold = 3
+//Slots not listed aren't counted, so are never treated as sold out. Volunteers restock by
+//editing the file - it's re-read when it changes, as with the catalogue
+pub struct StockLevels {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    slots: BTreeMap<String, SlotStock>,
+}
+
+impl StockLevels {
+    //A missing file is an empty one - nothing is counted until it's created
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, StockError> {
+        let path = path.into();
+        let slots = if path.exists() { read_stock(&path)? } else { BTreeMap::new() };
+        let modified = modified_time(&path);
+        Ok(Self { path, modified, slots })
+    }
+
+    pub fn reload_if_changed(&mut self) -> bool {
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        match read_stock(&self.path) {
+            Ok(slots) => {
+                println!("Stock levels reloaded - {} slots counted", slots.len());
+                self.slots = slots;
+                true
+            }
+            Err(e) => {
+                println!("Error - {} - keeping the previous stock levels", e);
+                false
+            }
+        }
+    }
+
+    pub fn get(&self, address: DispenserAddress) -> Option<SlotStock> {
+        self.slots.get(&address_key(address)).copied()
+    }
+
+    pub fn is_sold_out(&self, address: DispenserAddress) -> bool {
+        self.get(address).is_some_and(|slot| slot.count == 0)
+    }
+
+    //Every counted slot at or below its low stock threshold
+    pub fn low_stock(&self) -> Vec<(String, SlotStock)> {
+        self.slots.iter().filter(|(_, slot)| slot.is_low()).map(|(key, slot)| (key.clone(), *slot)).collect()
+    }
+
+    pub fn slots(&self) -> &BTreeMap<String, SlotStock> {
+        &self.slots
+    }
+
+    //Take one item out of a counted slot after a successful vend. Returns the slot's
+    //new level if that has just taken it to (or past) its low stock threshold
+    pub fn decrement(&mut self, address: DispenserAddress) -> Option<SlotStock> {
+        //Start from any restock made since the last poll
+        self.reload_if_changed();
+        let key = address_key(address);
+        let slot = self.slots.get_mut(&key)?;
+        let was_low = slot.is_low();
+        slot.count = slot.count.saturating_sub(1);
+        let slot = *slot;
+        self.save(&key, slot.count);
+        ((slot.is_low() && !was_low) || slot.count == 0).then_some(slot)
+    }
+
+    //Set the count for a slot, starting to count it if it wasn't already
+    pub fn set_count(&mut self, address: DispenserAddress, count: u16) {
+        self.reload_if_changed();
+        let key = address_key(address);
+        self.slots
+            .entry(key.clone())
+            .and_modify(|slot| slot.count = count)
+            .or_insert(SlotStock { count, low_threshold: None });
+        self.save(&key, count);
+    }
+
+    //Only the one slot's count is changed in the file, so volunteers' comments and any other edits
+    //they've made are kept - even ones we couldn't load
+    fn save(&mut self, key: &str, count: u16) {
+        match self.write_count(key, count) {
+            //Don't reload our own write
+            Ok(()) => self.modified = modified_time(&self.path),
+            Err(e) => println!("Error - {} - {} count of {} not saved", e, key, count),
+        }
+    }
+
+    //Written to a temporary file then renamed into place, so a crash mid-write can't lose the counts
+    fn write_count(&self, key: &str, count: u16) -> Result<(), StockError> {
+        let text = if self.path.exists() {
+            std::fs::read_to_string(&self.path).map_err(StockError::Io)?
+        } else {
+            String::new()
+        };
+        let mut doc: toml_edit::DocumentMut = text.parse().map_err(StockError::Edit)?;
+        //The file may spell the address differently, eg a0
+        let existing = doc
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .find(|name| parse_address(name).map(address_key).as_deref() == Some(key));
+        let name = existing.unwrap_or_else(|| key.to_string());
+        let slot = doc.entry(&name).or_insert(toml_edit::table());
+        let slot = slot.as_table_like_mut().ok_or_else(|| StockError::InvalidSlot(name.clone()))?;
+        slot.insert("count", toml_edit::value(count as i64));
+
+        let tmp = self.path.with_extension("toml.tmp");
+        std::fs::write(&tmp, doc.to_string()).and_then(|_| std::fs::rename(&tmp, &self.path)).map_err(StockError::Io)
+    }
+}
+
+pub fn address_key(address: DispenserAddress) -> String {
+    format!("{}{}", address.row, address.col)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_stock(path: &Path) -> Result<BTreeMap<String, SlotStock>, StockError> {
+    let text = std::fs::read_to_string(path).map_err(StockError::Io)?;
+    let slots: BTreeMap<String, SlotStock> = toml::from_str(&text).map_err(StockError::Parse)?;
+    //Normalise the keys, so "a0" finds A0 - but "a0" and "A0" can't both be given
+    let mut normalised = BTreeMap::new();
+    for (key, slot) in slots {
+        let Some(address) = parse_address(&key) else {
+            return Err(StockError::InvalidAddress(key));
+        };
+        if normalised.insert(address_key(address), slot).is_some() {
+            return Err(StockError::DuplicateAddress(key));
+        }
+    }
+    Ok(normalised)
+}
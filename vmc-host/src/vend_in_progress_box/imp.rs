@@ -4,6 +4,7 @@ use gtk4::{Box, Image, Label};
 
 #[derive(Default)]
 pub struct VendInProgressBox {
+    pub progress: Label,
 }
 
 #[glib::object_subclass]
@@ -26,6 +27,8 @@ impl ObjectImpl for VendInProgressBox {
                 .label("<span font=\"Arial Rounded MT 60\">\n\nVending\n-\nPlease Wait</span>")
                 .build(),
         );
+        self.progress.set_use_markup(true);
+        self.obj().append(&self.progress);
     }
 }
 
@@ -3,6 +3,7 @@ use glib::object::Cast;
 use gtk4::glib;
 use gtk4::glib::Object;
 use gtk4::subclass::prelude::*;
+use vmc_icd::dispenser::DispensePhase;
 mod imp;
 
 glib::wrapper! {
@@ -15,4 +16,24 @@ impl VendInProgressBox {
     pub fn new() -> Self {
         Object::builder().build()
     }
+
+    //Clear the progress from any previous vend
+    pub fn reset(&self) {
+        let i = imp::VendInProgressBox::from_obj(self);
+        i.progress.set_label("");
+    }
+
+    pub fn set_phase(&self, phase: DispensePhase) {
+        let i = imp::VendInProgressBox::from_obj(self);
+        let text = match phase {
+            DispensePhase::MotorStarted => "Starting motor",
+            DispensePhase::LeftHome => "Dispensing",
+            DispensePhase::ReturnedHome => "Dispensed",
+            DispensePhase::TimedOut => "Motor stuck",
+        };
+        i.progress.set_label(&format!(
+            "<span font=\"Arial Rounded MT 40\">{}</span>",
+            text
+        ));
+    }
 }
@@ -4,9 +4,20 @@ use postcard_rpc::{
     standard_icd::{PingEndpoint, WireError, ERROR_PATH},
 };
 
-use vmc_icd::{cashless_device::CashlessDeviceCommand, dispenser::{ DispenseCommand, DispenseError, Dispenser, DispenserAddress}, CashlessDeviceCmdEndpoint, DispenserStatusEndpoint };//; SetCoinAcceptorEnabled};
-use vmc_icd::{CoinAcceptorEnableEndpoint,DispenseEndpoint};
+use vmc_icd::{cashless_device::CashlessDeviceCommand, dispenser::{ DispenseCommand, DispenseError, DispenseProgress, Dispenser, DispenserAddress}, CashlessDeviceCmdEndpoint, DispenserMapEndpoint, DispenserStatusEndpoint };//; SetCoinAcceptorEnabled};
+use vmc_icd::{CoinAcceptorEnableEndpoint,CoinAcceptorInfoEndpoint,CoinAcceptorPayoutEndpoint,DispenseEndpoint};
+use vmc_icd::{ChillerInfoEndpoint, ChillerSetpointEndpoint};
+use vmc_icd::chiller::ChillerInfo;
+use vmc_icd::{icd_fingerprint, FirmwareInfoEndpoint};
+use vmc_icd::firmware::check_firmware;
+use vmc_icd::BillValidatorCmdEndpoint;
+use vmc_icd::{MachineLayoutEndpoint, SetMachineLayoutEndpoint};
+use vmc_icd::layout::{LayoutError, MachineLayout};
+use vmc_icd::{ConfigEndpoint, SetConfigEndpoint};
+use vmc_icd::config::{ConfigError, VmcConfig};
+use vmc_icd::bill_validator::{BillInserted, BillValidatorCommand, BillValidatorEvent};
 use std::convert::Infallible;
+use serde::Serialize;
 
 #[derive(Debug)]
 pub enum VmcClientError<E> {
@@ -20,7 +31,7 @@ impl<E> From<HostErr<WireError>> for VmcClientError<E> {
     }
 }
 
-use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinInserted, CoinRouting,};
+use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinAcceptorInfo, CoinInserted, CoinRouting,};
 
 use vmc_icd::cashless_device::{CashlessDeviceCommand::*,CashlessDeviceEvent};
 
@@ -32,9 +43,18 @@ pub enum VmcCommand {
     GetDispenser(char,char),            //Get information about a specific dispenser
     SetCoinAcceptorEnabled(bool),   //Whether the coin acceptor should accept coins
     RefundCoins(u16),               //Refund amount
-    CashlessCmd(CashlessDeviceCommand) //
+    GetCoinAcceptorInfo,            //Get coin types and tube levels
+    CashlessCmd(CashlessDeviceCommand), //
+    BillValidatorCmd(BillValidatorCommand), //Enable/disable and escrow commands
+    GetChillerInfo,                 //Get the current chiller temperature and status
+    SetChillerSetpoint(i8),         //Change the chiller target temperature ('C)
+    GetMachineLayout,               //Get the fitted slots and row types
+    SetMachineLayout(MachineLayout), //Change (and store) the layout - machine is remapped afterwards
+    GetConfig,                      //Get the VMC firmware configuration
+    SetConfig(VmcConfig),           //Change (and store) the VMC firmware configuration
 }
 
+#[derive(Serialize, Debug)]
 pub enum VmcResponse {
     MachineMap(Vec<Dispenser>),
     Dispenser(Dispenser),
@@ -42,8 +62,16 @@ pub enum VmcResponse {
     CoinAcceptorEvent(CoinAcceptorEvent),
     CoinInsertedEvent(CoinInserted),
     CashlessEvent(CashlessDeviceEvent),
+    BillInsertedEvent(BillInserted),
+    BillValidatorEvent(BillValidatorEvent),
+    DispenseProgress(DispenseProgress),
     DispenseSuccessEvent,
-    DispenseFailedEvent,
+    DispenseFailedEvent(DispenseError),
+    CoinsRefunded(u16, u16),        //Amount requested, amount actually paid out
+    CoinAcceptorInfo(Option<CoinAcceptorInfo>),
+    ChillerInfo(ChillerInfo),
+    MachineLayout(MachineLayout),
+    Config(VmcConfig),
 }
 
 pub struct VmcDriver {
@@ -51,16 +79,44 @@ pub struct VmcDriver {
 }
 
 impl VmcDriver {
-    pub fn new() -> Result<Self, String> {
-        match HostClient::try_new_raw_nusb(
+    pub async fn new() -> Result<Self, String> {
+        let driver = HostClient::try_new_raw_nusb(
             |c| c.product_string() == Some("vmc"),
             ERROR_PATH,
             8,
             VarSeqKind::Seq2,
-        ) {
-            Ok(driver) => Ok(Self { driver }),
-            Err(x) => Err(x),
+        )?;
+        Self::from_client(driver).await
+    }
+
+    //Connects to the process-wide emulated VMC rather than the real hardware
+    #[cfg(feature = "emulator")]
+    pub async fn new_emulated() -> Result<Self, String> {
+        Self::from_client(vmc_emulator::shared().connect()).await
+    }
+
+    //Wraps an already connected client, eg one from a vmc_emulator::Emulator
+    pub async fn from_client(driver: HostClient<WireError>) -> Result<Self, String> {
+        let mut vmc = Self { driver };
+        if let Err(e) = vmc.check_firmware().await {
+            vmc.driver.close();
+            return Err(e);
         }
+        Ok(vmc)
+    }
+
+    //Refuse firmware built against a different vmc-icd - its endpoints and topics
+    //would otherwise fail later in confusing ways
+    async fn check_firmware(&mut self) -> Result<(), String> {
+        let info = self
+            .driver
+            .send_resp::<FirmwareInfoEndpoint>(&())
+            .await
+            .map_err(|e| format!("VMC firmware did not report its version ({:?}) - it is too old and needs reflashing", e))?;
+
+        check_firmware(info, icd_fingerprint()).map_err(|e| format!("VMC {} - reflash the VMC or rebuild the host", e))?;
+        println!("VMC firmware v{}.{}.{}", info.version_major, info.version_minor, info.version_patch);
+        Ok(())
     }
 
     pub async fn dispense(&mut self, addr: DispenserAddress) -> Result<(), DispenseError>{
@@ -75,7 +131,7 @@ impl VmcDriver {
     }
 
     pub async fn force_dispense(&mut self, addr: DispenserAddress) -> Result<(), DispenseError>{
-        match self.driver.send_resp::<DispenseEndpoint>(&DispenseCommand::Vend(addr)).await {
+        match self.driver.send_resp::<DispenseEndpoint>(&DispenseCommand::ForceVend(addr)).await {
             Ok(res) => {
                 res
             }
@@ -85,15 +141,26 @@ impl VmcDriver {
         }
     }
 
-    pub async fn map_machine(&mut self) -> Vec<Dispenser> { 
-        let dispensers:Vec<Dispenser> = Vec::new();
-        //For all possible machine addresses, see if there is a dispenser present
-        for r in [ 'A', 'B', 'C', 'D', 'E', 'F','G' ] {
-            for c in ['0','1','2','3','4','5','6','7','8','9'] {
-                let disp = self.driver.send_resp::<DispenserStatusEndpoint>(&DispenserAddress{row:r, col:c}).await;
-            }
-        }
-        dispensers
+    //Returns every dispenser fitted to the machine
+    pub async fn map_machine(&mut self) -> Result<Vec<Dispenser>, VmcClientError<Infallible>> {
+        let map = self.driver.send_resp::<DispenserMapEndpoint>(&()).await?;
+        Ok(map.into_iter().flatten().flatten().collect())
+    }
+
+    //Returns None if there is no dispenser fitted at this address
+    pub async fn get_dispenser(&mut self, addr: DispenserAddress) -> Result<Option<Dispenser>, VmcClientError<Infallible>> {
+        let dispenser = self.driver.send_resp::<DispenserStatusEndpoint>(&addr).await?;
+        Ok(dispenser)
+    }
+
+    pub async fn get_machine_layout(&mut self) -> Result<MachineLayout, VmcClientError<Infallible>> {
+        let layout = self.driver.send_resp::<MachineLayoutEndpoint>(&()).await?;
+        Ok(layout)
+    }
+
+    //Fails with VmcClientError::Endpoint if the layout is invalid, or couldn't be stored
+    pub async fn set_machine_layout(&mut self, layout: MachineLayout) -> Result<(), VmcClientError<LayoutError>> {
+        self.driver.send_resp::<SetMachineLayoutEndpoint>(&layout).await?.map_err(VmcClientError::Endpoint)
     }
 
     //Sets whether the coin acceptor should accept coins or not
@@ -102,9 +169,42 @@ impl VmcDriver {
         Ok(())
     }
 
-    pub async fn dispense_coins(&mut self, value: u16) -> Result<(u16), VmcClientError<Infallible>> {
-      //  let amount_refunded = self.driver.send_resp::<DispenseCoins>(&value).await?;
-        Ok(10)
+    //Returns None if the VMC has no coin acceptor initialised
+    pub async fn get_coin_acceptor_info(&mut self) -> Result<Option<CoinAcceptorInfo>, VmcClientError<Infallible>> {
+        let info = self.driver.send_resp::<CoinAcceptorInfoEndpoint>(&()).await?;
+        Ok(info)
+    }
+
+    //Pays out coins from the coin acceptor tubes - returns the value actually paid out,
+    //which may be less than requested if the tubes run low
+    pub async fn dispense_coins(&mut self, value: u16) -> Result<u16, VmcClientError<Infallible>> {
+        let amount_refunded = self.driver.send_resp::<CoinAcceptorPayoutEndpoint>(&value).await?;
+        Ok(amount_refunded)
+    }
+
+    pub async fn get_chiller_info(&mut self) -> Result<ChillerInfo, VmcClientError<Infallible>> {
+        let info = self.driver.send_resp::<ChillerInfoEndpoint>(&()).await?;
+        Ok(info)
+    }
+
+    //Fails with VmcClientError::Endpoint if the VMC rejects the setpoint as out of range
+    pub async fn set_chiller_setpoint(&mut self, setpoint: i8) -> Result<(), VmcClientError<()>> {
+        self.driver.send_resp::<ChillerSetpointEndpoint>(&setpoint).await?.map_err(VmcClientError::Endpoint)
+    }
+
+    pub async fn get_config(&mut self) -> Result<VmcConfig, VmcClientError<Infallible>> {
+        let config = self.driver.send_resp::<ConfigEndpoint>(&()).await?;
+        Ok(config)
+    }
+
+    //Fails with VmcClientError::Endpoint if the config is invalid, or couldn't be stored
+    pub async fn set_config(&mut self, config: VmcConfig) -> Result<(), VmcClientError<ConfigError>> {
+        self.driver.send_resp::<SetConfigEndpoint>(&config).await?.map_err(VmcClientError::Endpoint)
+    }
+
+    pub async fn send_bill_validator_command(&mut self, cmd: BillValidatorCommand) -> Result<(), VmcClientError<Infallible>> {
+        self.driver.send_resp::<BillValidatorCmdEndpoint>(&cmd).await?;
+        Ok(())
     }
 
     pub async fn send_cashless_device_command(&mut self, cmd: CashlessDeviceCommand) -> Result<(),VmcClientError<Infallible>> {
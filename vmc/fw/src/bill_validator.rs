@@ -0,0 +1,141 @@
+use defmt::*;
+
+use embassy_rp::usb::Driver as UsbDriver;
+use embassy_time::Timer;
+
+use postcard_rpc::server::{impls::embassy_usb_v0_4::EUsbWireTx, Sender};
+
+use embassy_rp::peripherals::USB;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+
+use mdb_async::bill_validator::{BillValidator, PollEvent};
+use mdb_async::bill_validator::BillRouting as MdbBillRouting;
+
+use vmc_icd::bill_validator::{BillInserted, BillRouting, BillValidatorCommand, BillValidatorEvent};
+use vmc_icd::{BillInsertedTopic, BillValidatorEventTopic};
+
+use postcard_rpc::header::VarHeader;
+
+use crate::Context;
+use crate::MDB_DRIVER;
+use crate::config::config;
+
+static BILL_VALIDATOR_COMMAND_CHANNEL: Channel<ThreadModeRawMutex, BillValidatorCommand, 2> =
+    Channel::new();
+
+
+//Task will:
+//Init the bill validator, or keep retrying at the configured MDB init retry interval
+//Poll the bill validator at the configured MDB poll interval
+//If it fails to repond to a poll, it will get reinitialised
+#[embassy_executor::task]
+pub async fn bill_validator_task(
+    postcard_sender: Sender<EUsbWireTx<ThreadModeRawMutex, UsbDriver<'static, USB>>>,
+) -> ! {
+    loop {
+        let v = {
+            let mut b = MDB_DRIVER.lock().await;
+            let bus = b.as_mut().expect("MDB driver not present");
+            BillValidator::init(bus).await
+        };
+        match v {
+            Some(mut validator) => 'poll_loop: loop {
+                let events = {
+                    let mut b = MDB_DRIVER.lock().await;
+                    let bus = b.as_mut().expect("MDB driver not present");
+                    validator.poll(bus).await
+                };
+                match events {
+                    Ok(events) => {
+                        billvalidator_process_poll_events(events, &validator, &postcard_sender).await;
+                        Timer::after_millis(config().await.mdb_poll_interval_ms as u64).await;
+                    }
+                    Err(()) => {
+                        error!("Bill validator failed to reply to poll - will try to reinitialise");
+                        break 'poll_loop;
+                    }
+                }
+                //Handle any pending commands
+                while let Ok(cmd) = BILL_VALIDATOR_COMMAND_CHANNEL.try_receive() {
+                    let mut b = MDB_DRIVER.lock().await;
+                    let bus = b.as_mut().expect("MDB driver not present");
+                    match cmd {
+                        BillValidatorCommand::Enable => {
+                            debug!("Sending bill validator enable command");
+                            //Enable all bill types, all of which may be held in escrow
+                            let _ = validator.enable_bills(bus, 0xFFFFu16, 0xFFFFu16).await;
+                        }
+                        BillValidatorCommand::Disable => {
+                            debug!("Sending bill validator disable command");
+                            let _ = validator.enable_bills(bus, 0x0000u16, 0x0000u16).await;
+                        }
+                        BillValidatorCommand::AcceptEscrow => {
+                            debug!("Stacking escrowed bill");
+                            let _ = validator.escrow(bus, true).await;
+                        }
+                        BillValidatorCommand::RejectEscrow => {
+                            debug!("Returning escrowed bill");
+                            let _ = validator.escrow(bus, false).await;
+                        }
+                    }
+                }
+            },
+            None => {
+                info!("Bill validator not initialised");
+                Timer::after_millis(config().await.mdb_init_retry_interval_ms as u64).await;
+            }
+        }
+    }
+}
+
+//Process the potential list of poll events, and send these as event via postcard-rpc
+pub async fn billvalidator_process_poll_events(
+    events: [Option<PollEvent>; 16],
+    validator: &BillValidator,
+    postcard_sender: &Sender<EUsbWireTx<ThreadModeRawMutex, UsbDriver<'static, USB>>>,
+) {
+    let mut seq = 0x0000u16;
+    for event in events.iter().flatten() {
+        match event {
+            PollEvent::Status(byte) => {
+                let _ = postcard_sender
+                    .publish::<BillValidatorEventTopic>(seq.into(), &BillValidatorEvent::from(*byte))
+                    .await;
+                seq += 1;
+            }
+            PollEvent::Bill(x) => {
+                let routing = match x.routing {
+                    MdbBillRouting::Stacked => BillRouting::Stacked,
+                    MdbBillRouting::EscrowPosition => BillRouting::Escrow,
+                    MdbBillRouting::ReturnedToCustomer => BillRouting::Returned,
+                    MdbBillRouting::DisabledBillRejected => BillRouting::Rejected,
+                    _ => BillRouting::Unknown,
+                };
+                let value = bill_value(validator, x.bill_type as usize);
+                info!("Bill inserted - value: {}, routing: {}", value, routing as u8);
+                let _ = postcard_sender
+                    .publish::<BillInsertedTopic>(seq.into(), &BillInserted { value, routing })
+                    .await;
+                seq += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+//Value of a bill type in the validator's scaled units (ie pence), or zero if not a valid bill type
+fn bill_value(validator: &BillValidator, bill_type: usize) -> u16 {
+    match validator.bill_types.get(bill_type).copied().flatten() {
+        Some(bill) => bill.unscaled_value * validator.scaling_factor,
+        None => 0,
+    }
+}
+
+pub async fn bill_validator_cmd_handler(
+    _context: &mut Context,
+    _header: VarHeader,
+    cmd: BillValidatorCommand,
+) {
+    BILL_VALIDATOR_COMMAND_CHANNEL.send(cmd).await;
+}
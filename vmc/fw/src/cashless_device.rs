@@ -1,7 +1,7 @@
 use defmt::*;
 
 use embassy_rp::usb::Driver as UsbDriver;
-use embassy_time::{Duration, Timer};
+use embassy_time::Timer;
 
 use postcard_rpc::server::{impls::embassy_usb_v0_4::EUsbWireTx, Sender};
 
@@ -18,16 +18,15 @@ use postcard_rpc::header::VarHeader;
 
 use crate::Context;
 use crate::MDB_DRIVER;
+use crate::config::config;
 
-const CASHLESS_DEVICE_INIT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
-const CASHLESS_DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 static CASHLESS_COMMAND_CHANNEL: Channel<ThreadModeRawMutex, CashlessDeviceCommand, 2> =
     Channel::new();
 
 //Task will:
-//Init the cashless device, or keep retrying every ten seconds
-//Poll the cashless device every 100mS
+//Init the cashless device, or keep retrying at the configured MDB init retry interval
+//Poll the cashless device at the configured MDB poll interval
 //If it fails to repond to a poll, it will get reinitialised
 #[embassy_executor::task]
 pub async fn cashless_device_task(
@@ -168,11 +167,11 @@ pub async fn cashless_device_task(
                         }
                     }
                 }
-                Timer::after(CASHLESS_DEVICE_POLL_INTERVAL).await;
+                Timer::after_millis(config().await.mdb_poll_interval_ms as u64).await;
             }
             None => {
                 info!("Cashless device not found");
-                Timer::after(CASHLESS_DEVICE_INIT_RETRY_INTERVAL).await;
+                Timer::after_millis(config().await.mdb_init_retry_interval_ms as u64).await;
                 //loop will now try to reinitialise the device again
             }
         }
@@ -1,22 +1,30 @@
 use embassy_rp::adc::{Adc, Async};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Timer, WithTimeout};
 use embassy_rp::adc;
 
 use embassy_rp::gpio::{Level, Output};
 
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+
 use defmt::*;
 use libm::{log, pow};
 
-use crate::DISPENSER_DRIVER;
+use postcard_rpc::header::VarHeader;
+use postcard_rpc::server::Sender;
+
+use vmc_icd::chiller::{ChillerInfo, ChillerSetpointResult, MAX_CHILLER_SETPOINT, MIN_CHILLER_SETPOINT};
+use vmc_icd::config::VmcConfig;
+use vmc_icd::ChillerInfoTopic;
+
+use crate::config::{config, update_config};
+use crate::{AppTx, Context, DISPENSER_DRIVER};
 
-const DEFAULT_TEMPERATURE_SETPOINT:f32 = 6.0;
+//The setpoint, measurement interval and minimum cycle count come from the VMC config
 
 const NUM_MEASUREMENTS_TO_AVERAGE:usize = 10;
 const MEASUREMENT_DELAY:Duration = Duration::from_millis(10);
-const TEMPERATURE_MEASURE_INTERVAL:Duration = Duration::from_secs(60);
-const CHILLER_MIN_CYCLE_COUNT: u8 = 5;  //This is a multiple of the measurement interval - 
-                                        //the chiller will only be turned on or off this many measurement cycle
-                                        //to prevent burnout
 
 const THERMISTOR_PULLUP_VAL_OHMS:u64 = 10000;
 const MIN_TEMP:f64 = -10.0;
@@ -28,20 +36,39 @@ const THERMISTOR_A_VAL:f64 = 1.3811057615602958e-3;
 const THERMISTOR_B_VAL:f64 = 2.370102475713365e-4;
 const THERMISTOR_C_VAL:f64 = 9.879312896211082e-8;
 
+//Number of measurement cycles the duty cycle is calculated over (one bit per cycle)
+const DUTY_CYCLE_WINDOW:u32 = 64;
+
+//Wakes the chiller task early when the config changes, so a new setpoint is picked up promptly
+static CHILLER_CONFIG_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+//Most recent chiller status, as returned by the ChillerInfoEndpoint
+static CHILLER_INFO: Mutex<CriticalSectionRawMutex, ChillerInfo> = Mutex::new(ChillerInfo {
+    target_temp: VmcConfig::DEFAULT.chiller_setpoint,
+    current_temp: 0,
+    duty_cycle: 0,
+    compressor_status: false,
+});
 
 #[embassy_executor::task]
 pub async fn chiller_task(
     mut adc: Adc<'static, Async>,
     mut channel: adc::Channel<'static>,
     mut led_pin: Output<'static>,
+    postcard_sender: Sender<AppTx>,
 ) -> ! {
     let mut measurements = [0u16; NUM_MEASUREMENTS_TO_AVERAGE];
-    //Fixme - add channel to allow setpoint to be changed
-    let setpoint:f32 = DEFAULT_TEMPERATURE_SETPOINT;
+    let mut chiller_config = config().await;
+    let mut setpoint:f32 = chiller_config.chiller_setpoint as f32;
 
-    let mut chiller_change_cycle_count = CHILLER_MIN_CYCLE_COUNT; //this forces initial compute
+    let mut chiller_change_cycle_count = chiller_config.chiller_min_cycle_count; //this forces initial compute
     let mut chiller_current_state = false;
 
+    //Compressor state history for the duty cycle calculation - bit set if on during that cycle
+    let mut duty_cycle_history = 0u64;
+    let mut duty_cycle_samples = 0u32;
+    let mut seq = 0x0000u16;
+
     loop {
         //Take specified number of measurements and average them.c
         for val in measurements.iter_mut() {
@@ -62,9 +89,9 @@ pub async fn chiller_task(
                 }
                 else {
                     debug!("Thermistor resistor value (averaged): {}, temperature calculated as {}'C", res_val, temp);
-                    //We only turn on/off the chiller every MIN_CYCLE_COUNT poll intervals as it won't like
+                    //We only turn on/off the chiller every chiller_min_cycle_count poll intervals as it won't like
                     //being repeatedly turned on/off.
-                    if chiller_change_cycle_count == CHILLER_MIN_CYCLE_COUNT {
+                    if chiller_change_cycle_count >= chiller_config.chiller_min_cycle_count {
                         let chiller_new_state=  temp as f32 > setpoint + 0.5;
                         if chiller_new_state != chiller_current_state {
                             //If the desired chiler state has changed, apply it
@@ -86,16 +113,63 @@ pub async fn chiller_task(
                     }
                     info!("Drinks chiller temperature: {}'C, target {}'C, chiller_on: {}", temp, setpoint, chiller_current_state);
                 }
+
+                duty_cycle_history = (duty_cycle_history << 1) | chiller_current_state as u64;
+                duty_cycle_samples = (duty_cycle_samples + 1).min(DUTY_CYCLE_WINDOW);
+
+                let chiller_info = ChillerInfo {
+                    target_temp: setpoint as i8,
+                    current_temp: temp as i8,
+                    duty_cycle: (duty_cycle_history.count_ones() * 100 / duty_cycle_samples) as u8,
+                    compressor_status: chiller_current_state,
+                };
+                *CHILLER_INFO.lock().await = chiller_info;
+                let _ = postcard_sender
+                    .publish::<ChillerInfoTopic>(seq.into(), &chiller_info)
+                    .await;
+                seq = seq.wrapping_add(1);
             },
             Err(_e) => {
                 error!("Steinhart-Hart temperature calculation error");
             }
         }
-        //Wait specified period prior to checking again.
-        Timer::after(TEMPERATURE_MEASURE_INTERVAL).await;
+        //Wait specified period prior to checking again - a config change cuts the wait short,
+        //but a new setpoint is only acted on at the next permitted chiller on/off change
+        let interval = Duration::from_millis(chiller_config.chiller_measure_interval_ms as u64);
+        if CHILLER_CONFIG_CHANGED.wait().with_timeout(interval).await.is_ok() {
+            chiller_config = config().await;
+            if chiller_config.chiller_setpoint as f32 != setpoint {
+                info!("Chiller setpoint changed to {}'C", chiller_config.chiller_setpoint);
+                setpoint = chiller_config.chiller_setpoint as f32;
+                CHILLER_INFO.lock().await.target_temp = chiller_config.chiller_setpoint;
+            }
+        }
     }
 }
 
+pub async fn chiller_info(_context: &mut Context, _header: VarHeader, _rqst: ()) -> ChillerInfo {
+    *CHILLER_INFO.lock().await
+}
+
+pub async fn chiller_set_setpoint(
+    _context: &mut Context,
+    _header: VarHeader,
+    setpoint: i8,
+) -> ChillerSetpointResult {
+    if !(MIN_CHILLER_SETPOINT..=MAX_CHILLER_SETPOINT).contains(&setpoint) {
+        error!("Refusing chiller setpoint of {}'C - outside {} to {}'C", setpoint, MIN_CHILLER_SETPOINT, MAX_CHILLER_SETPOINT);
+        return Err(());
+    }
+    //Setpoint is part of the config, so is stored and survives a reboot
+    let mut new_config = config().await;
+    new_config.chiller_setpoint = setpoint;
+    update_config(new_config).await.map_err(|_| ())
+}
+
+pub fn notify_chiller_config_changed() {
+    CHILLER_CONFIG_CHANGED.signal(());
+}
+
 //From: https://pico.implrust.com/thermistor/steinhart.html
 fn steinhart_temp_calc(
     resistance: f64, // Resistance in Ohms
@@ -1,42 +1,60 @@
 use defmt::*;
 
+use core::cmp::Reverse;
+
 use embassy_rp::usb::Driver as UsbDriver;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Timer, WithTimeout};
 
 use postcard_rpc::server::{
     impls::embassy_usb_v0_4::EUsbWireTx,
     Sender};
 
 use embassy_rp::peripherals::USB;
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
 use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
 
-use mdb_async::coin_acceptor::{CoinAcceptor, PollEvent};
-use vmc_icd::EventTopic;
+use mdb_async::coin_acceptor::{CoinAcceptor, PollEvent, TubeStatus};
+use mdb_async::coin_acceptor::CoinRouting as MdbCoinRouting;
+use vmc_icd::{CoinAcceptorPayoutEndpoint, EventTopic};
 
 use vmc_icd::CoinInsertedTopic;
 
-use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinInserted, CoinRouting};
+use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinAcceptorInfo, CoinAcceptorInfoOption, CoinInserted, CoinRouting};
 
 use postcard_rpc::header::VarHeader;
 
 use crate::MDB_DRIVER;
-use crate::Context;
+use crate::config::config;
+use crate::{AppTx, Context, SpawnCtx};
 
 static TASK_COMMAND_CHANNEL: Channel<ThreadModeRawMutex, CoinAcceptorDriverCommand, 2> =
     Channel::new();
 
-const COIN_ACCEPTOR_INIT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
-const COIN_ACCEPTOR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+//The value actually paid out is returned from the poll task via this signal
+static PAYOUT_RESULT: Signal<ThreadModeRawMutex, u16> = Signal::new();
+
+//Snapshot of the acceptor setup and tube levels, refreshed by the poll task whenever the tubes change
+static COIN_ACCEPTOR_INFO: Mutex<CriticalSectionRawMutex, CoinAcceptorInfoOption> = Mutex::new(None);
+
+
+//MDB allows a maximum of 15 coins of a type to be requested in a single dispense command
+const MAX_COINS_PER_DISPENSE: u16 = 15;
+//Allowance for each coin to drop out of the tube before the tube levels are re-read
+const COIN_PAYOUT_TIME_PER_COIN: Duration = Duration::from_millis(500);
+//Allowance for the MDB traffic and tube level reads around a payout
+const PAYOUT_REPLY_MARGIN: Duration = Duration::from_secs(5);
 
 pub enum CoinAcceptorDriverCommand {
     Enable,
     Disable,
+    Payout(u16),
 }
 
 //Task will:
-//Init the coin acceptor, or keep retrying every ten seconds
-//Poll the coin acceptor every 100mS
+//Init the coin acceptor, or keep retrying at the configured MDB init retry interval
+//Poll the coin acceptor at the configured MDB poll interval
 //If it fails to repond to a poll, it will get reinitialised
 #[embassy_executor::task]
 pub async fn coin_acceptor_task(
@@ -50,6 +68,9 @@ pub async fn coin_acceptor_task(
         };
         match a {
             Some(mut acceptor) => 'poll_loop: loop {
+                if COIN_ACCEPTOR_INFO.lock().await.is_none() {
+                    coinacceptor_update_info(&mut acceptor).await;
+                }
                 let events = {
                     let mut b = MDB_DRIVER.lock().await;
                     let bus = b.as_mut().expect("MDB driver not present");
@@ -57,29 +78,37 @@ pub async fn coin_acceptor_task(
                 };
                 match events {
                     Ok(events) => {
-                        coinacceptor_process_poll_events(events, &postcard_sender).await;
-                        Timer::after(COIN_ACCEPTOR_POLL_INTERVAL).await;
+                        if coinacceptor_process_poll_events(events, &acceptor, &postcard_sender).await {
+                            coinacceptor_update_info(&mut acceptor).await;
+                        }
+                        Timer::after_millis(config().await.mdb_poll_interval_ms as u64).await;
                     }
                     Err(()) => {
                         error!("Coinacceptor failed to reply to poll - will try to reinitialise");
+                        *COIN_ACCEPTOR_INFO.lock().await = None;
                         break 'poll_loop;
                     }
                 }
                 //Handle any incoming requests and send those messages to the coin acceptor.
                 match TASK_COMMAND_CHANNEL.try_receive() {
-                    Ok(msg) => {
+                    Ok(CoinAcceptorDriverCommand::Enable) => {
                         let mut b = MDB_DRIVER.lock().await;
                         let bus = b.as_mut().expect("MDB driver not present");
-                        match msg {
-                            CoinAcceptorDriverCommand::Enable => {
-                                debug!("Sending coin acceptor enable command");
-                                let _ = acceptor.enable_coins(bus, 0xFFFFu16).await;
-                            }
-                            CoinAcceptorDriverCommand::Disable => {
-                                debug!("Sending coin acceptor disable command");
-                                let _ = acceptor.enable_coins(bus, 0x00u16).await;
-                            }
-                        }
+                        debug!("Sending coin acceptor enable command");
+                        let _ = acceptor.enable_coins(bus, 0xFFFFu16).await;
+                    }
+                    Ok(CoinAcceptorDriverCommand::Disable) => {
+                        let mut b = MDB_DRIVER.lock().await;
+                        let bus = b.as_mut().expect("MDB driver not present");
+                        debug!("Sending coin acceptor disable command");
+                        let _ = acceptor.enable_coins(bus, 0x00u16).await;
+                    }
+                    Ok(CoinAcceptorDriverCommand::Payout(amount)) => {
+                        //Payout manages its' own bus locking, as it has to wait for coins to drop
+                        debug!("Paying out {}", amount);
+                        let paid = coinacceptor_payout(&mut acceptor, amount).await;
+                        PAYOUT_RESULT.signal(paid);
+                        coinacceptor_update_info(&mut acceptor).await;
                     }
                     Err(_e) => {
                     //    error!("Task Command Channel rx error");
@@ -88,17 +117,27 @@ pub async fn coin_acceptor_task(
             },
             None => {
                 info!("Coin acceptor not initialised");
-                Timer::after(COIN_ACCEPTOR_INIT_RETRY_INTERVAL).await;
+                //Nothing can be paid out without an acceptor - answer (and discard) any pending payout requests
+                //so they don't get actioned unexpectedly once it comes back
+                while let Ok(msg) = TASK_COMMAND_CHANNEL.try_receive() {
+                    if let CoinAcceptorDriverCommand::Payout(_) = msg {
+                        PAYOUT_RESULT.signal(0);
+                    }
+                }
+                Timer::after_millis(config().await.mdb_init_retry_interval_ms as u64).await;
             }
         }
     }
 }
 
 //Process the potential list of poll events, and send these as event via postcard-rpc
+//Returns true if any coins were routed to the tubes, so the tube levels have changed
 pub async fn coinacceptor_process_poll_events(
     events: [Option<PollEvent>; 16],
+    acceptor: &CoinAcceptor,
     postcard_sender: &Sender<EUsbWireTx<ThreadModeRawMutex, UsbDriver<'static, USB>>>,
-) {
+) -> bool {
+    let mut tubes_changed = false;
     let mut seq = 0x0000u16;
     for e in events.iter() {
         match e {
@@ -111,10 +150,18 @@ pub async fn coinacceptor_process_poll_events(
                         seq += 1;
                     }
                     PollEvent::Coin(x) => {
-                        info!("Coin inserted - unscaled value: {}", x.unscaled_value);
+                        let routing = match x.routing {
+                            MdbCoinRouting::CashBox => CoinRouting::CashBox,
+                            MdbCoinRouting::Tubes => CoinRouting::Tube,
+                            MdbCoinRouting::Reject => CoinRouting::Reject,
+                            _ => CoinRouting::Unknown,
+                        };
+                        let value = x.unscaled_value * acceptor.scaling_factor as u16;
+                        info!("Coin inserted - value: {}, routing: {}", value, routing as u8);
+                        tubes_changed |= routing == CoinRouting::Tube;
                         let coinevent = CoinInserted {
-                            value: x.unscaled_value,
-                            routing: CoinRouting::CashBox, //fixme!
+                            value,
+                            routing,
                         };
                         let _ = postcard_sender
                             .publish::<CoinInsertedTopic>(seq.into(), &coinevent)
@@ -127,6 +174,136 @@ pub async fn coinacceptor_process_poll_events(
             _ => {}
         }
     }
+    tubes_changed
+}
+
+//Value of a coin type in the acceptor's scaled units (ie pence), or zero if not a valid coin type
+fn coin_value(acceptor: &CoinAcceptor, coin_type: usize) -> u16 {
+    match acceptor.coin_types[coin_type] {
+        Some(coin) => coin.unscaled_value * acceptor.scaling_factor as u16,
+        None => 0,
+    }
+}
+
+async fn coinacceptor_tube_status(acceptor: &mut CoinAcceptor) -> Option<TubeStatus> {
+    let mut b = MDB_DRIVER.lock().await;
+    let bus = b.as_mut().expect("MDB driver not present");
+    match acceptor.tube_status(bus).await {
+        Ok(status) => Some(status),
+        Err(()) => {
+            error!("Coin acceptor failed to report tube status");
+            None
+        }
+    }
+}
+
+//Refresh the info snapshot returned by the CoinAcceptorInfoEndpoint
+async fn coinacceptor_update_info(acceptor: &mut CoinAcceptor) {
+    let tube_status = coinacceptor_tube_status(acceptor).await;
+    let info = CoinAcceptorInfo {
+        currency_code: acceptor.country_code,
+        scaling_factor: acceptor.scaling_factor,
+        decimal_places: acceptor.decimal_places,
+        coin_values: core::array::from_fn(|i| coin_value(acceptor, i)),
+        tube_levels: tube_status.as_ref().map(|t| t.tube_status).unwrap_or([0; 16]),
+        tube_full: tube_status.as_ref().map(|t| t.tube_full_status).unwrap_or([false; 16]),
+    };
+    *COIN_ACCEPTOR_INFO.lock().await = Some(info);
+}
+
+//Pay out up to the requested amount from the coin tubes, largest coins first.
+//Returns the value actually paid, measured by the change in the tube levels
+async fn coinacceptor_payout(acceptor: &mut CoinAcceptor, amount: u16) -> u16 {
+    let Some(levels_before) = coinacceptor_tube_status(acceptor).await.map(|t| t.tube_status) else {
+        return 0;
+    };
+
+    let mut coin_types: [usize; 16] = core::array::from_fn(|i| i);
+    coin_types.sort_unstable_by_key(|&i| Reverse(coin_value(acceptor, i)));
+
+    let mut remaining = amount;
+    let mut levels = levels_before;
+    for coin_type in coin_types {
+        let value = coin_value(acceptor, coin_type);
+        if value == 0 {
+            continue;
+        }
+        while remaining >= value && levels[coin_type] > 0 {
+            let count = (remaining / value)
+                .min(levels[coin_type] as u16)
+                .min(MAX_COINS_PER_DISPENSE);
+            debug!("Dispensing {} coins of type {} (value {})", count, coin_type, value);
+            let result = {
+                let mut b = MDB_DRIVER.lock().await;
+                let bus = b.as_mut().expect("MDB driver not present");
+                acceptor.dispense(bus, coin_type as u8, count as u8).await
+            };
+            if result.is_err() {
+                error!("Coin acceptor rejected dispense command");
+                break;
+            }
+            remaining -= count * value;
+            levels[coin_type] -= count as u8;
+            Timer::after(COIN_PAYOUT_TIME_PER_COIN * count as u32).await;
+        }
+    }
+
+    match coinacceptor_tube_status(acceptor).await.map(|t| t.tube_status) {
+        Some(levels_after) => {
+            let paid: u16 = (0..16)
+                .map(|i| levels_before[i].saturating_sub(levels_after[i]) as u16 * coin_value(acceptor, i))
+                .sum();
+            info!("Payout of {} requested, {} paid", amount, paid);
+            paid
+        }
+        None => {
+            //Best guess - assume the coins we asked for came out
+            amount - remaining
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn coin_acceptor_payout_task(
+    _context: SpawnCtx,
+    header: VarHeader,
+    amount: u16,
+    sender: Sender<AppTx>,
+) {
+    PAYOUT_RESULT.reset();
+    let timeout = payout_reply_timeout(amount).await;
+    TASK_COMMAND_CHANNEL.send(CoinAcceptorDriverCommand::Payout(amount)).await;
+    let paid = match PAYOUT_RESULT.wait().with_timeout(timeout).await {
+        Ok(paid) => paid,
+        Err(_) => {
+            error!("Timed out awaiting coin payout");
+            0
+        }
+    };
+    let _ = sender.reply::<CoinAcceptorPayoutEndpoint>(header.seq_no, &paid).await;
+}
+
+//A payout requested during an MDB init retry is only answered after it, and could then take
+//the whole amount in the smallest coin. Giving up any sooner would report 0 for a payout
+//that may still happen
+async fn payout_reply_timeout(amount: u16) -> Duration {
+    let smallest_coin = COIN_ACCEPTOR_INFO
+        .lock()
+        .await
+        .and_then(|info| info.coin_values.iter().copied().filter(|&value| value > 0).min())
+        .unwrap_or(1);
+    let max_coins = amount.div_ceil(smallest_coin) as u32;
+    Duration::from_millis(config().await.mdb_init_retry_interval_ms as u64)
+        + COIN_PAYOUT_TIME_PER_COIN * max_coins
+        + PAYOUT_REPLY_MARGIN
+}
+
+pub async fn coin_acceptor_info(
+    _context: &mut Context,
+    _header: VarHeader,
+    _rqst: (),
+) -> CoinAcceptorInfoOption {
+    *COIN_ACCEPTOR_INFO.lock().await
 }
 
 pub async fn set_coin_acceptor_enabled(_context: &mut Context, _header: VarHeader, enable: bool) {
@@ -0,0 +1,116 @@
+use defmt::*;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+
+use postcard_rpc::header::VarHeader;
+use serde::Deserialize;
+
+use vmc_icd::config::{ConfigError, ConfigResult, VmcConfig};
+
+use crate::chiller_driver::notify_chiller_config_changed;
+use crate::flash_storage::{StorageRecord, FLASH_STORAGE, RECORD_LEN};
+use crate::Context;
+
+//Bump whenever VmcConfig changes, and add a migration from the previous version to migrate_config
+const CONFIG_VERSION: u16 = 2;
+
+//Live configuration - loaded from flash at boot, and read by each task as it needs it
+static VMC_CONFIG: Mutex<CriticalSectionRawMutex, VmcConfig> = Mutex::new(VmcConfig::DEFAULT);
+
+pub async fn config() -> VmcConfig {
+    *VMC_CONFIG.lock().await
+}
+
+//Loads the stored config (upgrading it if it was stored by older firmware), or uses the defaults
+pub async fn load_config() {
+    let mut buf = [0x00u8; RECORD_LEN];
+    let stored = match FLASH_STORAGE.read_record(StorageRecord::Config, &mut buf) {
+        Some((version, payload)) => migrate_config(version, payload).map(|config| (version, config)),
+        None => None,
+    };
+
+    match stored {
+        Some((version, config)) if config.validate().is_ok() => {
+            if version != CONFIG_VERSION {
+                info!("Upgraded config from version {} to {}", version, CONFIG_VERSION);
+                if let Err(e) = FLASH_STORAGE.store_record(StorageRecord::Config, CONFIG_VERSION, &config) {
+                    error!("Failed to store upgraded config - {}", e);
+                }
+            }
+            info!("Loaded config from flash");
+            *VMC_CONFIG.lock().await = config;
+        }
+        _ => {
+            info!("No valid config in flash - using defaults");
+        }
+    }
+}
+
+//Decodes a stored config of any known version into the current VmcConfig
+fn migrate_config(version: u16, payload: &[u8]) -> Option<VmcConfig> {
+    match version {
+        CONFIG_VERSION => postcard::from_bytes(payload).ok(),
+        1 => postcard::from_bytes::<VmcConfigV1>(payload).ok().map(VmcConfig::from),
+        _ => {
+            warn!("Stored config is unknown version {}", version);
+            None
+        }
+    }
+}
+
+//Version 1 - before the drop sensor was configured here rather than by a build feature. A
+//drop sensor is left disabled by the upgrade, so needs enabling again with SetConfig
+#[derive(Deserialize)]
+struct VmcConfigV1 {
+    chiller_setpoint: i8,
+    chiller_measure_interval_ms: u32,
+    chiller_min_cycle_count: u8,
+    motor_leave_home_timeout_ms: u32,
+    motor_return_home_timeout_ms: u32,
+    drop_detect_timeout_ms: u32,
+    mdb_poll_interval_ms: u32,
+    mdb_init_retry_interval_ms: u32,
+    watchdog_timeout_ms: u32,
+    watchdog_feed_interval_ms: u32,
+}
+
+impl From<VmcConfigV1> for VmcConfig {
+    fn from(v1: VmcConfigV1) -> Self {
+        Self {
+            chiller_setpoint: v1.chiller_setpoint,
+            chiller_measure_interval_ms: v1.chiller_measure_interval_ms,
+            chiller_min_cycle_count: v1.chiller_min_cycle_count,
+            motor_leave_home_timeout_ms: v1.motor_leave_home_timeout_ms,
+            motor_return_home_timeout_ms: v1.motor_return_home_timeout_ms,
+            drop_detect_timeout_ms: v1.drop_detect_timeout_ms,
+            mdb_poll_interval_ms: v1.mdb_poll_interval_ms,
+            mdb_init_retry_interval_ms: v1.mdb_init_retry_interval_ms,
+            watchdog_timeout_ms: v1.watchdog_timeout_ms,
+            watchdog_feed_interval_ms: v1.watchdog_feed_interval_ms,
+            drop_sensor_enabled: VmcConfig::DEFAULT.drop_sensor_enabled,
+            drop_sensor_pin: VmcConfig::DEFAULT.drop_sensor_pin,
+        }
+    }
+}
+
+//Validates, stores and applies a new config
+pub async fn update_config(new_config: VmcConfig) -> ConfigResult {
+    new_config.validate()?;
+    if let Err(e) = FLASH_STORAGE.store_record(StorageRecord::Config, CONFIG_VERSION, &new_config) {
+        error!("Failed to store config in flash - {}", e);
+        return Err(ConfigError::StorageError);
+    }
+    *VMC_CONFIG.lock().await = new_config;
+    notify_chiller_config_changed();
+    info!("Config updated");
+    Ok(())
+}
+
+pub async fn get_config(_context: &mut Context, _header: VarHeader, _rqst: ()) -> VmcConfig {
+    config().await
+}
+
+pub async fn set_config(_context: &mut Context, _header: VarHeader, new_config: VmcConfig) -> ConfigResult {
+    update_config(new_config).await
+}
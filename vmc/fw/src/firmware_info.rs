@@ -0,0 +1,10 @@
+use postcard_rpc::header::VarHeader;
+
+use vmc_icd::firmware::FirmwareInfo;
+use vmc_icd::icd_fingerprint;
+
+use crate::Context;
+
+pub async fn firmware_info(_context: &mut Context, _header: VarHeader, _rqst: ()) -> FirmwareInfo {
+    vmc_icd::firmware::firmware_info!(icd_fingerprint())
+}
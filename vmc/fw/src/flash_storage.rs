@@ -0,0 +1,39 @@
+use defmt::*;
+
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+use flash_record::{Record, RecordStore};
+pub use flash_record::RECORD_LEN;
+
+//Must match the size of the flash in memory.x
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+//memory.x keeps the firmware out of the top STORAGE_SECTORS erase sectors of flash
+const STORAGE_SECTORS: usize = 4;
+
+const RECORD_MAGIC: u32 = 0x534E_4B42; //"SNKB"
+//Records stored before the header had a version. Only machine layouts were stored then, so
+//they're read as version 1, and rewritten with a version next time
+const LEGACY_RECORD_VERSION: u16 = 1;
+
+pub static FLASH_STORAGE: RecordStore<CriticalSectionRawMutex, Flash<'static, FLASH, Blocking, FLASH_SIZE>> =
+    RecordStore::new(
+        RECORD_MAGIC,
+        (FLASH_SIZE - STORAGE_SECTORS * ERASE_SIZE) as u32,
+        Some(LEGACY_RECORD_VERSION),
+    );
+
+#[derive(Copy, Clone, Format)]
+pub enum StorageRecord {
+    MachineLayout,
+    Config,
+}
+
+impl Record for StorageRecord {
+    fn sector(self) -> usize {
+        self as usize
+    }
+}
@@ -44,23 +44,36 @@ use vmc_icd::*;
 
 mod coin_acceptor;
 mod cashless_device;
+mod bill_validator;
 mod motor_driver;
 mod usb_device_handler;
 mod chiller_driver;
 mod watchdog;
+mod firmware_info;
+mod flash_storage;
+mod config;
 
-use coin_acceptor::{coin_acceptor_task, set_coin_acceptor_enabled};
+use coin_acceptor::{coin_acceptor_task, coin_acceptor_info, coin_acceptor_payout_task, set_coin_acceptor_enabled};
 use cashless_device::{cashless_device_task, cashless_device_cmd_handler};
+use bill_validator::{bill_validator_task, bill_validator_cmd_handler};
 
-use motor_driver::{MotorDriver, motor_driver_dispense_task, motor_driver_dispenser_status};
+use motor_driver::{MotorDriver, load_machine_layout, motor_driver_dispense_task, motor_driver_dispenser_status, motor_driver_map};
+use motor_driver::{motor_driver_get_layout, motor_driver_set_layout};
 
 use usb_device_handler::usb_task;
 use usb_device_handler::UsbDeviceHandler;
 
-use chiller_driver::chiller_task;
+use chiller_driver::{chiller_task, chiller_info, chiller_set_setpoint};
 
 use watchdog::watchdog_task;
 
+use firmware_info::firmware_info;
+
+use flash_storage::FLASH_STORAGE;
+use embassy_rp::flash::Flash;
+
+use config::{load_config, get_config, set_config};
+
 type AppDriver = usb::Driver<'static, USB>;
 type BufStorage = PacketBuffers<1024, 1024>;
 static PBUFS: ConstStaticCell<BufStorage> = ConstStaticCell::new(BufStorage::new());
@@ -98,13 +111,25 @@ define_dispatch! {
         list: ENDPOINT_LIST;
         | EndpointTy                | kind        | handler                       |
         | ----------                | ----        | -------                       |
+        | FirmwareInfoEndpoint      | async       | firmware_info                 |
+        | ConfigEndpoint            | async       | get_config                    |
+        | SetConfigEndpoint         | async       | set_config                    | //Writing flash is quick enough not to need a spawn fn
+
         | DispenseEndpoint          | spawn       | motor_driver_dispense_task    | //Spawn fn due to duration of operation
         | DispenserStatusEndpoint   | async       | motor_driver_dispenser_status | //Finding status is fast enough to be an async fn
+        | DispenserMapEndpoint      | async       | motor_driver_map              | //As is mapping, as only fitted addresses are pulsed
+        | MachineLayoutEndpoint     | async       | motor_driver_get_layout       |
+        | SetMachineLayoutEndpoint  | async       | motor_driver_set_layout       |
 
         | CoinAcceptorEnableEndpoint| async       | set_coin_acceptor_enabled     |
-      //  | CoinAcceptorInfoEndpoint  | async       | coin_acceptor_info            |
+        | CoinAcceptorPayoutEndpoint| spawn       | coin_acceptor_payout_task     | //Spawn fn as coins take a while to drop
+        | CoinAcceptorInfoEndpoint  | async       | coin_acceptor_info            |
 
        | CashlessDeviceCmdEndpoint | async         |   cashless_device_cmd_handler       | 
+        | BillValidatorCmdEndpoint  | async       | bill_validator_cmd_handler    |
+
+        | ChillerInfoEndpoint       | async       | chiller_info                  |
+        | ChillerSetpointEndpoint   | async       | chiller_set_setpoint          |
     };
     
     topics_in: {    
@@ -150,6 +175,11 @@ static DISPENSER_DRIVER: Mutex<CriticalSectionRawMutex, Option<MotorDriver>> = M
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
        let resources = split_resources!(p);
+
+    debug!("Initialising flash storage");
+    FLASH_STORAGE.init(Flash::new_blocking(p.FLASH));
+    //Config is needed by all the tasks, including the watchdog
+    load_config().await;
    
     //Spawn the watchdog task first
     spawner.must_spawn(watchdog_task(resources.watchdog.watchdog, Output::new(resources.watchdog.heartbeat_pin, Level::High)));
@@ -193,8 +223,9 @@ async fn main(spawner: Spawner) {
     {
         debug!("Initialising motor driver");
         //Set up the dispenser motor driver struct - the task that uses it is spawned by postcard-rpc
+        let layout = load_machine_layout();
         let mut m = DISPENSER_DRIVER.lock().await;
-        *m = Some(MotorDriver::new(resources.motor_driver_pins).await);
+        *m = Some(MotorDriver::new(resources.motor_driver_pins, layout).await);
     }
 
     //Set up the ADC for the chiller thermistor and spawn its' task
@@ -203,7 +234,7 @@ async fn main(spawner: Spawner) {
     let adc_channel = adc::Channel::new_pin(resources.chiller.thermistor_pin, Pull::None);
 
     debug!("Spawning chiller task");
-    spawner.must_spawn(chiller_task(adc, adc_channel, Output::new(resources.chiller.led_pin, Level::Low))); 
+    spawner.must_spawn(chiller_task(adc, adc_channel, Output::new(resources.chiller.led_pin, Level::Low), server.sender().clone())); 
 
     //Set up the multi-drop bus peripheral (and its' PIO backed 9 bit uart) 
     debug!("Initialising PIO UART");
@@ -236,6 +267,10 @@ async fn main(spawner: Spawner) {
     debug!("Spawning cashless device poll task");
     spawner.must_spawn(cashless_device_task(server.sender().clone()));
 
+    //Spawn the bill validator poll task
+    debug!("Spawning bill validator poll task");
+    spawner.must_spawn(bill_validator_task(server.sender().clone()));
+
     
 
     debug!("Entering Postcard-RPC main loop");
@@ -1,17 +1,41 @@
 use defmt::*;
 
-use embassy_rp::gpio::{Level, OutputOpenDrain};
-use embassy_time::{Duration, Timer, WithTimeout};
+use embassy_rp::gpio::{AnyPin, Input, Level, OutputOpenDrain, Pull};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embassy_futures::join::join;
 
 use vmc_icd::dispenser::{
     CanStatus, DispenseError, DispenseResult, Dispenser, DispenserAddress, DispenseCommand,
-    DispenserOption, DispenserType, MotorStatus,
+    DispensePhase, DispenseProgress, DispenserOption, DispenserType, MachineMap, MotorStatus, DISPENSER_COLS, DISPENSER_ROWS,
 };
-use vmc_icd::DispenseEndpoint;
+use vmc_icd::layout::{LayoutError, LayoutResult, MachineLayout};
+use vmc_icd::{DispenseEndpoint, DispenseProgressTopic};
 
 use postcard_rpc::header::VarHeader;
 
 use crate::{AppTx, MotorDriverResources, Sender, SpawnCtx, Context, DISPENSER_DRIVER};
+use crate::flash_storage::{StorageRecord, FLASH_STORAGE};
+use crate::config::config;
+
+//Motor and drop sensor timeouts come from the VMC config - the drop detect timeout must cover the
+//whole motor cycle (leaving home, debounce, returning home) plus time for the product to fall
+
+//Bump if MachineLayout changes - older stored layouts are then ignored in favour of the default
+const LAYOUT_RECORD_VERSION: u16 = 1;
+
+//Loads the layout stored in flash, falling back to the default if none is stored
+pub fn load_machine_layout() -> MachineLayout {
+    match FLASH_STORAGE.load_record::<MachineLayout>(StorageRecord::MachineLayout, LAYOUT_RECORD_VERSION) {
+        Some(layout) if layout.validate().is_ok() => {
+            info!("Loaded machine layout from flash");
+            layout
+        }
+        _ => {
+            info!("No valid machine layout in flash - using default");
+            MachineLayout::DEFAULT
+        }
+    }
+}
 
 #[embassy_executor::task]
 pub async fn motor_driver_dispense_task(
@@ -25,10 +49,10 @@ pub async fn motor_driver_dispense_task(
     debug!("Sending dispense command");
     let result = match rqst {
         DispenseCommand::Vend(addr) => {
-            driver.dispense(addr).await
+            driver.dispense(addr, &sender).await
         }
         DispenseCommand::ForceVend(addr) => {
-            driver.force_dispense(addr).await
+            driver.force_dispense(addr, &sender).await
         }
     };
     debug!("Awaiting reply from dispense task");
@@ -44,17 +68,72 @@ pub async fn motor_driver_dispenser_status(
     driver.get_dispenser(addr).await
 }
 
+pub async fn motor_driver_map(
+    _context: &mut Context,
+    _header: VarHeader,
+    _rqst: ()) -> MachineMap {
+    let mut r = DISPENSER_DRIVER.lock().await;
+    let driver = r.as_mut().expect("Motor driver must be stored in mutex");
+    let mut map: MachineMap = [[None; DISPENSER_COLS.len()]; DISPENSER_ROWS.len()];
+    for (row, map_row) in core::iter::zip(DISPENSER_ROWS, map.iter_mut()) {
+        for (col, dispenser) in core::iter::zip(DISPENSER_COLS, map_row.iter_mut()) {
+            *dispenser = driver.get_dispenser(DispenserAddress { row, col }).await;
+        }
+    }
+    map
+}
+
+pub async fn motor_driver_get_layout(
+    _context: &mut Context,
+    _header: VarHeader,
+    _rqst: ()) -> MachineLayout {
+    let r = DISPENSER_DRIVER.lock().await;
+    let driver = r.as_ref().expect("Motor driver must be stored in mutex");
+    driver.layout()
+}
+
+pub async fn motor_driver_set_layout(
+    _context: &mut Context,
+    _header: VarHeader,
+    layout: MachineLayout) -> LayoutResult {
+    layout.validate()?;
+    if let Err(e) = FLASH_STORAGE.store_record(StorageRecord::MachineLayout, LAYOUT_RECORD_VERSION, &layout) {
+        error!("Failed to store machine layout in flash - {}", e);
+        return Err(LayoutError::StorageError);
+    }
+    let mut r = DISPENSER_DRIVER.lock().await;
+    let driver = r.as_mut().expect("Motor driver must be stored in mutex");
+    driver.set_layout(layout);
+    info!("Machine layout updated");
+    Ok(())
+}
+
+//The drop sensor's pin comes from the config, so is only known at runtime. It is always one of
+//DROP_SENSOR_PINS, which nothing else on the board uses
+async fn drop_sensor() -> Option<Input<'static>> {
+    let config = config().await;
+    if !config.drop_sensor_enabled {
+        return None;
+    }
+    info!("Drop sensor enabled on GPIO {}", config.drop_sensor_pin);
+    //Falling product interrupts the sensor beam, pulling the input low
+    let pin = unsafe { AnyPin::steal(config.drop_sensor_pin) };
+    Some(Input::new(pin, Pull::Up))
+}
+
 pub struct MotorDriver<'a> {
     bus: [OutputOpenDrain<'a>; 8],
     clks: [OutputOpenDrain<'a>; 3],
     output_enable: OutputOpenDrain<'a>,
     flipflop_clr: OutputOpenDrain<'a>,
-    valid_addresses: [DispenserAddress; 24],
+    layout: MachineLayout,
     chiller_on: bool,
+    progress_seq: u16,
+    drop_sensor: Option<Input<'a>>,
 }
 
 impl<'a> MotorDriver<'a> {
-    pub(crate) async fn new(pins: MotorDriverResources) -> Self {
+    pub(crate) async fn new(pins: MotorDriverResources, layout: MachineLayout) -> Self {
         let mut x = Self {
             bus: [
                 OutputOpenDrain::new(pins.p0, Level::High),
@@ -74,34 +153,10 @@ impl<'a> MotorDriver<'a> {
             output_enable: OutputOpenDrain::new(pins.oe, Level::High),
             flipflop_clr: OutputOpenDrain::new(pins.clr, Level::High),
 
-            valid_addresses: [
-                DispenserAddress { row: 'A', col: '0' },
-                DispenserAddress { row: 'A', col: '2' },
-                DispenserAddress { row: 'A', col: '4' },
-                DispenserAddress { row: 'A', col: '6' },
-                DispenserAddress { row: 'B', col: '0' },
-                DispenserAddress { row: 'B', col: '2' },
-                DispenserAddress { row: 'B', col: '4' },
-                DispenserAddress { row: 'B', col: '6' },
-                DispenserAddress { row: 'C', col: '0' },
-                DispenserAddress { row: 'C', col: '1' },
-                DispenserAddress { row: 'C', col: '2' },
-                DispenserAddress { row: 'C', col: '3' },
-                DispenserAddress { row: 'C', col: '4' },
-                DispenserAddress { row: 'C', col: '5' },
-                DispenserAddress { row: 'C', col: '6' },
-                DispenserAddress { row: 'C', col: '7' },
-                //Our cans
-                DispenserAddress { row: 'E', col: '0' },
-                DispenserAddress { row: 'E', col: '1' },
-                DispenserAddress { row: 'E', col: '2' },
-                DispenserAddress { row: 'E', col: '3' },
-                DispenserAddress { row: 'F', col: '0' },
-                DispenserAddress { row: 'F', col: '1' },
-                DispenserAddress { row: 'F', col: '2' },
-                DispenserAddress { row: 'F', col: '3' },
-            ],
+            layout,
             chiller_on: false,
+            progress_seq: 0,
+            drop_sensor: drop_sensor().await,
         };
 
         //Clear the flipflops using flipflop_clr
@@ -114,7 +169,20 @@ impl<'a> MotorDriver<'a> {
     }
 
     fn is_address_valid(&mut self, addr: DispenserAddress) -> bool {
-        self.valid_addresses.contains(&addr)
+        self.layout.is_fitted(addr)
+    }
+
+    pub fn layout(&self) -> MachineLayout {
+        self.layout
+    }
+
+    //Layout must already have been validated
+    pub fn set_layout(&mut self, layout: MachineLayout) {
+        self.layout = layout;
+    }
+
+    fn dispenser_type(&self, addr: DispenserAddress) -> DispenserType {
+        self.layout.dispenser_type(addr.row).unwrap_or(DispenserType::Spiral)
     }
 
     async fn stop_motors(&mut self) {
@@ -125,7 +193,7 @@ impl<'a> MotorDriver<'a> {
 
     async fn drive_motor(&mut self, addr: DispenserAddress) {
         debug!("Driving motor at {}{}", addr.row, addr.col);
-        let bytes = MotorDriver::calc_drive_bytes(addr).unwrap();
+        let bytes = MotorDriver::calc_drive_bytes(addr, self.dispenser_type(addr)).unwrap();
         self.write_bytes(bytes).await;
     }
 
@@ -156,7 +224,7 @@ impl<'a> MotorDriver<'a> {
         }
     }
 
-    fn calc_drive_bytes(addr: DispenserAddress) -> Result<[u8; 3], ()> {
+    fn calc_drive_bytes(addr: DispenserAddress, dispenser_type: DispenserType) -> Result<[u8; 3], ()> {
         /*
         Wiring is as follows
 
@@ -178,6 +246,13 @@ impl<'a> MotorDriver<'a> {
         0x20 - Row C Odd
         0x40 - Row D Even
         0x80 - Row D Odd
+
+        Home switches are read back on the bus:
+        0x01 - Rows A-D Even    0x10 - Row E
+        0x02 - Rows A-D Odd     0x40 - Row F
+        (0x20, 0x80 are the row E/F can sensors)
+        0x04 - Row G - UNVERIFIED, a guess as it's the unused line. Not checked on a machine
+               with the gum/mint module fitted
         */
         let mut drive_bytes: [u8; 3] = [0x00; 3];
 
@@ -191,12 +266,12 @@ impl<'a> MotorDriver<'a> {
         }
 
         let row_offset = addr.row as u8 - b'A';
-        let col_offset = match addr.row as u8 {
-            //Special handling for can chiller rows (E/F) due to discrepancy in numbering and wiring!
-            //Row E +F cans are numbered E0, E1, E2, E3 but are wired E0, E2, E4, E6
-            //G - Gum and Mint may need special handling if implemented as I suspect that's wired 0/2/4/6/8 also.
-            //G is the optional Gum/Mint module.
-            b'E' | b'F' => (addr.col as u8 - b'0') * 2,
+        let col_offset = match dispenser_type {
+            //Special handling for can and gum/mint rows due to discrepancy in numbering and wiring!
+            //Cans are numbered E0, E1, E2, E3 but are wired E0, E2, E4, E6
+            //G - the optional Gum/Mint module is numbered G0-G4. UNVERIFIED - I suspect it's wired
+            //G0, G2, G4, G6, G8 like the cans, but haven't had one to check
+            DispenserType::Can | DispenserType::GumMint => (addr.col as u8 - b'0') * 2,
             //Standard column offset
             _ => addr.col as u8 - b'0',
         };
@@ -237,6 +312,7 @@ impl<'a> MotorDriver<'a> {
         match addr.row {
             'E' => 4, //0x10u8
             'F' => 6, //0x40u8,
+            'G' => 2, //0x04u8 - UNVERIFIED guess, see calc_drive_bytes
             _ => {
                 if addr.col.to_digit(10).unwrap_or(0) % 2 == 0 {
                     0 //0x01
@@ -268,10 +344,7 @@ impl<'a> MotorDriver<'a> {
         if self.is_address_valid(addr) {
             Some(Dispenser {
                 address: addr,
-                dispenser_type: match addr.row {
-                    'E' | 'F' => DispenserType::Can,
-                    _ => DispenserType::Spiral,
-                },
+                dispenser_type: self.dispenser_type(addr),
                 motor_status: self.motor_home_status(addr).await,
                 can_status: self.can_status(addr).await,
             })
@@ -299,8 +372,8 @@ impl<'a> MotorDriver<'a> {
     }
 
     async fn can_status(&mut self, addr: DispenserAddress) -> Option<CanStatus> {
-        //Our can rows are E and F
-        if addr.row != 'E' && addr.row != 'F' {
+        //Can dispensers can only be fitted to rows E and F (see MachineLayout::validate)
+        if self.dispenser_type(addr) != DispenserType::Can {
             debug!("Checked can status for non can row {}", addr.row);
             return None;
         }
@@ -325,7 +398,25 @@ impl<'a> MotorDriver<'a> {
         Some(status)
     }
 
-    pub async fn dispense(&mut self, addr: DispenserAddress) -> DispenseResult {
+    async fn publish_progress(
+        &mut self,
+        sender: &Sender<AppTx>,
+        addr: DispenserAddress,
+        phase: DispensePhase,
+        start: Instant,
+    ) {
+        let progress = DispenseProgress {
+            address: addr,
+            phase,
+            elapsed_ms: start.elapsed().as_millis() as u32,
+        };
+        let _ = sender
+            .publish::<DispenseProgressTopic>(self.progress_seq.into(), &progress)
+            .await;
+        self.progress_seq = self.progress_seq.wrapping_add(1);
+    }
+
+    pub async fn dispense(&mut self, addr: DispenserAddress, sender: &Sender<AppTx>) -> DispenseResult {
         if self.motor_home_status(addr).await != MotorStatus::Ok {
             error!("Refusing to dispense item - motor not home at start of vend");
             return Err(DispenseError::MotorNotHome);
@@ -337,12 +428,43 @@ impl<'a> MotorDriver<'a> {
                 return Err(DispenseError::OneOrNoCansLeft);
             }
         }
-        self.force_dispense(addr).await
+        self.force_dispense(addr, sender).await
+    }
+
+    pub async fn force_dispense(&mut self, addr: DispenserAddress, sender: &Sender<AppTx>) -> DispenseResult {
+        let start = Instant::now();
+        let drop_detect_timeout = Duration::from_millis(config().await.drop_detect_timeout_ms as u64);
+        //The drop sensor is taken out of self whilst the motor runs, so both can be awaited at once
+        match self.drop_sensor.take() {
+            Some(mut sensor) => {
+                let (result, drop) = join(
+                    self.run_motor_cycle(addr, sender, start),
+                    sensor.wait_for_falling_edge().with_timeout(drop_detect_timeout),
+                )
+                .await;
+                self.drop_sensor = Some(sensor);
+                match (result, drop) {
+                    (Ok(()), Err(_)) => {
+                        error!("Motor cycle completed, but no product drop detected");
+                        Err(DispenseError::NoDropDetected)
+                    }
+                    (result, _) => result,
+                }
+            }
+            None => self.run_motor_cycle(addr, sender, start).await,
+        }
     }
 
-    pub async fn force_dispense(&mut self, addr: DispenserAddress) -> DispenseResult {
+    async fn run_motor_cycle(
+        &mut self,
+        addr: DispenserAddress,
+        sender: &Sender<AppTx>,
+        start: Instant,
+    ) -> DispenseResult {
+        let motor_config = config().await;
         debug!("Driving dispense motor at {}{}", addr.row, addr.col);
         self.drive_motor(addr).await;
+        self.publish_progress(sender, addr, DispensePhase::MotorStarted, start).await;
         let home_gpio_index = MotorDriver::motor_homed_gpio_index(addr);
         debug!("Motor homed gpio index is is {}", home_gpio_index);
         debug!("Waiting for motor to leave home");
@@ -353,27 +475,29 @@ impl<'a> MotorDriver<'a> {
 
         let b = self.bus[home_gpio_index]
             .wait_for_low()
-            .with_timeout(Duration::from_millis(1000))
+            .with_timeout(Duration::from_millis(motor_config.motor_leave_home_timeout_ms as u64))
             .await;
 
         if b.is_ok() {
             debug!("Motor left home");
+            self.publish_progress(sender, addr, DispensePhase::LeftHome, start).await;
         } else {
-            error!("Motor did not leave home in time (1 sec)");
+            error!("Motor did not leave home in time ({} ms)", motor_config.motor_leave_home_timeout_ms);
             //Turn the buffer off again.
             self.output_enable.set_high();
             Timer::after_micros(20).await;
             self.stop_motors().await;
+            self.publish_progress(sender, addr, DispensePhase::TimedOut, start).await;
             return Err(DispenseError::MotorStuckHome);
         }
 
         //Avoid issue with bouncing microswitch contacts
         Timer::after_millis(500).await;
 
-        //Now the motor is moving, it has 3 seconds to return home to complete the vend cycles
+        //Now the motor is moving, it has a limited time to return home to complete the vend cycle
         let b = self.bus[home_gpio_index]
             .wait_for_high()
-            .with_timeout(Duration::from_millis(2500))
+            .with_timeout(Duration::from_millis(motor_config.motor_return_home_timeout_ms as u64))
             .await;
 
         //Buffer off.
@@ -385,9 +509,11 @@ impl<'a> MotorDriver<'a> {
 
         if b.is_ok() {
             info!("Vend completed successfully");
+            self.publish_progress(sender, addr, DispensePhase::ReturnedHome, start).await;
             Ok(())
         } else {
-            error!("Motor did not return home in time (3 sec)");
+            error!("Motor did not return home in time ({} ms)", motor_config.motor_return_home_timeout_ms);
+            self.publish_progress(sender, addr, DispensePhase::TimedOut, start).await;
             Err(DispenseError::MotorStuckNotHome)
         }
     }
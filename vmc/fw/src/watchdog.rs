@@ -3,16 +3,17 @@ use embassy_rp::peripherals::WATCHDOG;
 use embassy_time::{Duration, Timer};
 use embassy_rp::gpio::Output;
 
-const WATCHDOG_TIMER_SECS:u64 = 2;
-const WATCHDOG_FEED_TIMER_MS:u64 = 250;
+use crate::config::config;
 
 #[embassy_executor::task]
 pub async fn watchdog_task(watchdog: WATCHDOG, mut heartbeat_pin: Output<'static>) -> ! {
+    //Timings are only read at startup, so changes take effect after a reboot
+    let watchdog_config = config().await;
     let mut dog = Watchdog::new(watchdog);
-    dog.start(Duration::from_secs(WATCHDOG_TIMER_SECS));
+    dog.start(Duration::from_millis(watchdog_config.watchdog_timeout_ms as u64));
     loop {        
         dog.feed();
-        Timer::after(Duration::from_millis(WATCHDOG_FEED_TIMER_MS)).await;
+        Timer::after(Duration::from_millis(watchdog_config.watchdog_feed_interval_ms as u64)).await;
         heartbeat_pin.toggle();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,223 @@
+//postcard-rpc endpoint handlers - these mirror the firmware's, but act on the simulated Machine
+use std::sync::Arc;
+
+use postcard_rpc::header::VarHeader;
+use postcard_rpc::server::impls::test_channels::dispatch_impl::{spawn_fn, WireSpawnImpl, WireTxImpl};
+use postcard_rpc::server::{Sender, SpawnContext};
+use postcard_rpc::define_dispatch;
+
+use tokio::time::{sleep, Instant};
+
+use vmc_icd::bill_validator::BillValidatorCommand;
+use vmc_icd::cashless_device::CashlessDeviceCommand;
+use vmc_icd::chiller::{ChillerInfo, ChillerSetpointResult, MAX_CHILLER_SETPOINT, MIN_CHILLER_SETPOINT};
+use vmc_icd::coin_acceptor::CoinAcceptorInfoOption;
+use vmc_icd::config::{ConfigResult, VmcConfig};
+use vmc_icd::dispenser::{
+    DispenseCommand, DispenseError, DispensePhase, DispenseProgress, DispenseResult, DispenserAddress,
+    DispenserOption, MachineMap, DISPENSER_COLS, DISPENSER_ROWS,
+};
+use vmc_icd::firmware::FirmwareInfo;
+use vmc_icd::layout::{LayoutResult, MachineLayout};
+use vmc_icd::*;
+
+use crate::machine::{Jam, Publish};
+use crate::Shared;
+
+pub struct EmulatorContext {
+    shared: Arc<Shared>,
+}
+
+impl EmulatorContext {
+    pub fn new(shared: Arc<Shared>) -> Self {
+        Self { shared }
+    }
+}
+
+pub struct EmulatorSpawnCtx {
+    shared: Arc<Shared>,
+}
+
+impl SpawnContext for EmulatorContext {
+    type SpawnCtxt = EmulatorSpawnCtx;
+    fn spawn_ctxt(&mut self) -> Self::SpawnCtxt {
+        EmulatorSpawnCtx { shared: self.shared.clone() }
+    }
+}
+
+define_dispatch! {
+    app: EmulatorApp;
+    spawn_fn: spawn_fn;
+    tx_impl: WireTxImpl;
+    spawn_impl: WireSpawnImpl;
+    context: EmulatorContext;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+        | EndpointTy                | kind        | handler                       |
+        | ----------                | ----        | -------                       |
+        | FirmwareInfoEndpoint      | blocking    | firmware_info                 |
+        | ConfigEndpoint            | blocking    | get_config                    |
+        | SetConfigEndpoint         | blocking    | set_config                    |
+
+        | DispenseEndpoint          | spawn       | dispense_task                 |
+        | DispenserStatusEndpoint   | blocking    | dispenser_status              |
+        | DispenserMapEndpoint      | blocking    | dispenser_map                 |
+        | MachineLayoutEndpoint     | blocking    | get_layout                    |
+        | SetMachineLayoutEndpoint  | blocking    | set_layout                    |
+
+        | CoinAcceptorEnableEndpoint| blocking    | set_coin_acceptor_enabled     |
+        | CoinAcceptorPayoutEndpoint| spawn       | coin_acceptor_payout_task     |
+        | CoinAcceptorInfoEndpoint  | blocking    | coin_acceptor_info            |
+
+        | CashlessDeviceCmdEndpoint | async       | cashless_device_cmd           |
+        | BillValidatorCmdEndpoint  | async       | bill_validator_cmd            |
+
+        | ChillerInfoEndpoint       | blocking    | chiller_info                  |
+        | ChillerSetpointEndpoint   | blocking    | chiller_set_setpoint          |
+    };
+
+    topics_in: {
+        list: TOPICS_IN_LIST;
+        | TopicTy                   | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+    };
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
+fn firmware_info(context: &mut EmulatorContext, _header: VarHeader, _rqst: ()) -> FirmwareInfo {
+    vmc_icd::firmware::firmware_info!(context.shared.config.icd_fingerprint.unwrap_or_else(icd_fingerprint))
+}
+
+fn get_config(context: &mut EmulatorContext, _header: VarHeader, _rqst: ()) -> VmcConfig {
+    context.shared.machine.lock().unwrap().config
+}
+
+fn set_config(context: &mut EmulatorContext, _header: VarHeader, config: VmcConfig) -> ConfigResult {
+    config.validate()?;
+    context.shared.machine.lock().unwrap().config = config;
+    context.shared.chiller_config_changed.notify_waiters();
+    Ok(())
+}
+
+async fn dispense_task(
+    context: EmulatorSpawnCtx,
+    header: VarHeader,
+    rqst: DispenseCommand,
+    sender: Sender<WireTxImpl>,
+) {
+    let result = match rqst {
+        DispenseCommand::Vend(addr) => dispense(&context.shared, addr, false).await,
+        DispenseCommand::ForceVend(addr) => dispense(&context.shared, addr, true).await,
+    };
+    let _ = sender.reply::<DispenseEndpoint>(header.seq_no, &result).await;
+}
+
+async fn dispense(shared: &Shared, addr: DispenserAddress, force: bool) -> DispenseResult {
+    let jam = shared.machine.lock().unwrap().start_vend(addr, force)?;
+    let start = Instant::now();
+    let progress = |phase| {
+        Publish::Progress(DispenseProgress {
+            address: addr,
+            phase,
+            elapsed_ms: start.elapsed().as_millis() as u32,
+        })
+    };
+
+    shared.publish(progress(DispensePhase::MotorStarted)).await;
+    sleep(shared.config.motor_phase_time).await;
+    if jam == Some(Jam::StuckHome) {
+        shared.publish(progress(DispensePhase::TimedOut)).await;
+        shared.machine.lock().unwrap().record_vend(addr, Err(DispenseError::MotorStuckHome));
+        return Err(DispenseError::MotorStuckHome);
+    }
+
+    shared.publish(progress(DispensePhase::LeftHome)).await;
+    sleep(shared.config.motor_phase_time).await;
+    if jam == Some(Jam::StuckNotHome) {
+        shared.publish(progress(DispensePhase::TimedOut)).await;
+        let mut machine = shared.machine.lock().unwrap();
+        machine.motor_stuck_not_home(addr);
+        machine.record_vend(addr, Err(DispenseError::MotorStuckNotHome));
+        return Err(DispenseError::MotorStuckNotHome);
+    }
+
+    shared.publish(progress(DispensePhase::ReturnedHome)).await;
+    shared.machine.lock().unwrap().finish_vend(addr, jam)
+}
+
+fn dispenser_status(context: &mut EmulatorContext, _header: VarHeader, addr: DispenserAddress) -> DispenserOption {
+    context.shared.machine.lock().unwrap().dispenser(addr)
+}
+
+fn dispenser_map(context: &mut EmulatorContext, _header: VarHeader, _rqst: ()) -> MachineMap {
+    let mut machine = context.shared.machine.lock().unwrap();
+    DISPENSER_ROWS.map(|row| DISPENSER_COLS.map(|col| machine.dispenser(DispenserAddress { row, col })))
+}
+
+fn get_layout(context: &mut EmulatorContext, _header: VarHeader, _rqst: ()) -> MachineLayout {
+    context.shared.machine.lock().unwrap().layout
+}
+
+fn set_layout(context: &mut EmulatorContext, _header: VarHeader, layout: MachineLayout) -> LayoutResult {
+    layout.validate()?;
+    context.shared.machine.lock().unwrap().layout = layout;
+    Ok(())
+}
+
+fn set_coin_acceptor_enabled(context: &mut EmulatorContext, _header: VarHeader, enable: bool) {
+    context.shared.machine.lock().unwrap().set_coins_enabled(enable);
+}
+
+async fn coin_acceptor_payout_task(
+    context: EmulatorSpawnCtx,
+    header: VarHeader,
+    amount: u16,
+    sender: Sender<WireTxImpl>,
+) {
+    let (paid, coins) = context.shared.machine.lock().unwrap().payout(amount);
+    sleep(context.shared.config.coin_payout_time * coins).await;
+    let _ = sender.reply::<CoinAcceptorPayoutEndpoint>(header.seq_no, &paid).await;
+}
+
+fn coin_acceptor_info(context: &mut EmulatorContext, _header: VarHeader, _rqst: ()) -> CoinAcceptorInfoOption {
+    context.shared.machine.lock().unwrap().coin_acceptor_info()
+}
+
+async fn cashless_device_cmd(context: &mut EmulatorContext, _header: VarHeader, cmd: CashlessDeviceCommand) {
+    let msg = context.shared.machine.lock().unwrap().cashless_command(cmd);
+    context.shared.maybe_publish(msg).await;
+}
+
+async fn bill_validator_cmd(context: &mut EmulatorContext, _header: VarHeader, cmd: BillValidatorCommand) {
+    let msg = {
+        let mut machine = context.shared.machine.lock().unwrap();
+        match cmd {
+            BillValidatorCommand::Enable => {
+                machine.set_bills_enabled(true);
+                None
+            }
+            BillValidatorCommand::Disable => {
+                machine.set_bills_enabled(false);
+                None
+            }
+            BillValidatorCommand::AcceptEscrow => machine.release_escrow(true),
+            BillValidatorCommand::RejectEscrow => machine.release_escrow(false),
+        }
+    };
+    context.shared.maybe_publish(msg).await;
+}
+
+fn chiller_info(context: &mut EmulatorContext, _header: VarHeader, _rqst: ()) -> ChillerInfo {
+    context.shared.machine.lock().unwrap().chiller_info()
+}
+
+fn chiller_set_setpoint(context: &mut EmulatorContext, _header: VarHeader, setpoint: i8) -> ChillerSetpointResult {
+    if !(MIN_CHILLER_SETPOINT..=MAX_CHILLER_SETPOINT).contains(&setpoint) {
+        return Err(());
+    }
+    context.shared.machine.lock().unwrap().config.chiller_setpoint = setpoint;
+    Ok(())
+}
@@ -0,0 +1,232 @@
+//Emulates the Pico VMC, serving every vmc-icd endpoint and topic over in-process channels,
+//so vmc-host can be run and tested without the real hardware plugged in.
+//
+//Connect a HostClient with Emulator::connect, then drive the simulated machine (insert coins,
+//present cards, jam motors etc) via the Emulator's other methods. Chiller status is published
+//periodically, as the firmware does, every chiller_measure_interval_ms of the VmcConfig.
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use postcard_rpc::header::VarSeqKind;
+use postcard_rpc::host_client::{test_channels::new_from_channels, HostClient};
+use postcard_rpc::server::impls::test_channels::{
+    dispatch_impl::{new_server, Settings, WireTxImpl},
+    ChannelWireRx, ChannelWireSpawn, ChannelWireTx,
+};
+use postcard_rpc::server::Sender;
+use postcard_rpc::standard_icd::WireError;
+
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{sleep, Duration};
+
+use vmc_icd::bill_validator::BillValidatorEvent;
+use vmc_icd::coin_acceptor::CoinAcceptorEvent;
+
+use vmc_icd::dispenser::{DispenseError, DispenserAddress};
+use vmc_icd::layout::MachineLayout;
+use vmc_icd::{
+    BillInsertedTopic, BillValidatorEventTopic, CashlessEventTopic, ChillerInfoTopic, CoinInsertedTopic,
+    DispenseProgressTopic, EventTopic,
+};
+
+mod handlers;
+mod machine;
+
+use handlers::{EmulatorApp, EmulatorContext};
+use machine::{Machine, Publish};
+
+pub use machine::Jam;
+
+#[derive(Debug, Copy, Clone)]
+pub struct EmulatorConfig {
+    pub layout: MachineLayout,
+    pub cans_per_slot: u8,
+    pub coins_per_tube: u8,
+    pub coin_acceptor_fitted: bool,
+    pub bill_validator_fitted: bool,
+    pub cashless_fitted: bool,
+    pub chiller_temp: i8,
+    pub motor_phase_time: Duration, //Time for the motor to leave home, and again to return
+    pub coin_payout_time: Duration, //Per coin paid out
+    pub icd_fingerprint: Option<u64>, //Reported instead of the real one, to test the host's firmware check
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        Self {
+            layout: MachineLayout::DEFAULT,
+            cans_per_slot: 6,
+            coins_per_tube: 20,
+            coin_acceptor_fitted: true,
+            bill_validator_fitted: true,
+            cashless_fitted: true,
+            chiller_temp: 6,
+            motor_phase_time: Duration::from_millis(500),
+            coin_payout_time: Duration::from_millis(50),
+            icd_fingerprint: None,
+        }
+    }
+}
+
+//State shared between the Emulator handle and the postcard-rpc server handlers
+pub(crate) struct Shared {
+    pub machine: Mutex<Machine>,
+    pub config: EmulatorConfig,
+    sender: Mutex<Option<Sender<WireTxImpl>>>, //Of the most recent connection
+    seq: AtomicU16,
+    pub chiller_config_changed: Notify,
+}
+
+impl Shared {
+    pub async fn publish(&self, msg: Publish) {
+        let Some(sender) = self.sender.lock().unwrap().clone() else {
+            return;
+        };
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed).into();
+        let _ = match msg {
+            Publish::CoinInserted(coin) => sender.publish::<CoinInsertedTopic>(seq, &coin).await,
+            Publish::CoinAcceptorEvent(event) => sender.publish::<EventTopic>(seq, &event).await,
+            Publish::BillInserted(bill) => sender.publish::<BillInsertedTopic>(seq, &bill).await,
+            Publish::BillValidatorEvent(event) => sender.publish::<BillValidatorEventTopic>(seq, &event).await,
+            Publish::Cashless(event) => sender.publish::<CashlessEventTopic>(seq, &event).await,
+            Publish::Chiller(info) => sender.publish::<ChillerInfoTopic>(seq, &info).await,
+            Publish::Progress(progress) => sender.publish::<DispenseProgressTopic>(seq, &progress).await,
+        };
+    }
+
+    pub async fn maybe_publish(&self, msg: Option<Publish>) {
+        if let Some(msg) = msg {
+            self.publish(msg).await;
+        }
+    }
+
+    //As the firmware's chiller task, publishes the chiller status after each measurement
+    async fn publish_chiller_info(&self) {
+        loop {
+            let interval = self.machine.lock().unwrap().config.chiller_measure_interval_ms;
+            tokio::select! {
+                _ = sleep(Duration::from_millis(interval as u64)) => {
+                    let info = self.machine.lock().unwrap().chiller_info();
+                    self.publish(Publish::Chiller(info)).await;
+                }
+                //Start timing the new interval straight away
+                _ = self.chiller_config_changed.notified() => {}
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Emulator {
+    shared: Arc<Shared>,
+}
+
+impl Emulator {
+    pub fn new(config: EmulatorConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                machine: Mutex::new(Machine::new(&config)),
+                config,
+                sender: Mutex::new(None),
+                seq: AtomicU16::new(0),
+                chiller_config_changed: Notify::new(),
+            }),
+        }
+    }
+
+    //Starts a server for a new connection on the current tokio runtime, and returns the client end.
+    //Machine state carries over between connections, as if the VMC had been unplugged and replugged
+    pub fn connect(&self) -> HostClient<WireError> {
+        let (client_tx, server_rx) = mpsc::channel(16);
+        let (server_tx, client_rx) = mpsc::channel(16);
+
+        let app = EmulatorApp::new(EmulatorContext::new(self.shared.clone()), ChannelWireSpawn {});
+        let kkind = app.min_key_len();
+        let mut server = new_server(
+            app,
+            Settings {
+                tx: ChannelWireTx::new(server_tx),
+                rx: ChannelWireRx::new(server_rx),
+                buf: 1024,
+                kkind,
+            },
+        );
+        *self.shared.sender.lock().unwrap() = Some(server.sender());
+        let shared = self.shared.clone();
+        tokio::task::spawn(async move {
+            //The server returns once the client goes away
+            tokio::select! {
+                _ = server.run() => {}
+                _ = shared.publish_chiller_info() => {}
+            }
+        });
+        new_from_channels(client_tx, client_rx, VarSeqKind::Seq2)
+    }
+
+    pub fn set_jam(&self, addr: DispenserAddress, jam: Option<Jam>) {
+        self.shared.machine.lock().unwrap().set_jam(addr, jam);
+    }
+
+    pub fn set_cans(&self, addr: DispenserAddress, cans: u8) {
+        self.shared.machine.lock().unwrap().set_cans(addr, cans);
+    }
+
+    pub fn set_layout(&self, layout: MachineLayout) {
+        self.shared.machine.lock().unwrap().layout = layout;
+    }
+
+    //Every vend attempted that reached the motor, and its result
+    pub fn vends(&self) -> Vec<(DispenserAddress, Result<(), DispenseError>)> {
+        self.shared.machine.lock().unwrap().vends().to_vec()
+    }
+
+    //Cash sales recorded with the cashless device (amount, address)
+    pub fn cash_sales(&self) -> Vec<(u16, DispenserAddress)> {
+        self.shared.machine.lock().unwrap().cash_sales().to_vec()
+    }
+
+    //Coins are rejected unless the host has enabled the acceptor
+    pub async fn insert_coin(&self, value: u16) {
+        let msg = self.shared.machine.lock().unwrap().insert_coin(value);
+        self.shared.maybe_publish(msg).await;
+    }
+
+    //Bills are held in escrow until the host accepts or rejects them
+    pub async fn insert_bill(&self, value: u16) {
+        let msg = self.shared.machine.lock().unwrap().insert_bill(value);
+        self.shared.maybe_publish(msg).await;
+    }
+
+    //Eg the escrow lever being pressed to cancel a purchase
+    pub async fn coin_acceptor_event(&self, event: CoinAcceptorEvent) {
+        let msg = self.shared.machine.lock().unwrap().coin_acceptor_event(event);
+        self.shared.maybe_publish(msg).await;
+    }
+
+    pub async fn bill_validator_event(&self, event: BillValidatorEvent) {
+        let msg = self.shared.machine.lock().unwrap().bill_validator_event(event);
+        self.shared.maybe_publish(msg).await;
+    }
+
+    //Present a card with the given funds - approves or denies a waiting vend
+    pub async fn present_card(&self, funds: u16) {
+        let msg = self.shared.machine.lock().unwrap().present_card(funds);
+        self.shared.maybe_publish(msg).await;
+    }
+
+    pub async fn set_cashless_available(&self, available: bool) {
+        let msg = self.shared.machine.lock().unwrap().set_cashless_available(available);
+        self.shared.maybe_publish(msg).await;
+    }
+
+    pub async fn set_chiller_temp(&self, temp: i8) {
+        let msg = self.shared.machine.lock().unwrap().set_chiller_temp(temp);
+        self.shared.maybe_publish(msg).await;
+    }
+}
+
+//A process-wide emulator with the default config, for vmc-host's emulator feature
+pub fn shared() -> &'static Emulator {
+    static EMULATOR: OnceLock<Emulator> = OnceLock::new();
+    EMULATOR.get_or_init(|| Emulator::new(EmulatorConfig::default()))
+}
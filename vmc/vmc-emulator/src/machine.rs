@@ -0,0 +1,364 @@
+//Simulated state of the vending machine - everything the real VMC would find out from its
+//motor driver and MDB peripherals. Nothing in here awaits, so it can sit behind a std Mutex
+use std::collections::HashMap;
+
+use vmc_icd::bill_validator::{BillInserted, BillRouting, BillValidatorEvent};
+use vmc_icd::cashless_device::{CashlessDeviceCommand, CashlessDeviceEvent};
+use vmc_icd::chiller::ChillerInfo;
+use vmc_icd::coin_acceptor::{CoinAcceptorEvent, CoinAcceptorInfo, CoinInserted, CoinRouting};
+use vmc_icd::config::VmcConfig;
+use vmc_icd::dispenser::{
+    CanStatus, DispenseError, DispenseProgress, Dispenser, DispenserAddress, DispenserType, MotorStatus,
+};
+use vmc_icd::layout::MachineLayout;
+
+//UK coins, in pence - the first four go to the tubes, the pound coins to the cashbox
+const COIN_VALUES: [u16; 6] = [5, 10, 20, 50, 100, 200];
+const TUBE_COIN_TYPES: usize = 4;
+const TUBE_CAPACITY: u8 = 50;
+const BILL_VALUES: [u16; 3] = [500, 1000, 2000];
+
+//Ways a motor can misbehave - set on a slot with Emulator::set_jam
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Jam {
+    NotHome,      //Motor is parked away from home, so won't be vended
+    StuckHome,    //Motor never leaves home
+    StuckNotHome, //Motor leaves home but never returns - then stays NotHome
+    NoDrop,       //Motor cycle completes but no product falls
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Slot {
+    jam: Option<Jam>,
+    cans: u8, //Only used on can rows
+}
+
+#[derive(Default)]
+struct Cashless {
+    available: bool,
+    enabled: bool,
+    card_funds: Option<u16>,    //Funds on the card currently presented, if any
+    pending_vend: Option<u16>,  //Vend requested, awaiting a card
+}
+
+//Messages the machine wants published on the postcard-rpc topics
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Publish {
+    CoinInserted(CoinInserted),
+    CoinAcceptorEvent(CoinAcceptorEvent),
+    BillInserted(BillInserted),
+    BillValidatorEvent(BillValidatorEvent),
+    Cashless(CashlessDeviceEvent),
+    Chiller(ChillerInfo),
+    Progress(DispenseProgress),
+}
+
+pub(crate) struct Machine {
+    pub layout: MachineLayout,
+    pub config: VmcConfig,
+    slots: HashMap<(char, char), Slot>,
+    cans_per_slot: u8,
+    coin_acceptor_fitted: bool,
+    coins_enabled: bool,
+    tube_levels: [u8; 16],
+    bill_validator_fitted: bool,
+    bills_enabled: bool,
+    escrowed_bill: Option<u16>,
+    cashless: Cashless,
+    current_temp: i8,
+    compressor_on: bool,
+    vends: Vec<(DispenserAddress, Result<(), DispenseError>)>,
+    cash_sales: Vec<(u16, DispenserAddress)>,
+}
+
+impl Machine {
+    pub fn new(config: &crate::EmulatorConfig) -> Self {
+        let mut tube_levels = [0u8; 16];
+        tube_levels[..TUBE_COIN_TYPES].fill(config.coins_per_tube);
+        Self {
+            layout: config.layout,
+            config: VmcConfig::DEFAULT,
+            slots: HashMap::new(),
+            cans_per_slot: config.cans_per_slot,
+            coin_acceptor_fitted: config.coin_acceptor_fitted,
+            coins_enabled: false,
+            tube_levels,
+            bill_validator_fitted: config.bill_validator_fitted,
+            bills_enabled: false,
+            escrowed_bill: None,
+            cashless: Cashless {
+                available: config.cashless_fitted,
+                ..Default::default()
+            },
+            current_temp: config.chiller_temp,
+            compressor_on: false,
+            vends: Vec::new(),
+            cash_sales: Vec::new(),
+        }
+    }
+
+    fn slot(&mut self, addr: DispenserAddress) -> &mut Slot {
+        let cans = self.cans_per_slot;
+        self.slots
+            .entry((addr.row, addr.col))
+            .or_insert(Slot { jam: None, cans })
+    }
+
+    pub fn set_jam(&mut self, addr: DispenserAddress, jam: Option<Jam>) {
+        self.slot(addr).jam = jam;
+    }
+
+    pub fn set_cans(&mut self, addr: DispenserAddress, cans: u8) {
+        self.slot(addr).cans = cans;
+    }
+
+    pub fn dispenser(&mut self, addr: DispenserAddress) -> Option<Dispenser> {
+        if !self.layout.is_fitted(addr) {
+            return None;
+        }
+        let dispenser_type = self.layout.dispenser_type(addr.row)?;
+        let slot = *self.slot(addr);
+        Some(Dispenser {
+            address: addr,
+            dispenser_type,
+            motor_status: if slot.jam == Some(Jam::NotHome) {
+                MotorStatus::MotorNotHome
+            } else {
+                MotorStatus::Ok
+            },
+            can_status: match dispenser_type {
+                DispenserType::Can if slot.cans > 1 => Some(CanStatus::Ok),
+                DispenserType::Can => Some(CanStatus::LastCan),
+                _ => None,
+            },
+        })
+    }
+
+    //Same checks the firmware makes before driving the motor - force vends skip them.
+    //Returns the jam (if any) the motor cycle will hit
+    pub fn start_vend(&mut self, addr: DispenserAddress, force: bool) -> Result<Option<Jam>, DispenseError> {
+        let Some(dispenser) = self.dispenser(addr) else {
+            return Err(DispenseError::MotorNotPresent);
+        };
+        if !force {
+            if dispenser.motor_status != MotorStatus::Ok {
+                return Err(DispenseError::MotorNotHome);
+            }
+            if dispenser.can_status == Some(CanStatus::LastCan) {
+                return Err(DispenseError::OneOrNoCansLeft);
+            }
+        }
+        Ok(self.slot(addr).jam)
+    }
+
+    //Motor left home, but didn't come back
+    pub fn motor_stuck_not_home(&mut self, addr: DispenserAddress) {
+        self.slot(addr).jam = Some(Jam::NotHome);
+    }
+
+    pub fn finish_vend(&mut self, addr: DispenserAddress, jam: Option<Jam>) -> Result<(), DispenseError> {
+        let result = match jam {
+            //Without a drop sensor the VMC can't tell nothing fell
+            Some(Jam::NoDrop) if self.config.drop_sensor_enabled => Err(DispenseError::NoDropDetected),
+            Some(Jam::NoDrop) => Ok(()),
+            _ => {
+                let slot = self.slot(addr);
+                slot.cans = slot.cans.saturating_sub(1);
+                Ok(())
+            }
+        };
+        self.record_vend(addr, result);
+        result
+    }
+
+    pub fn record_vend(&mut self, addr: DispenserAddress, result: Result<(), DispenseError>) {
+        self.vends.push((addr, result));
+    }
+
+    pub fn vends(&self) -> &[(DispenserAddress, Result<(), DispenseError>)] {
+        &self.vends
+    }
+
+    pub fn cash_sales(&self) -> &[(u16, DispenserAddress)] {
+        &self.cash_sales
+    }
+
+    pub fn set_coins_enabled(&mut self, enabled: bool) {
+        self.coins_enabled = enabled;
+    }
+
+    pub fn coin_acceptor_info(&self) -> Option<CoinAcceptorInfo> {
+        if !self.coin_acceptor_fitted {
+            return None;
+        }
+        let mut coin_values = [0u16; 16];
+        coin_values[..COIN_VALUES.len()].copy_from_slice(&COIN_VALUES);
+        Some(CoinAcceptorInfo {
+            currency_code: 0x1826,
+            scaling_factor: 1,
+            decimal_places: 2,
+            coin_values,
+            tube_levels: self.tube_levels,
+            tube_full: self.tube_levels.map(|level| level >= TUBE_CAPACITY),
+        })
+    }
+
+    pub fn insert_coin(&mut self, value: u16) -> Option<Publish> {
+        if !self.coin_acceptor_fitted {
+            return None;
+        }
+        let routing = match COIN_VALUES.iter().position(|v| *v == value) {
+            Some(_) if !self.coins_enabled => CoinRouting::Reject,
+            Some(i) if i < TUBE_COIN_TYPES && self.tube_levels[i] < TUBE_CAPACITY => {
+                self.tube_levels[i] += 1;
+                CoinRouting::Tube
+            }
+            Some(_) => CoinRouting::CashBox,
+            None => CoinRouting::Reject,
+        };
+        Some(Publish::CoinInserted(CoinInserted { value, routing }))
+    }
+
+    //Eg the escrow (coin return) lever being pressed, or a fault - only if the acceptor is fitted
+    pub fn coin_acceptor_event(&self, event: CoinAcceptorEvent) -> Option<Publish> {
+        self.coin_acceptor_fitted.then_some(Publish::CoinAcceptorEvent(event))
+    }
+
+    //Pays out greedily from the tubes, largest coin first, as the firmware does.
+    //Returns the amount paid, and the number of coins it took
+    pub fn payout(&mut self, amount: u16) -> (u16, u32) {
+        if !self.coin_acceptor_fitted {
+            return (0, 0);
+        }
+        let mut remaining = amount;
+        let mut coins = 0u32;
+        for i in (0..TUBE_COIN_TYPES).rev() {
+            let value = COIN_VALUES[i];
+            let count = (remaining / value).min(self.tube_levels[i] as u16);
+            self.tube_levels[i] -= count as u8;
+            remaining -= count * value;
+            coins += count as u32;
+        }
+        (amount - remaining, coins)
+    }
+
+    pub fn set_bills_enabled(&mut self, enabled: bool) {
+        self.bills_enabled = enabled;
+    }
+
+    //Enabled bills are held in escrow until the host accepts or rejects them
+    pub fn insert_bill(&mut self, value: u16) -> Option<Publish> {
+        if !self.bill_validator_fitted {
+            return None;
+        }
+        let routing = if !BILL_VALUES.contains(&value) || !self.bills_enabled || self.escrowed_bill.is_some() {
+            BillRouting::Rejected
+        } else {
+            self.escrowed_bill = Some(value);
+            BillRouting::Escrow
+        };
+        Some(Publish::BillInserted(BillInserted { value, routing }))
+    }
+
+    pub fn release_escrow(&mut self, stack: bool) -> Option<Publish> {
+        let value = self.escrowed_bill.take()?;
+        let routing = if stack { BillRouting::Stacked } else { BillRouting::Returned };
+        Some(Publish::BillInserted(BillInserted { value, routing }))
+    }
+
+    pub fn bill_validator_event(&self, event: BillValidatorEvent) -> Option<Publish> {
+        self.bill_validator_fitted.then_some(Publish::BillValidatorEvent(event))
+    }
+
+    pub fn set_cashless_available(&mut self, available: bool) -> Option<Publish> {
+        self.cashless.available = available;
+        if !available {
+            self.cashless.card_funds = None;
+            self.cashless.pending_vend = None;
+        }
+        Some(Publish::Cashless(if available {
+            CashlessDeviceEvent::Available
+        } else {
+            CashlessDeviceEvent::Unavailable
+        }))
+    }
+
+    //A card is presented to the reader - approves (or denies) any vend awaiting payment
+    pub fn present_card(&mut self, funds: u16) -> Option<Publish> {
+        if !self.cashless.available {
+            return None;
+        }
+        self.cashless.card_funds = Some(funds);
+        let amount = self.cashless.pending_vend.take()?;
+        Some(self.approve_vend(amount))
+    }
+
+    fn approve_vend(&mut self, amount: u16) -> Publish {
+        match self.cashless.card_funds {
+            Some(funds) if funds >= amount => Publish::Cashless(CashlessDeviceEvent::VendApproved(amount)),
+            _ => {
+                self.cashless.card_funds = None;
+                Publish::Cashless(CashlessDeviceEvent::VendDenied)
+            }
+        }
+    }
+
+    pub fn cashless_command(&mut self, cmd: CashlessDeviceCommand) -> Option<Publish> {
+        match cmd {
+            CashlessDeviceCommand::Reset => {
+                self.cashless.card_funds = None;
+                self.cashless.pending_vend = None;
+                self.cashless.available.then_some(Publish::Cashless(CashlessDeviceEvent::Available))
+            }
+            CashlessDeviceCommand::Enable => {
+                self.cashless.enabled = true;
+                None
+            }
+            CashlessDeviceCommand::Disable => {
+                self.cashless.enabled = false;
+                None
+            }
+            CashlessDeviceCommand::RecordCashTransaction(amount, addr) => {
+                self.cash_sales.push((amount, addr));
+                None
+            }
+            CashlessDeviceCommand::StartTransaction(amount, _addr) => {
+                if !self.cashless.available || !self.cashless.enabled {
+                    return Some(Publish::Cashless(CashlessDeviceEvent::VendDenied));
+                }
+                if self.cashless.card_funds.is_some() {
+                    Some(self.approve_vend(amount))
+                } else {
+                    self.cashless.pending_vend = Some(amount);
+                    None
+                }
+            }
+            CashlessDeviceCommand::CancelTransaction | CashlessDeviceCommand::VendFailed => {
+                self.cashless.card_funds = None;
+                self.cashless.pending_vend = None;
+                None
+            }
+            CashlessDeviceCommand::VendSuccess(_addr) => {
+                //Session ends - the card has to be presented again for another vend
+                self.cashless.card_funds = None;
+                None
+            }
+        }
+    }
+
+    pub fn chiller_info(&self) -> ChillerInfo {
+        ChillerInfo {
+            target_temp: self.config.chiller_setpoint,
+            current_temp: self.current_temp,
+            duty_cycle: if self.compressor_on { 100 } else { 0 },
+            compressor_status: self.compressor_on,
+        }
+    }
+
+    //Simple thermostat - no hysteresis or minimum cycle time
+    pub fn set_chiller_temp(&mut self, temp: i8) -> Option<Publish> {
+        self.current_temp = temp;
+        self.compressor_on = temp > self.config.chiller_setpoint;
+        Some(Publish::Chiller(self.chiller_info()))
+    }
+}
@@ -0,0 +1,65 @@
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+//These are used as part of the ICD for features relating to the MDB Bill Validator.
+//They are basically simplified copies of the MDB structs in the mdb-async::bill_validator
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum BillValidatorCommand {
+    Enable,
+    Disable,
+    AcceptEscrow, //Stack the bill currently held in escrow
+    RejectEscrow, //Return the bill currently held in escrow to the customer
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct BillInserted {
+    pub value: u16, //Bill value (scaled)
+    pub routing: BillRouting, //Where it went
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum BillRouting {
+    Stacked,
+    Escrow, //Held awaiting an AcceptEscrow or RejectEscrow command
+    Returned,
+    Rejected,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum BillValidatorEvent {
+    DefectiveMotor,
+    SensorProblem,
+    Busy,
+    RomChecksumError,
+    Jammed,
+    WasReset,
+    BillRemoved,
+    CashBoxOutOfPosition,
+    Disabled,
+    InvalidEscrowRequest,
+    BillRejected,
+    PossibleCreditedBillRemoval,
+    InvalidEvent,
+}
+
+impl From<u8> for BillValidatorEvent {
+    fn from(byte:u8) -> Self {
+        match byte {
+            0x01 => BillValidatorEvent::DefectiveMotor,
+            0x02 => BillValidatorEvent::SensorProblem,
+            0x03 => BillValidatorEvent::Busy,
+            0x04 => BillValidatorEvent::RomChecksumError,
+            0x05 => BillValidatorEvent::Jammed,
+            0x06 => BillValidatorEvent::WasReset,
+            0x07 => BillValidatorEvent::BillRemoved,
+            0x08 => BillValidatorEvent::CashBoxOutOfPosition,
+            0x09 => BillValidatorEvent::Disabled,
+            0x0A => BillValidatorEvent::InvalidEscrowRequest,
+            0x0B => BillValidatorEvent::BillRejected,
+            0x0C => BillValidatorEvent::PossibleCreditedBillRemoval,
+            _ => BillValidatorEvent::InvalidEvent,
+        }
+    }
+}
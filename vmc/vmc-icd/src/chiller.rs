@@ -1,11 +1,19 @@
 use postcard_schema::Schema;
 use serde::{Deserialize, Serialize};
 
+//Temperatures are in whole degrees C - signed, as the thermistor range goes below zero
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
 pub struct ChillerInfo {
-    pub target_temp: u8,
-    pub current_temp: u8,
-    pub duty_cycle: u8,
+    pub target_temp: i8,
+    pub current_temp: i8,
+    pub duty_cycle: u8, //Percentage of recent measurement cycles the compressor was on for
     pub compressor_status: bool,
 }
 
+//Result of a setpoint change - Err if the requested setpoint is outside the permitted range,
+//or the new setpoint couldn't be stored in the VMC config
+pub type ChillerSetpointResult = Result<(), ()>;
+
+//Range of setpoints the VMC will accept ('C)
+pub const MIN_CHILLER_SETPOINT: i8 = 2;
+pub const MAX_CHILLER_SETPOINT: i8 = 15;
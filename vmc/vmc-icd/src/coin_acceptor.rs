@@ -10,6 +10,21 @@ pub struct CoinInserted {
     pub routing: CoinRouting, //Where it went
 }
 
+//Details of the coin acceptor configuration and current tube contents
+//Coin values are already scaled (ie in pence), and are zero for unused coin types
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct CoinAcceptorInfo {
+    pub currency_code: u16, //MDB (ISO 4217 numeric, BCD) currency code, eg 0x1826 for GBP
+    pub scaling_factor: u8,
+    pub decimal_places: u8,
+    pub coin_values: [u16; 16],
+    pub tube_levels: [u8; 16], //Number of coins in the tube for each coin type
+    pub tube_full: [bool; 16],
+}
+
+//Will be None if no coin acceptor is currently initialised
+pub type CoinAcceptorInfoOption = Option<CoinAcceptorInfo>;
+
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
 pub enum CoinRouting {
     CashBox,
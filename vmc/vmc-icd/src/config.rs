@@ -0,0 +1,97 @@
+//Tunable settings for the VMC firmware, stored in the VMC's flash so they survive a reboot
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::chiller::{MAX_CHILLER_SETPOINT, MIN_CHILLER_SETPOINT};
+
+//All intervals and timeouts are in milliseconds
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct VmcConfig {
+    pub chiller_setpoint: i8, //'C
+    pub chiller_measure_interval_ms: u32,
+    pub chiller_min_cycle_count: u8, //Compressor only switched every this many measurements, to prevent burnout
+    pub motor_leave_home_timeout_ms: u32,
+    pub motor_return_home_timeout_ms: u32,
+    pub drop_detect_timeout_ms: u32, //Only used if drop_sensor_enabled
+    pub mdb_poll_interval_ms: u32,
+    pub mdb_init_retry_interval_ms: u32, //How often absent MDB peripherals are looked for
+    pub watchdog_timeout_ms: u32,        //Watchdog settings only take effect after a reboot
+    pub watchdog_feed_interval_ms: u32,
+    //A product drop sensor, if fitted, makes vends fail unless an item is seen to fall. It must pull
+    //its GPIO input low while an item passes, and can use any of DROP_SENSOR_PINS. Drop sensor
+    //settings only take effect after a reboot
+    pub drop_sensor_enabled: bool,
+    pub drop_sensor_pin: u8,
+}
+
+pub type ConfigResult = Result<(), ConfigError>;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum ConfigError {
+    InvalidChillerSetting,
+    InvalidMotorSetting,
+    InvalidMdbSetting,
+    InvalidWatchdogSetting,
+    StorageError, //Config was valid, but couldn't be saved to flash
+}
+
+//RP2040 watchdog can't count beyond ~8.3 seconds
+const MAX_WATCHDOG_TIMEOUT_MS: u32 = 8000;
+//Less leaves no margin for blocking work such as erasing flash, and the board resets before it can
+//be reconfigured
+const MIN_WATCHDOG_TIMEOUT_MS: u32 = 1000;
+//GPIOs not otherwise used by the VMC board
+pub const DROP_SENSOR_PINS: [u8; 8] = [13, 14, 15, 16, 17, 18, 27, 28];
+//Payouts requested whilst the coin acceptor is absent are only answered at the next retry
+const MAX_MDB_INIT_RETRY_INTERVAL_MS: u32 = 20000;
+
+impl VmcConfig {
+    pub const DEFAULT: Self = Self {
+        chiller_setpoint: 6,
+        chiller_measure_interval_ms: 60000,
+        chiller_min_cycle_count: 5,
+        motor_leave_home_timeout_ms: 1000,
+        motor_return_home_timeout_ms: 2500,
+        drop_detect_timeout_ms: 5000,
+        mdb_poll_interval_ms: 100,
+        mdb_init_retry_interval_ms: 10000,
+        watchdog_timeout_ms: 2000,
+        watchdog_feed_interval_ms: 250,
+        drop_sensor_enabled: false, //My machine does not have one
+        drop_sensor_pin: 13,
+    };
+
+    pub fn validate(&self) -> ConfigResult {
+        if !(MIN_CHILLER_SETPOINT..=MAX_CHILLER_SETPOINT).contains(&self.chiller_setpoint)
+            || self.chiller_measure_interval_ms == 0
+        {
+            return Err(ConfigError::InvalidChillerSetting);
+        }
+        if self.motor_leave_home_timeout_ms == 0
+            || self.motor_return_home_timeout_ms == 0
+            || self.drop_detect_timeout_ms == 0
+            || !DROP_SENSOR_PINS.contains(&self.drop_sensor_pin)
+        {
+            return Err(ConfigError::InvalidMotorSetting);
+        }
+        if self.mdb_poll_interval_ms == 0
+            || self.mdb_init_retry_interval_ms == 0
+            || self.mdb_init_retry_interval_ms > MAX_MDB_INIT_RETRY_INTERVAL_MS
+        {
+            return Err(ConfigError::InvalidMdbSetting);
+        }
+        if !(MIN_WATCHDOG_TIMEOUT_MS..=MAX_WATCHDOG_TIMEOUT_MS).contains(&self.watchdog_timeout_ms)
+            || self.watchdog_feed_interval_ms == 0
+            || self.watchdog_feed_interval_ms >= self.watchdog_timeout_ms
+        {
+            return Err(ConfigError::InvalidWatchdogSetting);
+        }
+        Ok(())
+    }
+}
+
+impl Default for VmcConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -4,6 +4,10 @@
 use postcard_schema::Schema;
 use serde::{Deserialize, Serialize};
 
+//Every address the motor driver can physically drive - rows A-G, columns 0-9
+pub const DISPENSER_ROWS: [char; 7] = ['A', 'B', 'C', 'D', 'E', 'F', 'G'];
+pub const DISPENSER_COLS: [char; 10] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
 pub struct DispenserAddress {
     pub row: char,
@@ -16,6 +20,22 @@ pub enum DispenseCommand {
     ForceVend(DispenserAddress),
 }
 
+//Published as a vend progresses, so the host can show progress and log timings
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct DispenseProgress {
+    pub address: DispenserAddress,
+    pub phase: DispensePhase,
+    pub elapsed_ms: u32, //Since the motor was started
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum DispensePhase {
+    MotorStarted,
+    LeftHome,
+    ReturnedHome,
+    TimedOut, //Motor failed to leave or return home in time
+}
+
 //The result of attempting a vend operation
 pub type DispenseResult = Result<(), DispenseError>;
 
@@ -26,7 +46,7 @@ pub enum DispenseError {
     MotorStuckHome,
     MotorStuckNotHome,
     OneOrNoCansLeft, //Can vendor won't (willingly) vend if only one can present
-    NoDropDetected,  //not implemented yet - my machine does not support
+    NoDropDetected,  //Only if a drop sensor is enabled in the VmcConfig - my machine does not have one
     InvalidAddress,
     CommsError,
 }
@@ -36,6 +56,10 @@ pub enum DispenseError {
 //Will return None if the motor is not present
 pub type DispenserOption = Option<Dispenser>;
 
+//Status of every address in the machine, indexed [row][col] in the order of
+//DISPENSER_ROWS and DISPENSER_COLS. Nested as serde can't handle arrays longer than 32
+pub type MachineMap = [[DispenserOption; DISPENSER_COLS.len()]; DISPENSER_ROWS.len()];
+
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq,Copy, Clone)]
 pub struct Dispenser {
     pub address: DispenserAddress,
@@ -48,6 +72,7 @@ pub struct Dispenser {
 pub enum DispenserType {
     Spiral,
     Can,
+    GumMint, //Optional gum and mint module - row G only
 }
 
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq,Copy, Clone)]
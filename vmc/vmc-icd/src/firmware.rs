@@ -0,0 +1,2 @@
+//The same for the VMC and keyboard - see icd_common
+pub use icd_common::{check_firmware, firmware_info, FirmwareInfo, IcdMismatch};
@@ -0,0 +1,93 @@
+//These structs describe which dispensers are fitted to the machine, so the layout
+//can be changed (and stored on the VMC) without rebuilding the firmware
+use postcard_schema::Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::dispenser::{DispenserAddress, DispenserType, DISPENSER_COLS, DISPENSER_ROWS};
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct MachineLayout {
+    pub rows: [RowLayout; DISPENSER_ROWS.len()], //In the order of DISPENSER_ROWS
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub struct RowLayout {
+    pub dispenser_type: DispenserType,
+    pub fitted_cols: u16, //Bit n set if column n is fitted - zero if the row is empty
+}
+
+pub type LayoutResult = Result<(), LayoutError>;
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Copy, Clone)]
+pub enum LayoutError {
+    InvalidColumn,  //A fitted column doesn't exist (or can't be wired) on that row
+    InvalidRowType, //Can dispensers are only wired to rows E and F, the gum/mint module to row G
+    StorageError,   //Layout was valid, but couldn't be saved to flash
+}
+
+impl MachineLayout {
+    //Used by the VMC until a layout has been stored in flash - this is how my machine is fitted
+    pub const DEFAULT: Self = Self {
+        rows: [
+            RowLayout { dispenser_type: DispenserType::Spiral, fitted_cols: 0b0101_0101 }, //A - 0,2,4,6
+            RowLayout { dispenser_type: DispenserType::Spiral, fitted_cols: 0b0101_0101 }, //B - 0,2,4,6
+            RowLayout { dispenser_type: DispenserType::Spiral, fitted_cols: 0b1111_1111 }, //C - 0-7
+            RowLayout { dispenser_type: DispenserType::Spiral, fitted_cols: 0 },           //D - not fitted
+            RowLayout { dispenser_type: DispenserType::Can, fitted_cols: 0b1111 },         //E - cans 0-3
+            RowLayout { dispenser_type: DispenserType::Can, fitted_cols: 0b1111 },         //F - cans 0-3
+            RowLayout { dispenser_type: DispenserType::GumMint, fitted_cols: 0 },          //G - gum/mint module, not fitted
+        ],
+    };
+
+    fn row(&self, row: char) -> Option<&RowLayout> {
+        let index = DISPENSER_ROWS.iter().position(|r| *r == row)?;
+        Some(&self.rows[index])
+    }
+
+    pub fn dispenser_type(&self, row: char) -> Option<DispenserType> {
+        self.row(row).map(|r| r.dispenser_type)
+    }
+
+    pub fn is_fitted(&self, addr: DispenserAddress) -> bool {
+        match (self.row(addr.row), addr.col.to_digit(10)) {
+            (Some(row), Some(col)) => row.fitted_cols & (0x01 << col) != 0,
+            _ => false,
+        }
+    }
+
+    pub fn validate(&self) -> LayoutResult {
+        for (row, layout) in core::iter::zip(DISPENSER_ROWS, self.rows.iter()) {
+            let max_cols = match layout.dispenser_type {
+                DispenserType::Can => {
+                    if row != 'E' && row != 'F' {
+                        return Err(LayoutError::InvalidRowType);
+                    }
+                    //Can columns are wired to every other drive column (see calc_drive_bytes)
+                    DISPENSER_COLS.len().div_ceil(2)
+                }
+                DispenserType::GumMint => {
+                    if row != 'G' {
+                        return Err(LayoutError::InvalidRowType);
+                    }
+                    //Assumed to be wired to every other drive column, like the cans - unverified
+                    DISPENSER_COLS.len().div_ceil(2)
+                }
+                //Row G only has an even row drive, so can't drive spirals
+                DispenserType::Spiral if row == 'G' && layout.fitted_cols != 0 => {
+                    return Err(LayoutError::InvalidRowType);
+                }
+                DispenserType::Spiral => DISPENSER_COLS.len(),
+            };
+            if layout.fitted_cols >> max_cols != 0 {
+                return Err(LayoutError::InvalidColumn);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for MachineLayout {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -10,9 +10,21 @@ use crate::coin_acceptor::*;
 pub mod cashless_device;
 use crate::cashless_device::*;
 
+pub mod bill_validator;
+use crate::bill_validator::*;
+
 pub mod chiller;
 use crate::chiller::*;
 
+pub mod firmware;
+use crate::firmware::*;
+
+pub mod layout;
+use crate::layout::*;
+
+pub mod config;
+use crate::config::*;
+
 
 
 endpoints! {
@@ -20,13 +32,27 @@ endpoints! {
     omit_std = true;
     | EndpointTy              | RequestTy        | ResponseTy           | Path             |
     | ----------              | ---------        | ----------           | ----             |
+    | FirmwareInfoEndpoint    | ()               | FirmwareInfo         | "/firmware/info"         |  //Firmware version and ICD fingerprint
+    | ConfigEndpoint          | ()               | VmcConfig            | "/config"                |  //Get the current firmware configuration
+    | SetConfigEndpoint       | VmcConfig        | ConfigResult         | "/config/set"            |  //Change (and store) the firmware configuration
+
     //Things to operate the motor driver
     | DispenseEndpoint        | DispenseCommand  | DispenseResult       | "/dispenser/dispense"    |  //Dispenses or force-dispenses an item
     | DispenserStatusEndpoint | DispenserAddress | DispenserOption      | "/dispenser/status"      |  //Get the status for a given dispenser
+    | DispenserMapEndpoint    | ()               | MachineMap           | "/dispenser/map"         |  //Get the status of every dispenser in the machine
+    | MachineLayoutEndpoint   | ()               | MachineLayout        | "/dispenser/layout"      |  //Get the fitted slots and row types
+    | SetMachineLayoutEndpoint | MachineLayout   | LayoutResult         | "/dispenser/layout/set"  |  //Change the layout - stored in flash, so survives a reboot
 
     | CoinAcceptorEnableEndpoint | bool          | ()                   | "/mdb/coinacceptor/enable" | //Whether acceptor should accept coins
+    | CoinAcceptorPayoutEndpoint | u16           | u16                  | "/mdb/coinacceptor/payout" | //Pay out change from the tubes - replies with the amount actually paid
+    | CoinAcceptorInfoEndpoint   | ()            | CoinAcceptorInfoOption | "/mdb/coinacceptor/info" | //Coin types, scaling and tube levels
 
     | CashlessDeviceCmdEndpoint  | CashlessDeviceCommand | ()    | "/mdb/cashlessdevice/cmd"  | //Commands to the cashless device
+
+    | BillValidatorCmdEndpoint   | BillValidatorCommand | ()     | "/mdb/billvalidator/cmd"   | //Enable/disable and escrow commands to the bill validator
+
+    | ChillerInfoEndpoint        | ()            | ChillerInfo          | "/chiller/info"            | //Current chiller temperature and status
+    | ChillerSetpointEndpoint    | i8            | ChillerSetpointResult | "/chiller/setpoint"       | //Change the chiller target temperature ('C)
 }
 
 topics! {
@@ -43,6 +69,17 @@ topics! {
     | -------                   | ---------             | ----                             | ---                           |
     | CoinInsertedTopic         | CoinInserted          | "/mdb/coinacceptor/coininserted" |                               |
     | EventTopic                | CoinAcceptorEvent     | "/mdb/coinacceptor/event"        |                               |
+    //Progress of a vend in the motor driver
+    | DispenseProgressTopic     | DispenseProgress      | "/dispenser/progress"            |                               |
     //An event from the cashless device
     | CashlessEventTopic        | CashlessDeviceEvent   | "/mdb/cashless/event"            |                               |
+    | BillInsertedTopic         | BillInserted          | "/mdb/billvalidator/billinserted" |                              |
+    | BillValidatorEventTopic   | BillValidatorEvent    | "/mdb/billvalidator/event"       |                               |
+    //Periodic chiller status, published after each temperature measurement
+    | ChillerInfoTopic          | ChillerInfo           | "/chiller/status"                |                               |
+}
+
+//Hash of every endpoint and topic in this ICD - see icd_common::fingerprint
+pub fn icd_fingerprint() -> u64 {
+    icd_common::fingerprint(&ENDPOINT_LIST, &TOPICS_IN_LIST, &TOPICS_OUT_LIST)
 }